@@ -0,0 +1,52 @@
+//! Times a trace of a filled 32x32x32 cube at several resolutions
+//! Run with `cargo bench --features gpu-bench`, the benchmark needs a gpu
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use voxeltracer::{
+    bench::create_bench_grid,
+    tracer::ACCELERATION_BVH,
+    AdapterOptions, Camera, GpuContext, Light, ShaderOptions, Tracer, Uniforms,
+};
+
+/// The resolutions the cube gets traced at
+const RESOLUTIONS: [(u32, u32); 3] = [(640, 360), (1280, 720), (1920, 1080)];
+
+fn trace(c: &mut Criterion) {
+    // The benchmark doesn't need a window
+    let gpu = pollster::block_on(GpuContext::new(&AdapterOptions::default())).unwrap();
+    let grid = create_bench_grid(32 * 32 * 32);
+    let lights = vec![Light::default()];
+    let camera = Camera::default();
+    let uniforms = Uniforms {
+        voxel_amount: grid.len() as u32,
+        light_count: lights.len() as u32,
+        acceleration: ACCELERATION_BVH,
+        ..Default::default()
+    };
+    let mut tracer = Tracer::new(&gpu, &uniforms, &ShaderOptions::default()).unwrap();
+
+    let mut group = c.benchmark_group("trace");
+    for (width, height) in RESOLUTIONS {
+        tracer.resize(&gpu, width, height).unwrap();
+        let uniforms = Uniforms {
+            resolution: glam::uvec2(width, height),
+            region_size: glam::uvec2(width, height),
+            ..uniforms
+        };
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}x{}", width, height)),
+            &uniforms,
+            |b, &uniforms| {
+                b.iter(|| {
+                    // Wait for the gpu, so the whole trace is timed and not only the submit
+                    tracer.dispatch(&gpu, uniforms, &grid, &lights, camera).unwrap();
+                    gpu.device.poll(wgpu::Maintain::Wait);
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, trace);
+criterion_main!(benches);
@@ -0,0 +1,81 @@
+/// The settings of a single denoise iteration
+struct DenoiseParams {
+    resolution: vec2<u32>,
+    // The distance between the pixels of the filter, this doubles every iteration
+    step_width: i32,
+    // How strongly differences in color, normal and depth stop the blur
+    color_phi: f32,
+    normal_phi: f32,
+    depth_phi: f32,
+};
+
+// The denoise settings
+@group(0)
+@binding(0)
+var<uniform> params: DenoiseParams;
+
+// The frame that gets filtered
+@group(0)
+@binding(1)
+var input: texture_2d<f32>;
+
+// The filtered frame
+@group(0)
+@binding(2)
+var output: texture_storage_2d<rgba8unorm, write>;
+
+// The normal and the distance from the camera for each pixel, written by the tracer
+@group(0)
+@binding(3)
+var<storage, read> guide: array<vec4<f32>>;
+
+/// Get the guide of a pixel
+fn load_guide(pixel: vec2<i32>) -> vec4<f32> {
+    return guide[u32(pixel.y) * params.resolution.x + u32(pixel.x)];
+}
+
+/// """
+/// Entrypoint
+/// A single iteration of the edge avoiding a-trous wavelet filter
+/// The workgroup size is replaced by the tracer to fit the limits of the gpu
+/// """
+@compute
+@workgroup_size(16, 16, 1)
+fn main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+) {
+    // Skip the invocations outside of the frame
+    if (any(id.xy >= params.resolution)) {
+        return;
+    }
+    let pixel = vec2<i32>(id.xy);
+    let size = vec2<i32>(params.resolution);
+    let color = textureLoad(input, pixel, 0);
+    let center = load_guide(pixel);
+
+    // The weights of the 5x5 B3 spline kernel
+    var kernel = array<f32, 5>(0.0625, 0.25, 0.375, 0.25, 0.0625);
+    var sum = vec4<f32>(0.0);
+    var total = 0.0;
+    for (var y = -2; y <= 2; y += 1) {
+        for (var x = -2; x <= 2; x += 1) {
+            // Spread the taps further apart every iteration, clamping them to the frame
+            let tap = clamp(pixel + vec2<i32>(x, y) * params.step_width, vec2<i32>(0), size - vec2<i32>(1));
+            let tap_color = textureLoad(input, tap, 0);
+            let tap_guide = load_guide(tap);
+
+            // Lower the weight of the taps across edges in color, normal or depth
+            let color_difference = tap_color.xyz - color.xyz;
+            let normal_difference = tap_guide.xyz - center.xyz;
+            let depth_difference = abs(tap_guide.w - center.w);
+            let weight = kernel[x + 2] * kernel[y + 2]
+                * exp(-dot(color_difference, color_difference) / params.color_phi)
+                * exp(-dot(normal_difference, normal_difference) / params.normal_phi)
+                * exp(-depth_difference / params.depth_phi);
+            sum += tap_color * weight;
+            total += weight;
+        }
+    }
+    // The centre tap always has a weight, so the total is never zero
+    textureStore(output, pixel, sum / total);
+}
@@ -3,6 +3,12 @@ struct Camera {
     position: vec3<f32>,
     look_at: vec3<f32>,
     zoom: f32,
+    up: vec3<f32>,
+    roll: f32,
+    projection: u32,
+    ortho_scale: f32,
+    aperture: f32,
+    focal_distance: f32,
 };
 
 /// The uniforms for the shader
@@ -15,10 +21,44 @@ struct Uniforms {
     background_color: vec4<f32>,
     floor_color: vec4<f32>,
     object_color: vec3<f32>,
-    light_position: vec3<f32>,
-    sun_intensity: f32,
+    light_count: u32,
     smoothing: f32,
     ambient_occlusion: i32,
+    floor_enabled: i32,
+    floor_height: f32,
+    alpha_background: i32,
+    ao_strength: f32,
+    region_offset: vec2<u32>,
+    region_size: vec2<u32>,
+    animate: i32,
+    near: f32,
+    far: f32,
+    sky_mode: u32,
+    sky_top_color: vec4<f32>,
+    sky_bottom_color: vec4<f32>,
+    tile_count: vec3<u32>,
+    tile_spacing: f32,
+    acceleration: u32,
+    accumulate: i32,
+    sample_count: u32,
+    output_mode: u32,
+    shadow_softness: f32,
+    samples_per_pixel: u32,
+    seed: u32,
+    exposure: f32,
+    tonemap_mode: u32,
+    max_bounces: u32,
+    fog_density: f32,
+    fog_color: vec4<f32>,
+    denoise: i32,
+    palette_size: u32,
+    clip_min: vec3<f32>,
+    clip_max: vec3<f32>,
+    instance_count: u32,
+    hit_epsilon: f32,
+    collect_stats: u32,
+    tile_offset: vec2<u32>,
+    full_resolution: vec2<u32>,
 };
 
 /// Represents a cast ray
@@ -32,12 +72,58 @@ struct RayHit {
     position: vec3<f32>,
     distance: f32,
     color: vec4<f32>,
+    emission: f32,
+    roughness: f32,
+    metallic: f32,
+    // The pattern of the voxel that was hit and the size of its cells, the other objects have no pattern
+    pattern: u32,
+    pattern_scale: f32,
 };
 
 /// Represents a voxel
 struct Voxel {
     position: vec3<i32>,
+    emission: f32,
     color: vec3<f32>,
+    roughness: f32,
+    amplitude: f32,
+    frequency: f32,
+    metallic: f32,
+    palette_index: u32,
+    // 0 is solid, 1 is a checkerboard and 2 is noise
+    pattern: u32,
+    pattern_scale: f32,
+};
+
+/// Represents a point light
+struct Light {
+    // The position of a point light, or the direction towards a directional light
+    position: vec3<f32>,
+    color: vec3<f32>,
+    intensity: f32,
+    // 0 is a point light, 1 is a directional light
+    light_type: u32,
+};
+
+/// Represents a copy of an instanced grid
+struct Instance {
+    // Transforms a world position into the space of the grid
+    inverse: mat4x4<f32>,
+    // The bounds of the grid and the range of its voxels in the instance voxels
+    min: vec3<f32>,
+    start: u32,
+    max: vec3<f32>,
+    count: u32,
+    // The smallest scale of the instance, the distances in the grid are multiplied by it
+    scale: f32,
+};
+
+/// Represents a node of the bvh
+struct BvhNode {
+    min: vec3<f32>,
+    start: u32,
+    max: vec3<f32>,
+    count: u32,
 };
 
 // Shader uniforms
@@ -60,6 +146,73 @@ var<storage, read> voxels: array<Voxel>;
 @binding(3)
 var output: texture_storage_2d<rgba8unorm, write>;
 
+// The lights
+@group(0)
+@binding(4)
+var<storage, read> lights: array<Light>;
+
+// The bvh over the voxels
+@group(0)
+@binding(5)
+var<storage, read> nodes: array<BvhNode>;
+
+// The sum of the accumulated samples for each pixel
+@group(0)
+@binding(6)
+var<storage, read_write> accumulation: array<vec4<f32>>;
+
+// The normal and the distance from the camera for each pixel, this guides the denoiser
+@group(0)
+@binding(7)
+var<storage, read_write> guide: array<vec4<f32>>;
+
+// The colors the palette indices of the voxels refer to
+@group(0)
+@binding(8)
+var<storage, read> palette: array<vec3<f32>>;
+
+// The voxels of the instanced grids one after another
+@group(0)
+@binding(9)
+var<storage, read> instance_voxels: array<Voxel>;
+
+// The instances that place the instanced grids in the world
+@group(0)
+@binding(10)
+var<storage, read> instances: array<Instance>;
+
+/// The counters of the render stats, these are added to by every invocation
+struct Stats {
+    steps: atomic<u32>,
+    hits: atomic<u32>,
+    rays: atomic<u32>,
+    pixels: atomic<u32>,
+};
+
+// The render stats, only written when they're collected
+@group(0)
+@binding(11)
+var<storage, read_write> stats: Stats;
+
+// The voxels that glow, the glow of the lighting only checks these
+@group(0)
+@binding(12)
+var<storage, read> emissive: array<Voxel>;
+
+// The march steps and the hits of the invocation, these are added to the stats once at the end
+var<private> invocation_steps: u32;
+var<private> invocation_hits: u32;
+
+/// Hash a pixel and a sample to two pseudo random numbers between 0 and 1
+/// The numbers only depend on the seed and not on the time, so renders can be reproduced
+fn hash2(pixel: vec2<u32>, index: u32) -> vec2<f32> {
+    var h = pixel.x * 1973u + pixel.y * 9277u + index * 26699u + uniforms.seed * 83492791u;
+    h = (h ^ (h >> 16u)) * 0x7feb352du;
+    h = (h ^ (h >> 15u)) * 0x846ca68bu;
+    h = h ^ (h >> 16u);
+    return vec2<f32>(f32(h & 0xffffu), f32(h >> 16u)) / 65535.0;
+}
+
 /// Round the number to the nearest multiple
 fn round_mul(num: f32, mul: i32) -> i32 {
     if (mul == 0) {
@@ -89,11 +242,34 @@ fn get_grid_positon(position: vec3<f32>, grid_size: i32) -> vec3<i32> {
     return vec3<i32>(x, y, z);
 }
 
-/// Create a ray cast from the camera
-fn create_camera_ray(uv: vec2<f32>, camera: Camera) -> Ray {
+/// Get the forward, right and up directions of the camera
+fn camera_basis(camera: Camera) -> mat3x3<f32> {
     let f = normalize(camera.look_at - camera.position);
-    let r = cross(vec3<f32>(0.0, 1.0, 0.0), f);
+    // Fall back to another up direction when looking along the up direction
+    var up = camera.up;
+    if (length(cross(up, f)) < 0.001) {
+        up = select(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(1.0, 0.0, 0.0), abs(f.z) > 0.9);
+    }
+    let r = normalize(cross(up, f));
     let u = cross(f, r);
+    // Rotate the right and up directions around the view direction
+    let s = sin(camera.roll);
+    let c = cos(camera.roll);
+    return mat3x3<f32>(f, r * c + u * s, u * c - r * s);
+}
+
+/// Create a ray cast from the camera
+fn create_camera_ray(uv: vec2<f32>, camera: Camera) -> Ray {
+    let basis = camera_basis(camera);
+    let f = basis[0];
+    let r = basis[1];
+    let u = basis[2];
+
+    // Cast parallel rays from the image plane for the orthographic projection
+    if (camera.projection == 1u) {
+        let origin = camera.position + (uv.x * r + uv.y * u) * camera.ortho_scale;
+        return Ray(origin, f);
+    }
     
     let c = camera.position + f * (camera.zoom - 0.1);
     let i = c + uv.x * r + uv.y * u;
@@ -101,6 +277,21 @@ fn create_camera_ray(uv: vec2<f32>, camera: Camera) -> Ray {
     return Ray(camera.position, direction);
 }
 
+/// Move the start of a camera ray across the lens, the ray still goes through the same point on the focal plane
+/// The lens sample is a random point in the unit square that gets spread over the disk of the lens
+fn apply_lens(ray: Ray, camera: Camera, lens_sample: vec2<f32>) -> Ray {
+    let basis = camera_basis(camera);
+    // Find where the ray crosses the focal plane, the direction isn't normalized
+    let focus = ray.position + ray.direction * (camera.focal_distance / dot(ray.direction, basis[0]));
+    // Take a point on the lens, the square root spreads the points evenly over the disk
+    let radius = camera.aperture * sqrt(lens_sample.x);
+    let angle = 6.2831853 * lens_sample.y;
+    let position = ray.position + (cos(angle) * basis[1] + sin(angle) * basis[2]) * radius;
+    // Keep the length of the direction, since it scales the march distances
+    let direction = normalize(focus - position) * length(ray.direction);
+    return Ray(position, direction);
+}
+
 /// Draw a sphere
 fn draw_sphere(
     hit: ptr<function, RayHit>, 
@@ -117,6 +308,10 @@ fn draw_sphere(
         (*hit).position = raypos;
         (*hit).color = color; 
         (*hit).distance = distance;
+        (*hit).emission = 0.0;
+        (*hit).roughness = 1.0;
+        (*hit).metallic = 0.0;
+        (*hit).pattern = 0u;
     }
 }
 
@@ -126,7 +321,10 @@ fn draw_rectangle(
     raypos: vec3<f32>, 
     position: vec3<f32>, 
     size: vec3<f32>, 
-    color: vec4<f32>
+    color: vec4<f32>,
+    emission: f32,
+    roughness: f32,
+    metallic: f32
 ) {
     let d = abs(raypos - position) - size;
     let distance = min(max(d.x, max(d.y, d.z)), 0.0) + length(max(d, vec3<f32>(0.0)));
@@ -137,23 +335,169 @@ fn draw_rectangle(
         (*hit).position = raypos;
         (*hit).color = color; 
         (*hit).distance = distance;
+        (*hit).emission = emission;
+        (*hit).roughness = roughness;
+        (*hit).metallic = metallic;
+        (*hit).pattern = 0u;
+    }
+}
+
+/// Draw a horizontal plane
+fn draw_plane(
+    hit: ptr<function, RayHit>, 
+    raypos: vec3<f32>, 
+    height: f32, 
+    color: vec4<f32>
+) {
+    let distance = raypos.y - height;
+    // Check if the plane is closer than the previous objects
+    if (distance < (*hit).distance) {
+        // Update the hit
+        (*hit).position = raypos;
+        (*hit).color = color; 
+        (*hit).distance = distance;
+        (*hit).emission = 0.0;
+        (*hit).roughness = 1.0;
+        (*hit).metallic = 0.0;
+        (*hit).pattern = 0u;
+    }
+}
+
+/// Get the color of a voxel from the palette, or its own color if it doesn't use the palette
+fn base_color(voxel: Voxel) -> vec3<f32> {
+    if (voxel.palette_index > 0u && voxel.palette_index <= uniforms.palette_size) {
+        return palette[voxel.palette_index - 1u];
+    }
+    return voxel.color;
+}
+
+/// Get the color of a voxel at the current time
+fn voxel_color(voxel: Voxel) -> vec3<f32> {
+    if (uniforms.animate == 0) {
+        return base_color(voxel);
+    }
+    // Pulse the brightness of the voxel
+    let pulse = sin(uniforms.time * voxel.frequency * 6.2831853);
+    return base_color(voxel) * max(1.0 + voxel.amplitude * pulse, 0.0);
+}
+
+/// Wrap a position into the first copy of the tiled grid
+/// The copies are placed along the positive axes, so the grid data is only stored once
+fn tile_position(position: vec3<f32>) -> vec3<f32> {
+    if (uniforms.tile_spacing <= 0.0) {
+        return position;
+    }
+    // Get the copy the position is in, clamped to the copies that exist
+    let last = vec3<f32>(max(uniforms.tile_count, vec3<u32>(1u)) - vec3<u32>(1u));
+    let tile = clamp(round(position / uniforms.tile_spacing), vec3<f32>(0.0), last);
+    return position - tile * uniforms.tile_spacing;
+}
+
+/// Check if the centre of a voxel is outside of the clip box, the clipped voxels are treated as empty
+fn is_clipped(position: vec3<i32>) -> bool {
+    let centre = vec3<f32>(position) * 0.16 * 2.0;
+    return any(centre < uniforms.clip_min) || any(centre > uniforms.clip_max);
+}
+
+/// Draw a single voxel
+fn draw_voxel(hit: ptr<function, RayHit>, raypos: vec3<f32>, voxel: Voxel) {
+    if (is_clipped(voxel.position)) {
+        return;
+    }
+    let previous = (*hit).distance;
+    draw_rectangle(
+        hit, 
+        raypos, 
+        vec3<f32>(voxel.position) * 0.16 * 2.0, 
+        vec3<f32>(0.16, 0.16, 0.16), 
+        vec4<f32>(voxel_color(voxel), 1.0),
+        voxel.emission,
+        voxel.roughness,
+        voxel.metallic
+    );
+    // Keep the pattern of the voxel if it's the closest so far
+    if ((*hit).distance < previous) {
+        (*hit).pattern = voxel.pattern;
+        (*hit).pattern_scale = voxel.pattern_scale;
+    }
+}
+
+/// Draw the voxels using the bvh
+/// The nodes that are further away than the closest voxel so far are skipped
+fn draw_voxels_bvh(hit: ptr<function, RayHit>, raypos: vec3<f32>) {
+    var stack: array<u32, 32>;
+    stack[0] = 0u;
+    var top = 1;
+    loop {
+        if (top <= 0) {
+            break;
+        }
+        top -= 1;
+        let node = nodes[stack[top]];
+        // Skip the node if it can't contain a closer voxel
+        let outside = max(max(node.min - raypos, raypos - node.max), vec3<f32>(0.0));
+        if (length(outside) >= (*hit).distance) {
+            continue;
+        }
+        if (node.count > 0u) {
+            // Draw the voxels in the leaf
+            for (var i = node.start; i < node.start + node.count; i += 1u) {
+                draw_voxel(hit, raypos, voxels[i]);
+            }
+        } else if (top < 31) {
+            // Visit both children
+            stack[top] = node.start;
+            stack[top + 1] = node.start + 1u;
+            top += 2;
+        }
+    }
+}
+
+/// Draw the voxels of the instances, marching each grid in its own space
+fn draw_instances(hit: ptr<function, RayHit>, raypos: vec3<f32>) {
+    for (var i = 0u; i < uniforms.instance_count; i += 1u) {
+        let instance = instances[i];
+        let local = (instance.inverse * vec4<f32>(raypos, 1.0)).xyz;
+        // Skip the instance if its bounds are further away than the closest object so far
+        let outside = max(max(instance.min - local, local - instance.max), vec3<f32>(0.0));
+        if (length(outside) * instance.scale >= (*hit).distance) {
+            continue;
+        }
+        // Draw the voxels with the distances in the space of the grid
+        var local_hit = *hit;
+        local_hit.distance = (*hit).distance / instance.scale;
+        for (var j = instance.start; j < instance.start + instance.count; j += 1u) {
+            draw_voxel(&local_hit, local, instance_voxels[j]);
+        }
+        if (local_hit.distance * instance.scale < (*hit).distance) {
+            *hit = local_hit;
+            (*hit).distance = local_hit.distance * instance.scale;
+        }
     }
 }
 
 /// Draws the voxels
 fn map(raypos: vec3<f32>, hit: RayHit) -> RayHit {
     var result = hit;
-    // Draw each voxel
-    for (var i = 0; i < (i32(uniforms.voxel_amount)); i += 1) {
-        let voxel = voxels[i];
-        draw_rectangle(
-            &result, 
-            raypos, 
-            vec3<f32>(voxel.position) * 0.16 * 2.0, 
-            vec3<f32>(0.16, 0.16, 0.16), 
-            vec4<f32>(voxel.color, 1.0)
-        );
+    // Draw the floor
+    if (uniforms.floor_enabled != 0) {
+        draw_plane(&result, raypos, uniforms.floor_height, uniforms.floor_color);
     }
+    // Draw each voxel, wrapping the position to repeat the grid
+    let tiled = tile_position(raypos);
+    if (uniforms.acceleration == 1u) {
+        if (uniforms.voxel_amount > 0u) {
+            draw_voxels_bvh(&result, tiled);
+        }
+    } else {
+        for (var i = 0; i < (i32(uniforms.voxel_amount)); i += 1) {
+            draw_voxel(&result, tiled, voxels[i]);
+        }
+    }
+    // Draw the instanced grids
+    draw_instances(&result, raypos);
+    // Keep the hit in world space for the lighting
+    result.position = raypos;
     return result;
 }
 
@@ -177,32 +521,208 @@ fn calculate_normal(hit: RayHit) -> vec3<f32> {
     return normalize(vec3<f32>(x, y, z));
 }
 
-/// Calculate the ambient occlusion
-fn ambient_occlusion(normal: vec3<f32>, step_dist: f32, steps: i32, hit: RayHit) -> f32 {
-    var occlusion = 0.0;
-    var max_occlusion = 0.0;
+/// Check if a grid cell contains a voxel using the bvh
+/// Only the nodes with the centre of the cell inside them are visited
+fn is_occupied_bvh(cell: vec3<i32>) -> bool {
+    let centre = vec3<f32>(cell) * 0.16 * 2.0;
+    var stack: array<u32, 32>;
+    stack[0] = 0u;
+    var top = 1;
+    loop {
+        if (top <= 0) {
+            break;
+        }
+        top -= 1;
+        let node = nodes[stack[top]];
+        if (any(centre < node.min) || any(centre > node.max)) {
+            continue;
+        }
+        if (node.count > 0u) {
+            for (var i = node.start; i < node.start + node.count; i += 1u) {
+                if (all(voxels[i].position == cell)) {
+                    return true;
+                }
+            }
+        } else if (top < 31) {
+            stack[top] = node.start;
+            stack[top + 1] = node.start + 1u;
+            top += 2;
+        }
+    }
+    return false;
+}
+
+/// Check if a grid cell contains a voxel
+fn is_occupied(cell: vec3<i32>) -> bool {
+    if (is_clipped(cell) || uniforms.voxel_amount == 0u) {
+        return false;
+    }
+    if (uniforms.acceleration == 1u) {
+        return is_occupied_bvh(cell);
+    }
+    for (var i = 0; i < (i32(uniforms.voxel_amount)); i += 1) {
+        if (all(voxels[i].position == cell)) {
+            return true;
+        }
+    }
+    return false;
+}
+
+/// Calculate the ambient occlusion by checking the grid cells surrounding the hit face
+/// The edge neighbours are checked first, then the corner neighbours
+fn ambient_occlusion(normal: vec3<f32>, samples: i32, strength: f32, hit: RayHit) -> f32 {
+    // The empty cell in front of the hit face
+    let cell = vec3<i32>(round(tile_position(hit.position + normal * 0.16) / (0.16 * 2.0)));
+    // The directions along the hit face
+    var tangent = vec3<i32>(1, 0, 0);
+    var bitangent = vec3<i32>(0, 0, 1);
+    if (abs(normal.x) > abs(normal.y) && abs(normal.x) > abs(normal.z)) {
+        tangent = vec3<i32>(0, 1, 0);
+    } else if (abs(normal.z) > abs(normal.y)) {
+        bitangent = vec3<i32>(0, 1, 0);
+    }
 
-    for (var i = 0; i < steps; i += 1) {
-        let p = hit.position + normal * (f32(i)+1.0) * step_dist;
-        occlusion += 1.0 / pow(2.0, f32(i)) * map(p, hit).distance;
-        max_occlusion += 1.0 / pow(2.0, f32(i)) * (f32(i)+1.0) * step_dist;
+    // The neighbours along the face
+    var offsets = array<vec2<i32>, 8>(
+        vec2<i32>(1, 0),
+        vec2<i32>(-1, 0),
+        vec2<i32>(0, 1),
+        vec2<i32>(0, -1),
+        vec2<i32>(1, 1),
+        vec2<i32>(1, -1),
+        vec2<i32>(-1, 1),
+        vec2<i32>(-1, -1),
+    );
+    let count = clamp(samples, 0, 8);
+    var occluded = 0.0;
+    for (var i = 0; i < count; i += 1) {
+        let offset = offsets[i];
+        if (is_occupied(cell + tangent * offset.x + bitangent * offset.y)) {
+            occluded += 1.0;
+        }
     }
 
-    return occlusion / max_occlusion;
+    if (count == 0) {
+        return 1.0;
+    }
+    return 1.0 - occluded / f32(count) * strength;
 }
 
 
+/// Calculate how much of a light reaches a point, from 0 in full shadow to 1 fully lit
+/// The closest the march passes by an occluder relative to the distance travelled decides the penumbra
+/// The march stops at the max distance, which is the distance to the light or the far distance for a directional light
+fn soft_shadow(origin: vec3<f32>, direction: vec3<f32>, max_distance: f32) -> f32 {
+    // Smaller softness gives sharper shadows
+    let k = 1.0 / uniforms.shadow_softness;
+    var result = 1.0;
+    var travelled = 0.02;
+    for (var i = 0; i < i32(uniforms.max_steps) && travelled < max_distance; i += 1) {
+        // Start each step without a hit
+        var probe: RayHit;
+        probe.distance = max_distance;
+        let distance = map(origin + direction * travelled, probe).distance;
+        if (distance < uniforms.hit_epsilon) {
+            return 0.0;
+        }
+        result = min(result, k * distance / travelled);
+        travelled += distance;
+    }
+    return clamp(result, 0.0, 1.0);
+}
+
+/// Hash a cell of a pattern to a pseudo random number between 0 and 1
+fn cell_hash(cell: vec2<i32>) -> f32 {
+    var h = u32(cell.x) * 1973u + u32(cell.y) * 9277u;
+    h = (h ^ (h >> 16u)) * 0x7feb352du;
+    h = (h ^ (h >> 15u)) * 0x846ca68bu;
+    h = h ^ (h >> 16u);
+    return f32(h & 0xffffu) / 65535.0;
+}
+
+/// Smoothly interpolate the random values of the corners of the cells
+fn value_noise(position: vec2<f32>) -> f32 {
+    let cell = floor(position);
+    let f = position - cell;
+    let s = f * f * (vec2<f32>(3.0) - 2.0 * f);
+    let c = vec2<i32>(cell);
+    let bottom = mix(cell_hash(c), cell_hash(c + vec2<i32>(1, 0)), s.x);
+    let top = mix(cell_hash(c + vec2<i32>(0, 1)), cell_hash(c + vec2<i32>(1, 1)), s.x);
+    return mix(bottom, top, s.y);
+}
+
+/// Get how much the pattern of the hit darkens its color, 1 keeps the color as is
+/// The pattern is laid out on the plane of the face, so the normal picks the axes
+fn pattern_factor(hit: RayHit, normal: vec3<f32>) -> f32 {
+    if (hit.pattern == 0u) {
+        return 1.0;
+    }
+    let scaled = hit.position / max(hit.pattern_scale, 0.001);
+    let n = abs(normal);
+    var uv = scaled.xy;
+    if (n.x >= n.y && n.x >= n.z) {
+        uv = scaled.yz;
+    } else if (n.y >= n.z) {
+        uv = scaled.xz;
+    }
+    switch hit.pattern {
+        case 1u: {
+            let cell = vec2<i32>(floor(uv));
+            return select(1.0, 0.5, ((cell.x + cell.y) & 1) != 0);
+        }
+        case 2u: {
+            return mix(0.5, 1.0, value_noise(uv));
+        }
+        default: {
+            return 1.0;
+        }
+    }
+}
+
 /// Calculates both the diffuse and ambient lighting
-fn lighting(hit: RayHit) -> vec3<f32> {
+fn lighting(hit: RayHit, view_dir: vec3<f32>) -> vec3<f32> {
     // Calculate diffuse lighting
     let normal = calculate_normal(hit);
-    let light_dir = normalize(uniforms.light_position - hit.position);
-    let diffuse = uniforms.sun_intensity / 10.0 + clamp(dot(normal, light_dir), 0.0, 1.0);
-    // Return the ambient lighting
-    // let ambient = ambient_occlusion(normal, 0.0015, uniforms.ambient_occlusion, hit);
+    var diffuse = vec3<f32>(0.0);
+    var specular = vec3<f32>(0.0);
+    // Rough surfaces scatter the light, smooth surfaces get a sharp highlight
+    let shininess = mix(64.0, 1.0, hit.roughness);
+    // Metals have no diffuse light and tint their highlights with their color
+    let tint = mix(vec3<f32>(1.0), hit.color.xyz, hit.metallic);
+    // Accumulate the contribution of each light
+    for (var i = 0; i < i32(uniforms.light_count); i += 1) {
+        let light = lights[i];
+        // A directional light shines along parallel rays from infinitely far away
+        var light_dir = normalize(light.position);
+        var light_distance = uniforms.far;
+        if (light.light_type == 0u) {
+            let to_light = light.position - hit.position;
+            light_distance = length(to_light);
+            light_dir = to_light / light_distance;
+        }
+        // Only march the shadow when soft shadows are enabled
+        var shadow = 1.0;
+        if (uniforms.shadow_softness > 0.0) {
+            shadow = soft_shadow(hit.position + normal * 0.01, light_dir, light_distance);
+        }
+        diffuse += light.color * light.intensity * (0.1 + clamp(dot(normal, light_dir), 0.0, 1.0) * shadow);
+        let half_dir = normalize(light_dir - view_dir);
+        specular += tint * light.color * light.intensity * pow(clamp(dot(normal, half_dir), 0.0, 1.0), shininess) * (1.0 - hit.roughness) * shadow;
+    }
+    // Accumulate the glow of the emissive voxels
+    for (var i = 0u; i < arrayLength(&emissive); i += 1u) {
+        let voxel = emissive[i];
+        if (voxel.emission > 0.0 && !is_clipped(voxel.position)) {
+            let to_voxel = vec3<f32>(voxel.position) * 0.16 * 2.0 - hit.position;
+            let distance = length(to_voxel);
+            diffuse += base_color(voxel) * voxel.emission * clamp(dot(normal, to_voxel / distance), 0.0, 1.0) / (1.0 + distance * distance);
+        }
+    }
+    // Calculate the ambient occlusion
+    let ambient = ambient_occlusion(normal, uniforms.ambient_occlusion, uniforms.ao_strength, hit);
 
-    // Return the result
-    return hit.color.xyz * diffuse; // * ambient; // color * diffuse * ambient;
+    // Return the result, the pattern only darkens the color of the surface
+    return hit.color.xyz * pattern_factor(hit, normal) * (diffuse * ambient * (1.0 - hit.metallic) + hit.emission) + specular;
 }
 
 // /// Draw a mandlebulb
@@ -234,61 +754,273 @@ fn lighting(hit: RayHit) -> vec3<f32> {
 //     return 0.5*log(r)*r/dr;
 // }
 
+/// Get the color of the sky in a direction
+/// Everything that shows the background should use this so it stays consistent
+fn sky(direction: vec3<f32>) -> vec4<f32> {
+    // How far up the direction points, from 0 at the bottom to 1 at the top
+    let height = direction.y * 0.5 + 0.5;
+    switch (uniforms.sky_mode) {
+        // Vertical gradient
+        case 1u: {
+            return mix(uniforms.sky_bottom_color, uniforms.sky_top_color, height);
+        }
+        // Procedural sky
+        case 2u: {
+            // Fade quickly from the horizon to the top color
+            var color = mix(uniforms.sky_bottom_color, uniforms.sky_top_color, sqrt(clamp(direction.y, 0.0, 1.0)));
+            // Darken below the horizon
+            if (direction.y < 0.0) {
+                color = vec4<f32>(uniforms.sky_bottom_color.xyz * (1.0 + direction.y * 0.5), uniforms.sky_bottom_color.w);
+            }
+            // Add a sun
+            let sun = pow(max(dot(direction, normalize(vec3<f32>(0.5, 0.6, -0.6))), 0.0), 256.0);
+            return color + vec4<f32>(vec3<f32>(sun), 0.0);
+        }
+        // Flat color
+        default: {
+            return uniforms.background_color;
+        }
+    }
+}
+
+/// Get the color of a debug output mode, the shaded output is handled by `cast_ray`
+/// The misses are black, except for the step count which is shown for every pixel
+fn debug_color(hit: RayHit, found: bool, travelled: f32, steps: i32, max_iters: i32) -> vec4<f32> {
+    switch (uniforms.output_mode) {
+        // The normal, mapped from -1..1 to 0..1
+        case 1u: {
+            if (!found) {
+                return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+            }
+            return vec4<f32>(calculate_normal(hit) * 0.5 + 0.5, 1.0);
+        }
+        // The distance along the ray, white up close and fading to black
+        case 2u: {
+            if (!found) {
+                return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+            }
+            return vec4<f32>(vec3<f32>(1.0 / (1.0 + travelled)), 1.0);
+        }
+        // The amount of march steps relative to the maximum
+        default: {
+            return vec4<f32>(vec3<f32>(f32(steps) / f32(max(max_iters, 1))), 1.0);
+        }
+    }
+}
+
 /// Cast a ray
-fn cast_ray(ray: ptr<function, Ray>, id: vec3<u32>, max_iters: i32) -> RayHit {
+fn cast_ray(ray: ptr<function, Ray>, id: vec3<u32>, max_iters: i32, near: f32) -> RayHit {
     // The potential hit
     var hit: RayHit;
     hit.color = uniforms.background_color;
+    hit.roughness = 1.0;
     hit.distance = f32(max_iters);
-    hit = map((*ray).position, hit);
 
+    // Start marching at the near plane
+    var travelled = near;
+    hit = map((*ray).position + travelled * (*ray).direction, hit);
     // The distance travelled so far
-    var travelled = hit.distance;
+    travelled += hit.distance;
+    // The amount of march steps, shown by the step count output
+    var steps = 0;
     
     for (var i = 0; i < max_iters && travelled > 0.01; i += 1) {
+        steps = i + 1;
+        invocation_steps += 1u;
         // Calculate the ray's position
         let raypos = (*ray).position + travelled * (*ray).direction;
         // Update the potential hit
         hit = map(raypos, hit);
         // Check if the potential hit is close enough
-        if (hit.distance < 0.001) {
+        if (hit.distance < uniforms.hit_epsilon) {
+            if (uniforms.output_mode != 0u) {
+                hit.color = debug_color(hit, true, travelled, steps, max_iters);
+                return hit;
+            }
             // Update the lighting
-            hit.color = vec4<f32>(lighting(hit), 1.0);
+            hit.color = vec4<f32>(lighting(hit, normalize((*ray).direction)), 1.0);
             return hit;
         }   
-        if (travelled > 1000.0) {
+        // Stop at the far plane and shade the background
+        if (travelled > uniforms.far) {
             break;
         }
         travelled += hit.distance;
     }
 
-    hit.color = uniforms.background_color;
+    if (uniforms.output_mode != 0u) {
+        hit.color = debug_color(hit, false, travelled, steps, max_iters);
+        return hit;
+    }
+    hit.color = sky(normalize((*ray).direction));
+    // Make the background transparent when exporting for compositing
+    if (uniforms.alpha_background != 0) {
+        hit.color.w = 0.0;
+    }
     return hit;
 }
 
+/// Blend a color towards the fog color by the distance from the camera
+fn apply_fog(color: vec3<f32>, distance: f32) -> vec3<f32> {
+    let amount = 1.0 - exp(-uniforms.fog_density * distance);
+    return mix(color, uniforms.fog_color.xyz, amount);
+}
+
 /// Shade a pixel
+/// Metallic hits are mixed with the color of the reflected ray, for up to `max_bounces` bounces
 fn shade(id: vec3<u32>, uv: vec2<f32>, ray: ptr<function, Ray>) -> vec4<f32> {
     // Get the hit 
-    let hit = cast_ray(ray, id, i32(uniforms.max_steps));
-    // Return the hit color
-    return hit.color;
+    let hit = cast_ray(ray, id, i32(uniforms.max_steps), uniforms.near);
+    if (hit.distance < uniforms.hit_epsilon) {
+        invocation_hits += 1u;
+    }
+    // Write the guide of the denoiser, the misses get no normal and the far distance
+    if (uniforms.denoise != 0) {
+        var pixel_guide = vec4<f32>(0.0, 0.0, 0.0, uniforms.far);
+        if (hit.distance < uniforms.hit_epsilon) {
+            pixel_guide = vec4<f32>(calculate_normal(hit), length(hit.position - (*ray).position));
+        }
+        guide[id.y * uniforms.resolution.x + id.x] = pixel_guide;
+    }
+    if (uniforms.output_mode != 0u) {
+        // Return the hit color
+        return hit.color;
+    }
+
+    // The sum of the colors so far and how much the next bounce contributes to it
+    var color = vec3<f32>(0.0);
+    var weight = 1.0;
+    var current = hit;
+    var direction = normalize((*ray).direction);
+    // Clamp the bounces to MAX_BOUNCES of the tracer to bound the cost
+    for (var bounce = 0u; bounce < min(uniforms.max_bounces, 8u); bounce += 1u) {
+        // Stop at misses and at surfaces that don't reflect
+        if (current.distance >= uniforms.hit_epsilon || current.metallic <= 0.0) {
+            break;
+        }
+        color += weight * (1.0 - current.metallic) * current.color.xyz;
+        weight *= current.metallic;
+        // Reflect the ray, starting just off the surface so it doesn't hit itself
+        let normal = calculate_normal(current);
+        direction = reflect(direction, normal);
+        var reflected = Ray(current.position + normal * 0.01, direction);
+        current = cast_ray(&reflected, id, i32(uniforms.max_steps), 0.02);
+    }
+    color += weight * current.color.xyz;
+    // Fade the hits into the fog, the background is left as is
+    if (uniforms.fog_density > 0.0 && hit.distance < uniforms.hit_epsilon) {
+        color = apply_fog(color, length(hit.position - (*ray).position));
+    }
+    // Premultiply a transparent background, so averaging the samples doesn't blend in the sky
+    if (uniforms.alpha_background != 0) {
+        color *= hit.color.w;
+    }
+    return vec4<f32>(color, hit.color.w);
+}
+
+/// Map a linear color to the range of the frame, applying the exposure
+fn tonemap(color: vec3<f32>) -> vec3<f32> {
+    let exposed = color * uniforms.exposure;
+    switch (uniforms.tonemap_mode) {
+        // Reinhard
+        case 1u: {
+            return exposed / (vec3<f32>(1.0) + exposed);
+        }
+        // Fit of the ACES filmic curve
+        case 2u: {
+            let numerator = exposed * (2.51 * exposed + vec3<f32>(0.03));
+            let denominator = exposed * (2.43 * exposed + vec3<f32>(0.59)) + vec3<f32>(0.14);
+            return clamp(numerator / denominator, vec3<f32>(0.0), vec3<f32>(1.0));
+        }
+        // Only the exposure, the frame clamps the color
+        default: {
+            return exposed;
+        }
+    }
+}
+
+/// Encode a linear color as srgb, the frame stores srgb so the saved images match the screen
+fn srgb_from_linear(color: vec3<f32>) -> vec3<f32> {
+    let clamped = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+    let lower = clamped * 12.92;
+    let higher = 1.055 * pow(clamped, vec3<f32>(1.0 / 2.4)) - vec3<f32>(0.055);
+    return select(higher, lower, clamped <= vec3<f32>(0.0031308));
 }
 
 /// """ 
 /// Entrypoint
+/// The workgroup size is replaced by the tracer to fit the limits of the gpu
 /// """
 @compute
 @workgroup_size(16, 16, 1)
 fn main(
     @builtin(global_invocation_id) id: vec3<u32>,
 ) { 
-    // Calculate the uv
-    var uv = vec2<f32>(id.xy)/vec2<f32>(uniforms.resolution);
-    // Offset so that the centre is the origin
-    uv -= vec2<f32>(0.5);
-    uv.y *= -1.0;
-    // Create the ray
-    var ray = create_camera_ray(uv, camera);
-    // Shade the pixel
-    textureStore(output, vec2<i32>(id.xy), shade(id, uv, &ray));
+    // Offset the invocation into the render region
+    let pixel = id.xy + uniforms.region_offset;
+    // Skip the invocations outside of the region or the frame
+    if (any(id.xy >= uniforms.region_size) || any(pixel >= uniforms.resolution)) {
+        return;
+    }
+    // Get the pixel in the whole image, the frame is only a tile of it when rendering tiled
+    let image_pixel = pixel + uniforms.tile_offset;
+    var image_resolution = uniforms.resolution;
+    if (all(uniforms.full_resolution > vec2<u32>(0u))) {
+        image_resolution = uniforms.full_resolution;
+    }
+    // Jitter the ray within the pixel when accumulating, the first sample isn't jittered
+    var jitter = vec2<f32>(0.0);
+    if (uniforms.accumulate != 0 && uniforms.sample_count > 0u) {
+        jitter = hash2(image_pixel, uniforms.sample_count) - vec2<f32>(0.5);
+    }
+    // Trace multiple rays through the pixel and average them to smooth the edges
+    let samples = max(uniforms.samples_per_pixel, 1u);
+    var color = vec4<f32>(0.0);
+    for (var i = 0u; i < samples; i += 1u) {
+        // Spread the rays over the pixel with the R2 sequence, the first ray goes through the centre
+        let offset = fract(f32(i) * vec2<f32>(0.7548777, 0.5698403) + vec2<f32>(0.5)) - vec2<f32>(0.5);
+        // Calculate the uv
+        var uv = (vec2<f32>(image_pixel) + jitter + offset)/vec2<f32>(image_resolution);
+        // Offset so that the centre is the origin
+        uv -= vec2<f32>(0.5);
+        uv.y *= -1.0;
+        // Stretch the horizontal axis by the aspect ratio so the image isn't distorted
+        uv.x *= f32(image_resolution.x) / f32(image_resolution.y);
+        // Create the ray
+        var ray = create_camera_ray(uv, camera);
+        // Blur what isn't in focus by starting every ray somewhere else on the lens
+        if (camera.aperture > 0.0) {
+            ray = apply_lens(ray, camera, hash2(image_pixel, (uniforms.sample_count * samples + i) ^ 0x5bd1e995u));
+        }
+        // Shade the pixel
+        color += shade(vec3<u32>(pixel, id.z), uv, &ray);
+    }
+    color = color / vec4<f32>(f32(samples));
+    // Average the sample with the previous samples
+    if (uniforms.accumulate != 0) {
+        let index = pixel.y * uniforms.resolution.x + pixel.x;
+        var sum = color;
+        if (uniforms.sample_count > 0u) {
+            sum += accumulation[index];
+        }
+        accumulation[index] = sum;
+        color = sum / f32(uniforms.sample_count + 1u);
+    }
+    // Undo the premultiplied alpha of a transparent background, the png stores straight alpha
+    // Pixels that are fully transparent stay black, so the checkerboard behind the preview shows through
+    if (uniforms.output_mode == 0u && uniforms.alpha_background != 0 && color.w > 0.0) {
+        color = vec4<f32>(color.xyz / color.w, color.w);
+    }
+    // Tonemap the shaded output after accumulating, so the samples are averaged in linear space
+    if (uniforms.output_mode == 0u) {
+        color = vec4<f32>(tonemap(color.xyz), color.w);
+    }
+    textureStore(output, vec2<i32>(pixel), vec4<f32>(srgb_from_linear(color.xyz), color.w));
+    // Add the counters of the pixel once, so the atomics aren't hammered by every step
+    if (uniforms.collect_stats != 0u) {
+        atomicAdd(&stats.steps, invocation_steps);
+        atomicAdd(&stats.hits, invocation_hits);
+        atomicAdd(&stats.rays, samples);
+        atomicAdd(&stats.pixels, 1u);
+    }
 }
\ No newline at end of file
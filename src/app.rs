@@ -1,13 +1,102 @@
-use std::{io::Write, marker::PhantomData, time::Instant};
+use std::{
+    collections::VecDeque,
+    io::Write,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 use pollster::block_on;
 
 use crate::{
-    render::RenderContext,
-    tracer::{Camera, Tracer, Uniforms, VoxelGrid, Voxel},
+    config::Config,
+    error::VoxelError,
+    history::{Edit, History, Settings},
+    palette::Palette,
+    reference::PickResult,
+    render::{AdapterOptions, RenderContext},
+    scene::Scene,
+    stats::RenderStats,
+    tracer::{
+        save_with_metadata, Camera, FrameReadback, Light, ShaderOptions, Tracer, Uniforms, UniformsBuilder, Voxel, VoxelGrid, ACCELERATION_BVH, ACCELERATION_LINEAR,
+        LIGHT_DIRECTIONAL, LIGHT_POINT, MAX_BOUNCES, PATTERN_CHECKER, PATTERN_NOISE, PATTERN_SOLID, MAX_LIGHTS, OUTPUT_DEPTH, OUTPUT_NORMALS, OUTPUT_SHADED, OUTPUT_STEPS, PROJECTION_ORTHOGRAPHIC,
+        PROJECTION_PERSPECTIVE, SKY_FLAT, SKY_GRADIENT,
+        SKY_PROCEDURAL, TONEMAP_ACES, TONEMAP_NONE, TONEMAP_REINHARD,
+    },
     window::Window,
 };
 
+// The closest and furthest distance the camera can zoom to the look at point
+const MIN_ZOOM_DISTANCE: f32 = 0.1;
+const MAX_ZOOM_DISTANCE: f32 = 1000.0;
+// The amount the distance shrinks per scroll step
+const ZOOM_SPEED: f32 = 0.1;
+// How long the scroll wheel has to stay still before a zoom is recorded as an edit
+const ZOOM_RECORD_DELAY: Duration = Duration::from_millis(300);
+// The angle in radians the camera orbits per point the mouse is dragged
+const ORBIT_SPEED: f32 = 0.01;
+// How close the camera can orbit to the up axis, so it never flips over the look at point
+const MAX_ORBIT_ELEVATION: f32 = 89.0 * std::f32::consts::PI / 180.0;
+// The distance the camera flies per second by default
+const DEFAULT_FLY_SPEED: f32 = 2.0;
+
+// The folder the screenshots are saved to
+const SCREENSHOT_DIR: &str = "screenshots";
+// How long a status message stays visible
+const STATUS_DURATION: Duration = Duration::from_secs(3);
+// The size in points of a cell of the checkerboard behind a transparent frame
+const CHECKER_SIZE: f32 = 8.0;
+// How long the window has to keep the same size before the frame is resized to it
+const RESIZE_DELAY: Duration = Duration::from_millis(100);
+
+// The highest fps that is shown, very short frames would otherwise show absurd values
+const MAX_DISPLAYED_FPS: f32 = 10_000.0;
+
+// The amount of frames the frame time graph and the averages cover
+const FRAME_HISTORY: usize = 120;
+
+/// The default maximum amount of edits that can be undone
+pub const MAX_HISTORY_DEPTH: usize = 100;
+
+/// A single frame render that is still running on the gpu
+struct PendingRender {
+    // The image that holds the frame once the gpu is done
+    frame: egui::TextureId,
+    // The time the render was started
+    start: Instant,
+    // Set by wgpu once the submitted work has finished
+    done: Arc<AtomicBool>,
+}
+
+/// An image save that is waiting on the gpu to copy the frame
+struct PendingSave {
+    // The file the image is written to
+    path: PathBuf,
+    readback: FrameReadback,
+    // The palette the image is quantized to and the flag for if it's dithered
+    quantize: Option<(Palette, bool)>,
+    // The settings the frame was rendered with, these are stored in the png
+    camera: Camera,
+    uniforms: Uniforms,
+}
+
+/// A turntable export that writes one frame per app frame
+struct TurntableExport {
+    // The folder the frames are written to
+    dir: PathBuf,
+    // The next frame to write
+    frame: u32,
+    frames: u32,
+    radius: f32,
+    // The camera the orbit starts from
+    camera: Camera,
+}
+
 /// De main applicatie struct
 /// Behandeld de control-flow van de applicatie
 pub struct App {
@@ -17,71 +106,141 @@ pub struct App {
     egui_platform: egui_sdl2_platform::Platform,
     // The resuling image to be drawn
     frame: egui::TextureId,
+    // The amount of time the gpu spent on the last measured trace in milliseconds
+    gpu_time: Option<f32>,
+    // The counters of the last trace that collected stats
+    stats: Option<RenderStats>,
     // The amount of time it took to render the frame in milliseconds
     frame_time: f32,
     // The amount of time it took to render the screen in seconds
     delta_time: f32,
+    // The delta time and frame time of the last frames, the newest is at the back
+    frame_history: VecDeque<(f32, f32)>,
+    // The flag for if the averages of the last frames are shown instead of the last frame
+    smooth_frame_times: bool,
+    // The single frame render that is waiting on the gpu
+    pending_render: Option<PendingRender>,
 
     // The voxel grid
     grid: VoxelGrid,
+    // The lights
+    lights: Vec<Light>,
     // The uniforms
     uniforms: Uniforms,
     // The camera
     camera: Camera,
+    // The distance the camera flies per second with the movement keys
+    fly_speed: f32,
+
+    // The maximum height of an imported heightmap
+    heightmap_height: u32,
+    // The flag for if an imported heightmap should be colored by height
+    heightmap_colorize: bool,
+
+    // The index of the voxel that is being edited
+    selected_voxel: usize,
+    // The grid cells under the cursor
+    hovered_cell: Option<PickResult>,
+
+    // The undo and redo history
+    history: History,
+    // The settings as of the last recorded edit
+    committed_settings: Settings,
+    // The scene the accumulated samples belong to
+    accumulated_scene: (Settings, Vec<Light>, VoxelGrid),
+    // The selected voxel as of the last recorded edit
+    committed_voxel: Option<(usize, Voxel)>,
+    // The palette of the grid as of the last recorded edit
+    committed_palette: Vec<glam::Vec3>,
+    // When the camera was last zoomed with the scroll wheel, the zoom is recorded once the scrolling stops
+    zoomed_at: Option<Instant>,
+
+    // The last error that occured, shown in the ui
+    error: Option<String>,
+    // The error of the shader that failed to reload, the previous shader is used until it compiles
+    shader_error: Option<String>,
+    // The last status message and the time it was shown
+    status: Option<(String, Instant)>,
+    // The saved images that are waiting on the gpu
+    pending_saves: Vec<PendingSave>,
+    // The screenshots and saved images that are still being written to disk
+    screenshots: Vec<(PathBuf, JoinHandle<anyhow::Result<()>>)>,
+
+    // The palette the saved frames are quantized to
+    palette: Option<Palette>,
+    // The flag for if the saved frames are quantized to the palette
+    quantize: bool,
+    // The flag for if the quantized frames are dithered
+    dither: bool,
+
+    // The amount of frames and the orbit radius of a turntable export
+    turntable_frames: u32,
+    turntable_radius: f32,
+    // The turntable export that is running
+    turntable: Option<TurntableExport>,
+    // The resolution and the tile size of a high resolution export
+    export_resolution: glam::UVec2,
+    export_tile_size: u32,
 
+    // The flag for if the bounding box and axis overlay should be drawn
+    show_overlay: bool,
+    // The flag for if the frame should be resized to the central panel
+    resize_pending: bool,
+    // When the window was last resized, the frame waits for the resizing to settle
+    resized_at: Option<Instant>,
+    // The flag for if the resolution follows the central panel, otherwise the resolution is set by hand
+    fit_window: bool,
+    // The resolution that is used when not fitting the window
+    fixed_resolution: glam::UVec2,
     // The flag for if the frame should be rendered in realrime
     realtime: bool,
+    // The flag for if the time and the frame counter are frozen
+    paused: bool,
     // The flag for if the app should run
     should_run: bool,
 }
 
 impl App {
     /// Construct a new [`App`]
-    pub fn new() -> anyhow::Result<Self> {
+    /// The shader is read from the path of the shader options if it's set, otherwise the bundled shader is used
+    pub fn new(options: AdapterOptions, shader: ShaderOptions) -> anyhow::Result<Self> {
         // Create the window
         let window = Window::new("Voxel Renderer", 1280, 720, true)?;
         // Create the render context.
         // Pollster is used here to execute the async method in a single-threaded context
-        let mut render_context = pollster::block_on(RenderContext::new(&window))?;
+        let mut render_context = pollster::block_on(RenderContext::new(&window, &options))?;
         // Create the egui platform
         let egui_platform = egui_sdl2_platform::Platform::new(window.size())?;
         // Create the grid
-        let grid = VoxelGrid(vec![
+        let grid = VoxelGrid::from_voxels(vec![
             Voxel::new(glam::ivec3(0, 0, 0), glam::vec3(1.0, 1.0, 1.0)),
             Voxel::new(glam::ivec3(1, 1, 0), glam::vec3(0.0, 1.0, 0.0)),
             Voxel::new(glam::ivec3(1, 2, 0), glam::vec3(1.0, 1.0, 0.0)),
             Voxel::new(glam::ivec3(-1, 1, 0), glam::vec3(0.0, 0.0, 1.0)),
             Voxel::new(glam::ivec3(0, 1, -1), glam::vec3(1.0, 0.0, 0.0)),
         ]);
-        // Create the uniforms
+        // Create the lights
+        let lights = vec![Light::default()];
+        // Load the settings from the previous session
+        let config = Config::load().unwrap_or_default();
+        // Create the uniforms, the saved settings are clamped in case the config was edited by hand
         let uniforms = Uniforms {
             time: 0.0,
-            resolution: glam::uvec2(1080, 1080),
             frames: 0,
-            max_steps: 50,
-            voxel_amount: grid.0.len() as u32,
-            light_position: glam::vec3(0.0, 0.25, 0.0),
-            background_color: glam::vec4(0.0, 0.0, 0.0, 1.0),
-            floor_color: glam::vec4(0.1, 0.1, 0.1, 1.0),
-            object_color: glam::vec3(1.0, 1.0, 1.0),
-            sun_intensity: 1.0,
-            smoothing: 0.0,
-            ambient_occlusion: 20
+            voxel_amount: grid.len() as u32,
+            light_count: lights.len() as u32,
+            ..UniformsBuilder::from(config.uniforms).build()
         };
         // Create the camera
-        let camera = Camera {
-            position: glam::vec3(0.0, 0.0, 2.0),
-            look_at: glam::vec3(0.0, 0.0, 0.0),
-            zoom: 1.0,
-        };
+        let camera = config.camera;
         // Create the tracer
-        let tracer = Tracer::new(&mut render_context, &uniforms)?;
+        let mut tracer = Tracer::new(&render_context.gpu, &uniforms, &shader)?;
         // Trace the frame
         let before = Instant::now();
-        let frame = tracer
-            .trace(&mut render_context, uniforms, &grid, camera)
-            .unwrap();
+        let frame = tracer.trace(&mut render_context, uniforms, &grid, &lights, camera)?;
         let frame_time = (before.elapsed().as_secs_f64() * 1000.0) as f32;
+        let accumulated_scene = (Settings::new(uniforms, camera), lights.clone(), grid.clone());
+        let committed_palette = grid.1.clone();
 
         Ok(Self {
             window,
@@ -90,11 +249,47 @@ impl App {
             egui_platform,
             frame,
             frame_time,
+            gpu_time: None,
+            stats: None,
             delta_time: 0.0,
+            frame_history: VecDeque::with_capacity(FRAME_HISTORY),
+            smooth_frame_times: true,
+            pending_render: None,
             grid,
+            lights,
             uniforms,
             camera,
-            realtime: false,
+            fly_speed: DEFAULT_FLY_SPEED,
+            heightmap_height: 16,
+            heightmap_colorize: true,
+            selected_voxel: 0,
+            hovered_cell: None,
+            history: History::new(MAX_HISTORY_DEPTH),
+            committed_settings: Settings::new(uniforms, camera),
+            accumulated_scene,
+            committed_voxel: None,
+            committed_palette,
+            zoomed_at: None,
+            error: None,
+            shader_error: None,
+            status: None,
+            pending_saves: Vec::new(),
+            screenshots: Vec::new(),
+            palette: None,
+            quantize: false,
+            dither: false,
+            turntable_frames: 60,
+            turntable_radius: 2.0,
+            turntable: None,
+            export_resolution: glam::uvec2(7680, 4320),
+            export_tile_size: 1024,
+            show_overlay: false,
+            resize_pending: true,
+            resized_at: None,
+            fit_window: true,
+            fixed_resolution: uniforms.resolution,
+            realtime: config.realtime,
+            paused: false,
             should_run: true,
         })
     }
@@ -108,16 +303,59 @@ impl App {
             .update_time(start_time.elapsed().as_secs_f64());
         // Get the egui context and start the egui frame
         let egui_ctx = self.egui_platform.context();
+        // Scale the ui to match the display
+        egui_ctx.set_pixels_per_point(self.window.scale_factor());
+
+        // Show the single frame render once the gpu has finished it
+        self.render_context.gpu.device.poll(wgpu::Maintain::Poll);
+        self.gpu_time = self.tracer.gpu_time();
+        self.stats = self.tracer.stats();
+        if let Some(pending) = self.pending_render.take() {
+            if pending.done.load(Ordering::Acquire) {
+                self.frame = pending.frame;
+                self.frame_time = (pending.start.elapsed().as_secs_f64() * 1000.0) as f32;
+            } else {
+                self.pending_render = Some(pending);
+            }
+        }
+
+        // Reload the shader when its file changes
+        self.reload_shader(false);
+
+        // Start accumulating from scratch when the scene changes
+        if self.uniforms.accumulate != 0 {
+            self.check_accumulation();
+        }
+
+        // Write the next frame of the turntable export
+        self.export_turntable_step();
+
+        // Fly the camera with the held movement keys, unless egui is using the keyboard
+        if !egui_ctx.wants_keyboard_input() {
+            self.fly(self.window.movement().normalize_or_zero() * self.delta_time);
+        }
 
         // Render the frame if in realtime mode
         if self.realtime {
             let before = Instant::now();
-            self.frame = self.tracer.trace(
+            match self.tracer.trace(
                 &mut self.render_context,
                 self.uniforms,
                 &self.grid,
+                &self.lights,
                 self.camera,
-            )?;
+            ) {
+                Ok(frame) => self.frame = frame,
+                // Keep showing the last frame, so the error can be fixed from the ui
+                Err(e @ VoxelError::Validation(_)) => {
+                    // Only log the error once instead of every frame
+                    if self.error.is_none() {
+                        log::error!("Failed to trace the frame: {}", e);
+                    }
+                    self.error = Some(format!("Failed to trace the frame: {}", e));
+                }
+                Err(e) => return Err(e.into()),
+            }
             self.frame_time = (before.elapsed().as_secs_f64() * 1000.0) as f32;
         }
 
@@ -130,59 +368,1264 @@ impl App {
         let paint_jobs = self.egui_platform.tessellate(&full_output);
 
         // Render to the screen
-        self.render_context
-            .render(&self.window, full_output, paint_jobs)?;
+        match self.render_context.render(&self.window, full_output, paint_jobs) {
+            Ok(()) => {}
+            // Reconfigure the surface and skip the frame
+            Err(VoxelError::SurfaceLost) => {
+                log::warn!("The surface was lost, reconfiguring it");
+                let (width, height) = self.window.drawable_size();
+                self.render_context.resize(width, height);
+            }
+            Err(e) => return Err(e.into()),
+        }
 
         // Handle the window events
         self.window
             .handle_events(&mut self.render_context, &mut self.egui_platform);
+        // Take a screenshot if requested
+        if self.window.take_screenshot_request() {
+            if let Err(e) = self.take_screenshot() {
+                log::error!("Failed to take a screenshot: {}", e);
+                self.error = Some(format!("Failed to take a screenshot: {}", e));
+            }
+        }
+        self.poll_saves();
+        self.poll_screenshots();
+        // Resize the frame to the central panel on the next frame
+        if self.window.take_resized() {
+            self.resize_pending = true;
+            self.resized_at = Some(Instant::now());
+        }
         // Check if the app should be running
         self.should_run = !self.window.should_close();
         // Calculate the delta time
         self.delta_time = start.elapsed().as_secs_f32();
+        // Remember the times for the graph
+        if self.frame_history.len() == FRAME_HISTORY {
+            self.frame_history.pop_front();
+        }
+        self.frame_history.push_back((self.delta_time, self.frame_time));
+
+        // Update the uniforms, the time is advanced instead of set so it can be scrubbed in the ui
+        if !self.paused {
+            self.uniforms.time += self.delta_time;
+            self.uniforms.frames += 1;
+        }
+        Ok(())
+    }
+
+    /// Position the camera so the whole grid is in view
+    /// Leaves the camera unchanged if the grid is empty
+    pub fn frame_scene(&mut self) {
+        if self.grid.is_empty() {
+            return;
+        }
+        // Calculate the bounding box of the grid in world space
+        let (min, max) = self.grid.world_bounds();
+        let center = (min + max) / 2.0;
+        let radius = (max - min).length() / 2.0;
+
+        // Calculate the half field of view from the zoom, the shader places the image plane at `zoom - 0.1`
+        // The narrowest side of the frame decides the field of view
+        let tan_half_fov = 0.5 * self.aspect_ratio().min(1.0) / (self.camera.zoom - 0.1).max(0.01);
+        // Calculate the distance at which the bounding sphere fits the view
+        let distance = radius * (1.0 + tan_half_fov * tan_half_fov).sqrt() / tan_half_fov;
+
+        // Keep the current viewing direction
+        let direction = (self.camera.position - self.camera.look_at)
+            .try_normalize()
+            .unwrap_or(glam::Vec3::Z);
+        self.camera.look_at = center;
+        self.camera.position = center + direction * distance;
+        // The orthographic view has to be as large as the bounding sphere
+        if self.camera.projection == PROJECTION_ORTHOGRAPHIC {
+            self.camera.ortho_scale = 2.0 * radius / self.aspect_ratio().min(1.0);
+        }
+    }
+
+    /// Zoom the camera towards the point under the cursor
+    /// Zooms towards the center if there is no cursor position
+    pub fn zoom_to_cursor(&mut self, scroll: f32, uv: Option<glam::Vec2>) {
+        // Moving an orthographic camera doesn't change the size, so shrink the view instead
+        if self.camera.projection == PROJECTION_ORTHOGRAPHIC {
+            self.camera.ortho_scale *= (1.0 - ZOOM_SPEED).powf(scroll / 8.0);
+            return;
+        }
+        let offset = self.camera.look_at - self.camera.position;
+        let distance = offset.length();
+        if distance <= 0.0 {
+            return;
+        }
+
+        // Find the point under the cursor at the depth of the look at point
+        let direction = self.camera.ray_direction(uv.unwrap_or(glam::Vec2::ZERO));
+        let depth = distance / direction.dot(offset / distance).max(0.01);
+        let target = self.camera.position + direction * depth;
+
+        // Scale the camera around the target, clamping the distance so it never flips through the look at point
+        let scale = (1.0 - ZOOM_SPEED).powf(scroll / 8.0);
+        let scale = (distance * scale).clamp(MIN_ZOOM_DISTANCE, MAX_ZOOM_DISTANCE) / distance;
+        self.camera.position = target + (self.camera.position - target) * scale;
+        self.camera.look_at = target + (self.camera.look_at - target) * scale;
+    }
+
+    /// Orbit the camera around the look at point by a mouse drag in points
+    /// The distance to the look at point stays the same, only the angle changes
+    pub fn orbit(&mut self, drag: glam::Vec2) {
+        let offset = self.camera.position - self.camera.look_at;
+        let radius = offset.length();
+        let up = self.camera.up.try_normalize().unwrap_or(glam::Vec3::Y);
+        if radius <= 0.0 {
+            return;
+        }
+
+        // Rotate around the up axis for horizontal movement
+        let offset = glam::Quat::from_axis_angle(up, -drag.x * ORBIT_SPEED) * offset;
+        // Rotate towards the up axis for vertical movement, clamping the elevation
+        let elevation = (offset.dot(up) / radius).clamp(-1.0, 1.0).asin();
+        let target = (elevation + drag.y * ORBIT_SPEED).clamp(-MAX_ORBIT_ELEVATION, MAX_ORBIT_ELEVATION);
+        let offset = match up.cross(offset).try_normalize() {
+            Some(right) => glam::Quat::from_axis_angle(right, elevation - target) * offset,
+            None => offset,
+        };
+        // Renormalize to keep the radius from drifting
+        self.camera.position = self.camera.look_at + offset.normalize() * radius;
+    }
+
+    /// Move the camera along its right, up and forward directions, scaled by the fly speed
+    /// The look at point moves along, so the view direction stays the same
+    pub fn fly(&mut self, movement: glam::Vec3) {
+        if movement == glam::Vec3::ZERO {
+            return;
+        }
+        let (forward, right, up) = self.camera.basis();
+        let offset = (right * movement.x + up * movement.y + forward * movement.z) * self.fly_speed;
+        self.camera.position += offset;
+        self.camera.look_at += offset;
+    }
+
+    /// Reset the accumulated samples if the camera, the settings or the scene changed since the last check
+    fn check_accumulation(&mut self) {
+        let settings = Settings::new(self.uniforms, self.camera);
+        let (last_settings, last_lights, last_grid) = &self.accumulated_scene;
+        if settings != *last_settings || self.lights != *last_lights || self.grid != *last_grid {
+            self.tracer.reset_accumulation();
+            self.accumulated_scene = (settings, self.lights.clone(), self.grid.clone());
+        }
+    }
+
+    /// Start rendering a single frame without waiting for the gpu
+    /// The frame is shown once the gpu is done, see [`App::execute`]
+    fn start_render(&mut self) {
+        let start = Instant::now();
+        match self.tracer.trace(
+            &mut self.render_context,
+            self.uniforms,
+            &self.grid,
+            &self.lights,
+            self.camera,
+        ) {
+            Ok(frame) => {
+                // Get notified when the trace has finished
+                let done = Arc::new(AtomicBool::new(false));
+                let flag = done.clone();
+                self.render_context
+                    .gpu
+                    .queue
+                    .on_submitted_work_done(move || flag.store(true, Ordering::Release));
+                self.pending_render = Some(PendingRender { frame, start, done });
+            }
+            Err(e) => {
+                log::error!("Failed to render the frame: {}", e);
+                self.error = Some(format!("Failed to render: {}", e));
+            }
+        }
+    }
+
+    /// Save the frame to the screenshot folder with the current time as its name
+    /// The image is encoded and written on another thread so the app doesn't stutter
+    fn take_screenshot(&mut self) -> anyhow::Result<()> {
+        std::fs::create_dir_all(SCREENSHOT_DIR)?;
+        let name = chrono::Local::now().format("%Y-%m-%dT%H-%M-%S%.3f");
+        self.start_save(Path::new(SCREENSHOT_DIR).join(format!("{}.png", name)), None);
+        Ok(())
+    }
+
+    /// Start saving the frame to a file without waiting for the gpu
+    /// The image is quantized to the palette if given, and written once the gpu has copied the frame
+    fn start_save(&mut self, path: PathBuf, quantize: Option<(Palette, bool)>) {
+        let readback = self.tracer.start_read_frame(&self.render_context.gpu);
+        self.pending_saves.push(PendingSave {
+            path,
+            readback,
+            quantize,
+            camera: self.camera,
+            uniforms: self.uniforms,
+        });
+    }
+
+    /// Write the saved images the gpu is done copying on another thread
+    fn poll_saves(&mut self) {
+        let mut pending = vec![];
+        for save in std::mem::take(&mut self.pending_saves) {
+            match save.readback.try_finish() {
+                None => pending.push(save),
+                Some(Ok(mut image)) => {
+                    let path = save.path.clone();
+                    let handle = std::thread::spawn(move || {
+                        if let Some((palette, dither)) = save.quantize {
+                            palette.quantize(&mut image, dither);
+                        }
+                        Ok(save_with_metadata(&image, &save.path, &save.camera, &save.uniforms)?)
+                    });
+                    self.screenshots.push((path, handle));
+                }
+                Some(Err(e)) => {
+                    log::error!("Failed to read the frame for {}: {}", save.path.display(), e);
+                    self.error = Some(format!("Failed to save image: {}", e));
+                }
+            }
+        }
+        self.pending_saves = pending;
+    }
+
+    /// Check which screenshots and saved images have been written to disk
+    fn poll_screenshots(&mut self) {
+        let (finished, running): (Vec<_>, Vec<_>) = std::mem::take(&mut self.screenshots)
+            .into_iter()
+            .partition(|(_, handle)| handle.is_finished());
+        self.screenshots = running;
+        for (path, handle) in finished {
+            match handle.join() {
+                Ok(Ok(())) => {
+                    log::info!("Saved the frame to {}", path.display());
+                    self.status = Some((format!("Saved {}", path.display()), Instant::now()));
+                }
+                Ok(Err(e)) => {
+                    log::error!("Failed to save the frame to {}: {}", path.display(), e);
+                    self.error = Some(format!("Failed to save image: {}", e));
+                }
+                Err(_) => self.error = Some("The thread saving the image panicked".to_string()),
+            }
+        }
+    }
+
+    /// Render the scene at a resolution that can be larger than the gpu supports, one tile at a time
+    /// The tiles are traced right away, the image is written on another thread
+    pub fn export_high_res(&mut self, path: PathBuf) -> anyhow::Result<()> {
+        let tile_size = glam::UVec2::splat(self.export_tile_size);
+        let image = Tracer::render_tiled(
+            &self.render_context.gpu,
+            self.export_resolution,
+            tile_size,
+            self.uniforms,
+            &self.grid,
+            &self.lights,
+            self.camera,
+        )?;
+        let (camera, uniforms) = (self.camera, self.uniforms);
+        let target = path.clone();
+        let handle = std::thread::spawn(move || Ok(save_with_metadata(&image, &target, &camera, &uniforms)?));
+        self.screenshots.push((path, handle));
+        Ok(())
+    }
+
+    /// Write a turntable of the scene to a folder as `frame_0000.png` and up
+    /// The camera orbits the look at point once around the up axis at the given radius, keeping its height
+    pub fn export_turntable(&mut self, dir: impl AsRef<Path>, frames: u32, radius: f32) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        for frame in 0..frames {
+            let camera = turntable_camera(self.camera, radius, frame, frames);
+            self.export_turntable_frame(dir.as_ref(), frame, camera)?;
+        }
+        Ok(())
+    }
+
+    /// Trace a frame of a turntable and write it to the folder
+    fn export_turntable_frame(&mut self, dir: &Path, frame: u32, camera: Camera) -> anyhow::Result<()> {
+        // Every frame is a single sample
+        let uniforms = Uniforms {
+            accumulate: 0,
+            ..self.uniforms
+        };
+        self.frame = self
+            .tracer
+            .trace(&mut self.render_context, uniforms, &self.grid, &self.lights, camera)?;
+        let path = dir.join(format!("frame_{:04}.png", frame));
+        block_on(self.tracer.frame_to_image(&path, &self.render_context.gpu))?;
+        Ok(())
+    }
+
+    /// Write the next frame of the running turntable export
+    fn export_turntable_step(&mut self) {
+        let export = match self.turntable.take() {
+            Some(export) => export,
+            None => return,
+        };
+        let camera = turntable_camera(export.camera, export.radius, export.frame, export.frames);
+        if let Err(e) = self.export_turntable_frame(&export.dir, export.frame, camera) {
+            log::error!("Failed to export the turntable to {}: {}", export.dir.display(), e);
+            self.error = Some(format!("Failed to export turntable: {}", e));
+            return;
+        }
+        // The accumulated samples belong to the camera of the app
+        self.tracer.reset_accumulation();
+        if export.frame + 1 < export.frames {
+            self.turntable = Some(TurntableExport {
+                frame: export.frame + 1,
+                ..export
+            });
+        } else {
+            log::info!("Exported {} turntable frames to {}", export.frames, export.dir.display());
+            self.status = Some((format!("Exported the turntable to {}", export.dir.display()), Instant::now()));
+        }
+    }
 
-        // Update the uniforms
-        self.uniforms.time = start_time.elapsed().as_secs_f32();
-        self.uniforms.frames += 1;
+    /// Copy the current frame to the clipboard
+    pub fn copy_frame_to_clipboard(&self) -> anyhow::Result<()> {
+        // Read the frame from the gpu
+        let image = block_on(self.tracer.read_frame(&self.render_context.gpu))?;
+        // Put the image on the clipboard
+        let mut clipboard = arboard::Clipboard::new()?;
+        clipboard.set_image(arboard::ImageData {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            bytes: image.into_raw().into(),
+        })?;
         Ok(())
     }
 
+    /// Draw the bounding box of the grid and the world axes over the frame
+    fn draw_overlay(&self, painter: &egui::Painter, rect: egui::Rect) {
+        // Convert a point in world space to a position on the screen
+        let to_screen = |point: glam::Vec3| {
+            self.camera.project(point).map(|uv| {
+                rect.min
+                    + egui::vec2(
+                        (uv.x / self.aspect_ratio() + 0.5) * rect.width(),
+                        (0.5 - uv.y) * rect.height(),
+                    )
+            })
+        };
+        // Draw a line, skipping it if either end is behind the camera
+        let line = |from: glam::Vec3, to: glam::Vec3, color: egui::Color32| {
+            if let (Some(from), Some(to)) = (to_screen(from), to_screen(to)) {
+                painter.line_segment([from, to], egui::Stroke::new(1.0, color));
+            }
+        };
+
+        // Draw the axes
+        line(glam::Vec3::ZERO, glam::Vec3::X, egui::Color32::RED);
+        line(glam::Vec3::ZERO, glam::Vec3::Y, egui::Color32::GREEN);
+        line(glam::Vec3::ZERO, glam::Vec3::Z, egui::Color32::BLUE);
+
+        // Draw the bounding box
+        if self.grid.is_empty() {
+            return;
+        }
+        let (min, max) = self.grid.world_bounds();
+        let corner = |i: usize| {
+            glam::vec3(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+        for i in 0..8 {
+            for axis in [1, 2, 4] {
+                // Connect each corner to the corners that differ along a single axis
+                if i & axis == 0 {
+                    line(corner(i), corner(i | axis), egui::Color32::YELLOW);
+                }
+            }
+        }
+    }
+
+    /// Reload the shader, or only check if its file changed if `force` isn't set
+    /// The compile error is shown in the ui instead of stopping the app
+    fn reload_shader(&mut self, force: bool) {
+        let result = if force {
+            self.tracer.reload_shader(&self.render_context.gpu).map(|_| true)
+        } else {
+            self.tracer.reload_shader_if_changed(&self.render_context.gpu)
+        };
+        match result {
+            Ok(true) => {
+                self.shader_error = None;
+                self.status = Some(("Reloaded the shader".to_string(), Instant::now()));
+            }
+            Ok(false) => {}
+            Err(e) => {
+                log::error!("Failed to reload the shader: {}", e);
+                self.shader_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Get the width of the frame divided by the height
+    fn aspect_ratio(&self) -> f32 {
+        let resolution = self.tracer.resolution().as_vec2();
+        resolution.x / resolution.y.max(1.0)
+    }
+
+    /// Resize the traced frame and trace it again so the image shows the new texture
+    fn resize_frame(&mut self, width: u32, height: u32) {
+        let previous = self.uniforms.resolution;
+        if let Err(e) = self.tracer.resize(&self.render_context.gpu, width, height) {
+            log::error!("Failed to resize the frame: {}", e);
+            self.error = Some(format!("Failed to resize the frame: {}", e));
+            return;
+        }
+        let resolution = self.tracer.resolution();
+        self.uniforms.resolution = resolution;
+        // Keep rendering the whole frame if the region covered it
+        if self.uniforms.region_offset == glam::UVec2::ZERO && self.uniforms.region_size == previous {
+            self.uniforms.region_size = resolution;
+        }
+        // Keep the region within the frame
+        self.uniforms.region_offset = self.uniforms.region_offset.min(resolution);
+        self.uniforms.region_size = self.uniforms.region_size.min(resolution);
+        // The committed settings follow the resolution so resizing isn't recorded as an edit
+        self.committed_settings = Settings::new(self.uniforms, self.camera);
+
+        match self.tracer.trace(
+            &mut self.render_context,
+            self.uniforms,
+            &self.grid,
+            &self.lights,
+            self.camera,
+        ) {
+            Ok(frame) => self.frame = frame,
+            Err(e) => {
+                log::error!("Failed to trace the resized frame: {}", e);
+                self.error = Some(format!("Failed to trace the resized frame: {}", e));
+            }
+        }
+    }
+
+    /// Apply an edit to the scene
+    /// Outside of realtime mode the frame is traced again, so the edit shows up without pressing render
+    fn apply_edit(&mut self, edit: Edit) {
+        match edit {
+            Edit::Add(index, voxel) => {
+                self.grid.insert_at(index, voxel);
+            }
+            Edit::Remove(index, _) => {
+                self.grid.remove_at(index);
+            }
+            Edit::Change(index, _, voxel) => {
+                self.grid.replace_at(index, voxel);
+            }
+            Edit::Replace(_, grid) => self.grid = grid,
+            Edit::Palette(_, palette) => self.grid.1 = palette,
+            Edit::Settings(_, settings) => {
+                // Keep the fields that aren't tracked by the history
+                self.uniforms = Uniforms {
+                    time: self.uniforms.time,
+                    frames: self.uniforms.frames,
+                    voxel_amount: self.uniforms.voxel_amount,
+                    light_count: self.uniforms.light_count,
+                    resolution: self.uniforms.resolution,
+                    ..settings.uniforms
+                };
+                self.camera = settings.camera;
+            }
+        }
+        // Update the committed state so the applied edit isn't recorded again
+        self.uniforms.voxel_amount = self.grid.len() as u32;
+        self.committed_settings = Settings::new(self.uniforms, self.camera);
+        self.committed_voxel = None;
+        self.committed_palette = self.grid.1.clone();
+        if !self.realtime {
+            self.start_render();
+        }
+    }
+
+    /// Save the grid, the lights, the camera and the uniforms to a ron file
+    pub fn save_scene(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let scene = Scene {
+            grid: self.grid.clone(),
+            lights: self.lights.clone(),
+            camera: self.camera,
+            uniforms: self.uniforms,
+        };
+        scene.save(path)
+    }
+
+    /// Load a scene from a ron file and trace it immediately
+    /// The resolution of the frame is kept and the history is cleared
+    pub fn load_scene(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let scene = Scene::load(path)?;
+        self.grid = scene.grid;
+        self.lights = scene.lights;
+        self.lights.truncate(MAX_LIGHTS);
+        self.camera = scene.camera;
+        self.uniforms = Uniforms {
+            time: self.uniforms.time,
+            frames: self.uniforms.frames,
+            resolution: self.uniforms.resolution,
+            region_offset: self.uniforms.region_offset,
+            region_size: self.uniforms.region_size,
+            voxel_amount: self.grid.len() as u32,
+            light_count: self.lights.len() as u32,
+            ..scene.uniforms
+        };
+
+        // Start over with the new scene
+        self.history.clear();
+        self.committed_settings = Settings::new(self.uniforms, self.camera);
+        self.committed_voxel = None;
+        self.committed_palette = self.grid.1.clone();
+        self.selected_voxel = 0;
+        self.tracer.reset_accumulation();
+        self.frame = self.tracer.trace(
+            &mut self.render_context,
+            self.uniforms,
+            &self.grid,
+            &self.lights,
+            self.camera,
+        )?;
+        Ok(())
+    }
+
+    /// Use the camera and the uniforms a saved png was rendered with
+    /// The scene and the resolution are kept, the change can be undone like any other settings change
+    pub fn load_image_settings(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let (camera, uniforms) = Tracer::load_metadata(path)
+            .ok_or_else(|| anyhow::anyhow!("The image doesn't contain any settings"))?;
+        self.camera = camera;
+        self.uniforms = Uniforms {
+            time: self.uniforms.time,
+            frames: self.uniforms.frames,
+            resolution: self.uniforms.resolution,
+            region_offset: self.uniforms.region_offset,
+            region_size: self.uniforms.region_size,
+            voxel_amount: self.uniforms.voxel_amount,
+            light_count: self.uniforms.light_count,
+            ..uniforms
+        };
+        Ok(())
+    }
+
+    /// Add a voxel with the object color to an empty cell of the grid
+    pub fn add_voxel_at(&mut self, cell: glam::IVec3) {
+        if self.grid.get(cell).is_some() {
+            return;
+        }
+        let edit = Edit::Add(self.grid.len(), Voxel::new(cell, self.uniforms.object_color));
+        self.apply_edit(edit.clone());
+        self.history.push(edit);
+    }
+
+    /// Remove the voxel in a cell of the grid
+    pub fn remove_voxel_at(&mut self, cell: glam::IVec3) {
+        if let Some(index) = self.grid.index_of(cell) {
+            let edit = Edit::Remove(index, self.grid.voxels()[index]);
+            self.apply_edit(edit.clone());
+            self.history.push(edit);
+        }
+    }
+
+    /// Change the maximum amount of edits that can be undone
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history.set_max_depth(depth);
+    }
+
+    /// Undo the last edit
+    pub fn undo(&mut self) {
+        if let Some(edit) = self.history.undo() {
+            self.apply_edit(edit);
+        }
+    }
+
+    /// Redo the last undone edit
+    pub fn redo(&mut self) {
+        if let Some(edit) = self.history.redo() {
+            self.apply_edit(edit);
+        }
+    }
+
+    /// Record the edits made through the ui
+    /// Edits are only recorded once the mouse is released, so dragging a value counts as a single edit
+    /// Flying and scroll zooming are recorded once the movement keys are released and the scroll wheel stops
+    fn record_edits(&mut self, ctx: &egui::Context) {
+        if ctx.input().pointer.any_down() {
+            return;
+        }
+        if self.window.movement() != glam::Vec3::ZERO {
+            return;
+        }
+        if self.zoomed_at.filter(|at| at.elapsed() < ZOOM_RECORD_DELAY).is_some() {
+            return;
+        }
+        self.zoomed_at = None;
+
+        // Record the changed settings
+        let settings = Settings::new(self.uniforms, self.camera);
+        if settings != self.committed_settings {
+            self.history.push(Edit::Settings(Box::new(self.committed_settings), Box::new(settings)));
+            self.committed_settings = settings;
+        }
+
+        // Record the changes to the selected voxel
+        if let Some((index, before)) = self.committed_voxel {
+            if let Some(&after) = self.grid.voxels().get(index) {
+                if after != before {
+                    self.history.push(Edit::Change(index, before, after));
+                }
+            }
+        }
+        self.committed_voxel = self
+            .grid
+            .voxels()
+            .get(self.selected_voxel)
+            .map(|&voxel| (self.selected_voxel, voxel));
+
+        // Record the changes to the palette
+        if self.grid.1 != self.committed_palette {
+            let before = std::mem::replace(&mut self.committed_palette, self.grid.1.clone());
+            self.history.push(Edit::Palette(before, self.grid.1.clone()));
+        }
+    }
+
     /// Draw the ui
     pub fn draw_ui(&mut self, ctx: &egui::Context) {
+        // Handle the undo and redo shortcuts when egui isn't using the keyboard
+        if !ctx.wants_keyboard_input() {
+            let (undo, redo) = {
+                let input = ctx.input();
+                // Ctrl+Shift+Z redoes as well as Ctrl+Y
+                let shift = input.modifiers.shift;
+                (
+                    input.modifiers.command && !shift && input.key_pressed(egui::Key::Z),
+                    input.modifiers.command
+                        && (input.key_pressed(egui::Key::Y) || shift && input.key_pressed(egui::Key::Z)),
+                )
+            };
+            if undo {
+                self.undo();
+            }
+            if redo {
+                self.redo();
+            }
+        }
+
         // Draw the side panel
         egui::SidePanel::right("Config").show(ctx, |ui| {
             // Draw the info
-            ui.label(format!("Fps: {}", 1.0 / self.delta_time));
-            ui.label(format!("FrameMs: {}", self.frame_time));
+            let adapter = &self.render_context.gpu.adapter_info;
+            ui.label(format!("Gpu: {}", adapter.name))
+                .on_hover_text(format!("{:?} on {:?}", adapter.device_type, adapter.backend));
+            let (delta_time, frame_time) = if self.smooth_frame_times && !self.frame_history.is_empty() {
+                let count = self.frame_history.len() as f32;
+                let (delta_sum, frame_sum) = self
+                    .frame_history
+                    .iter()
+                    .fold((0.0, 0.0), |(a, b), (delta, frame)| (a + delta, b + frame));
+                (delta_sum / count, frame_sum / count)
+            } else {
+                (self.delta_time, self.frame_time)
+            };
+            // The delta time is zero before the first frame finished, so there is no fps to show yet
+            let fps = if delta_time > 0.0 {
+                format!("{:.1}", (1.0 / delta_time).min(MAX_DISPLAYED_FPS))
+            } else {
+                "—".to_string()
+            };
+            ui.label(format!("Fps: {}", fps));
+            ui.label(format!("FrameMs: {:.3}", frame_time))
+                .on_hover_text("The cpu time of recording and submitting the trace");
+            let gpu_time = match self.gpu_time {
+                Some(gpu_time) => format!("{:.3}", gpu_time),
+                None if self.tracer.supports_gpu_time() => "—".to_string(),
+                None => "not supported".to_string(),
+            };
+            ui.label(format!("GpuMs: {}", gpu_time))
+                .on_hover_text("The time the gpu spent tracing, measured with timestamp queries");
+            let mut collect_stats = self.uniforms.collect_stats != 0;
+            ui.checkbox(&mut collect_stats, "Collect Stats")
+                .on_hover_text("Count the march steps and the hits, the counting slows the trace down a bit");
+            self.uniforms.collect_stats = collect_stats as u32;
+            if collect_stats {
+                match self.stats {
+                    Some(stats) => {
+                        ui.label(format!("Avg Steps/Pixel: {:.1}", stats.steps_per_pixel()));
+                        ui.label(format!("Hit Rate: {:.1}%", stats.hit_rate() * 100.0));
+                    }
+                    None => {
+                        ui.label("Avg Steps/Pixel: —");
+                    }
+                }
+            }
+            ui.checkbox(&mut self.smooth_frame_times, "Smooth")
+                .on_hover_text(format!("Average over the last {} frames", FRAME_HISTORY));
+            // Draw the graph of the frame durations in milliseconds
+            let durations: Vec<f32> = self
+                .frame_history
+                .iter()
+                .map(|(delta, _)| delta * 1000.0)
+                .collect();
+            egui::plot::Plot::new("frame_times")
+                .height(80.0)
+                .include_y(0.0)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .show_x(false)
+                .show(ui, |plot| plot.line(egui::plot::Line::new(egui::plot::Values::from_ys_f32(&durations))));
+            ui.label(format!("Workgroup: {0}x{0}", self.tracer.workgroup_size()));
+            match self.hovered_cell.map(|pick| pick.cell()) {
+                Some(cell) => ui.label(format!("Cursor: {} {} {}", cell.x, cell.y, cell.z)),
+                None => ui.label("Cursor: —"),
+            };
+            // Draw the history buttons
+            ui.horizontal(|ui| {
+                if ui.add_enabled(self.history.can_undo(), egui::Button::new("Undo")).clicked() {
+                    self.undo();
+                }
+                if ui.add_enabled(self.history.can_redo(), egui::Button::new("Redo")).clicked() {
+                    self.redo();
+                }
+            });
+            // Draw the last status message for a short while
+            if let Some((status, time)) = &self.status {
+                if time.elapsed() < STATUS_DURATION {
+                    ui.label(status);
+                }
+            }
+            // Draw the last error
+            if let Some(error) = &self.error {
+                ui.separator();
+                ui.colored_label(egui::Color32::RED, error);
+                if ui.button("Dismiss").clicked() {
+                    self.error = None;
+                }
+            }
+            // Draw the shader error until the shader compiles again
+            if let Some(error) = &self.shader_error {
+                ui.separator();
+                ui.colored_label(egui::Color32::RED, egui::RichText::new(error).monospace());
+            }
+            // Only a shader loaded from a file can change
+            if ui
+                .add_enabled(self.tracer.shader_path().is_some(), egui::Button::new("Reload Shader"))
+                .on_disabled_hover_text("Start with --shader to load the shader from a file")
+                .clicked()
+            {
+                self.reload_shader(true);
+            }
 
             ui.separator();
             // Render the frame
             if !self.realtime {
-                if ui.button("Render").clicked() {
-                    let before = Instant::now();
-                    self.frame = self
-                        .tracer
-                        .trace(
-                            &mut self.render_context,
-                            self.uniforms,
-                            &self.grid,
-                            self.camera,
-                        )
-                        .unwrap();
-                    self.frame_time = (before.elapsed().as_secs_f64() * 1000.0) as f32;
-                }
+                ui.horizontal(|ui| {
+                    let rendering = self.pending_render.is_some();
+                    if ui.add_enabled(!rendering, egui::Button::new("Render")).clicked() {
+                        self.start_render();
+                    }
+                    if rendering {
+                        ui.spinner();
+                        ui.label("Rendering…");
+                    }
+                });
             }
             ui.checkbox(&mut self.realtime, "Realtime");
+            // Time config
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.paused, "Pause");
+                ui.label("Time: ");
+                ui.add(
+                    egui::DragValue::new(&mut self.uniforms.time)
+                        .speed(0.01)
+                        .suffix(" s")
+                        .clamp_range(0.0..=f32::MAX),
+                )
+                .on_hover_text("The time the animations are shown at, pause to keep it still");
+            });
+            // Present mode config
+            ui.horizontal(|ui| {
+                ui.label("Present: ");
+                let modes = [
+                    (wgpu::PresentMode::AutoVsync, "Auto Vsync"),
+                    (wgpu::PresentMode::AutoNoVsync, "Auto No Vsync"),
+                    (wgpu::PresentMode::Fifo, "Fifo"),
+                    (wgpu::PresentMode::Immediate, "Immediate"),
+                ];
+                let current = self.render_context.surface_config.present_mode;
+                let selected = modes
+                    .iter()
+                    .find(|(mode, _)| *mode == current)
+                    .map_or("Other", |(_, name)| name);
+                egui::ComboBox::from_id_source("present_mode")
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        for (mode, name) in modes {
+                            let supported = self.render_context.supports_present_mode(mode);
+                            let clicked = ui
+                                .add_enabled(supported, egui::SelectableLabel::new(mode == current, name))
+                                .on_disabled_hover_text("Not supported by this display")
+                                .clicked();
+                            if clicked && mode != current {
+                                if let Err(e) = self.render_context.set_present_mode(mode) {
+                                    log::error!("Failed to change the present mode: {}", e);
+                                    self.error = Some(e.to_string());
+                                }
+                            }
+                        }
+                    });
+            });
+            // Resolution config
+            ui.horizontal(|ui| {
+                ui.label("Resolution: ");
+                if ui.selectable_label(self.fit_window, "Fit Window").clicked() {
+                    self.fit_window = true;
+                    self.resize_pending = true;
+                }
+                if ui.selectable_label(!self.fit_window, "Fixed").clicked() && self.fit_window {
+                    self.fit_window = false;
+                    self.fixed_resolution = self.tracer.resolution();
+                }
+            });
+            if !self.fit_window {
+                let max = self.render_context.gpu.device.limits().max_texture_dimension_2d;
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut self.fixed_resolution.x).clamp_range(1..=max));
+                    ui.label("x");
+                    ui.add(egui::DragValue::new(&mut self.fixed_resolution.y).clamp_range(1..=max));
+                    if ui.button("1:1").clicked() {
+                        self.fixed_resolution.x = self.fixed_resolution.y;
+                    }
+                });
+                // Only resize once the value is released
+                if self.fixed_resolution != self.tracer.resolution() && !ui.ctx().input().pointer.any_down() {
+                    self.resize_frame(self.fixed_resolution.x, self.fixed_resolution.y);
+                    // Don't retry a resolution the gpu doesn't support
+                    self.fixed_resolution = self.tracer.resolution();
+                }
+            }
+            ui.horizontal(|ui| {
+                let mut accumulate = self.uniforms.accumulate != 0;
+                ui.checkbox(&mut accumulate, "Accumulate");
+                self.uniforms.accumulate = accumulate as i32;
+                if accumulate {
+                    ui.label(format!("Samples: {}", self.tracer.sample_count()));
+                }
+            });
+            let mut denoise = self.uniforms.denoise != 0;
+            ui.checkbox(&mut denoise, "Denoise")
+                .on_hover_text("Smooth the noise of the ambient occlusion and the soft shadows");
+            self.uniforms.denoise = denoise as i32;
+            ui.horizontal(|ui| {
+                ui.label("Samples Per Pixel: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.samples_per_pixel).speed(0.1).clamp_range(1..=64))
+                    .on_hover_text("More samples smooth the edges but take longer to trace");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Seed: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.seed).speed(1))
+                    .on_hover_text("The same seed renders the same noise");
+            });
             // Save the image
-            if ui.button("Save").clicked() {
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    // Open file dialogue
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("PNG", &["png"])
+                        .add_filter("JPEG", &["jpg", "jpeg"])
+                        .add_filter("BMP", &["bmp"])
+                        .save_file()
+                    {
+                        // Save the image in the background, quantizing it first if enabled
+                        let quantize = self
+                            .palette
+                            .clone()
+                            .filter(|_| self.quantize)
+                            .map(|palette| (palette, self.dither));
+                        self.error = None;
+                        self.start_save(path, quantize);
+                    }
+                }
+                if !self.pending_saves.is_empty() || !self.screenshots.is_empty() {
+                    ui.spinner();
+                    ui.label("Saving…");
+                }
+            });
+            // Turntable export
+            match self.turntable.as_ref().map(|export| (export.frame, export.frames)) {
+                Some((frame, frames)) => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!("Exporting frame {}/{}", frame + 1, frames));
+                        if ui.button("Cancel").clicked() {
+                            self.turntable = None;
+                        }
+                    });
+                }
+                None => {
+                    ui.horizontal(|ui| {
+                        ui.label("Frames: ");
+                        ui.add(egui::DragValue::new(&mut self.turntable_frames).clamp_range(1..=9999));
+                        ui.label("Radius: ");
+                        ui.add(egui::DragValue::new(&mut self.turntable_radius).speed(0.01).clamp_range(0.01..=MAX_ZOOM_DISTANCE));
+                    });
+                    if ui.button("Export Turntable").clicked() {
+                        if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+                            self.turntable = Some(TurntableExport {
+                                dir,
+                                frame: 0,
+                                frames: self.turntable_frames,
+                                radius: self.turntable_radius,
+                                camera: self.camera,
+                            });
+                        }
+                    }
+                }
+            }
+            // High resolution export
+            ui.horizontal(|ui| {
+                ui.label("Export Size: ");
+                ui.add(egui::DragValue::new(&mut self.export_resolution.x).clamp_range(1..=65535));
+                ui.add(egui::DragValue::new(&mut self.export_resolution.y).clamp_range(1..=65535));
+                ui.label("Tile: ");
+                let max_tile = self.render_context.gpu.device.limits().max_texture_dimension_2d;
+                ui.add(egui::DragValue::new(&mut self.export_tile_size).clamp_range(16..=max_tile))
+                    .on_hover_text("Only a frame of the tile size is allocated on the gpu");
+            });
+            if ui.button("Export High-Res").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("PNG", &["png"])
+                    .add_filter("JPEG", &["jpg", "jpeg"])
+                    .add_filter("BMP", &["bmp"])
+                    .save_file()
+                {
+                    self.error = None;
+                    if let Err(e) = self.export_high_res(path) {
+                        log::error!("Failed to export the high resolution image: {}", e);
+                        self.error = Some(format!("Failed to export: {}", e));
+                    }
+                }
+            }
+            // Save and load the scene
+            ui.horizontal(|ui| {
+                if ui.button("Save Scene").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("RON", &["ron"])
+                        .save_file()
+                    {
+                        match self.save_scene(&path) {
+                            Ok(()) => log::info!("Saved the scene to {}", path.display()),
+                            Err(e) => {
+                                log::error!("Failed to save the scene to {}: {}", path.display(), e);
+                                self.error = Some(format!("Failed to save scene: {}", e));
+                            }
+                        }
+                    }
+                }
+                if ui.button("Load Scene").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("RON", &["ron"])
+                        .pick_file()
+                    {
+                        match self.load_scene(&path) {
+                            Ok(()) => log::info!("Loaded the scene from {}", path.display()),
+                            Err(e) => {
+                                log::error!("Failed to load the scene from {}: {}", path.display(), e);
+                                self.error = Some(format!("Failed to load scene: {}", e));
+                            }
+                        }
+                    }
+                }
+            });
+            if ui
+                .button("Load Settings From Image")
+                .on_hover_text("Use the camera and the settings a saved png was rendered with")
+                .clicked()
+            {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("PNG", &["png"])
+                    .pick_file()
+                {
+                    match self.load_image_settings(&path) {
+                        Ok(()) => log::info!("Loaded the settings from {}", path.display()),
+                        Err(e) => {
+                            log::error!("Failed to load the settings from {}: {}", path.display(), e);
+                            self.error = Some(format!("Failed to load settings: {}", e));
+                        }
+                    }
+                }
+            }
+            // Palette config
+            ui.horizontal(|ui| {
+                if ui.button("Load Palette").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Palette", &["hex", "gpl"])
+                        .pick_file()
+                    {
+                        match Palette::load(&path) {
+                            Ok(palette) => {
+                                self.palette = Some(palette);
+                                self.quantize = true;
+                            }
+                            Err(e) => {
+                                log::error!("Failed to load the palette {}: {}", path.display(), e);
+                                self.error = Some(format!("Failed to load palette: {}", e));
+                            }
+                        }
+                    }
+                }
+                if let Some(palette) = &self.palette {
+                    ui.label(format!("{} colors", palette.0.len()));
+                }
+            });
+            if self.palette.is_some() {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.quantize, "Quantize");
+                    ui.add_enabled(self.quantize, egui::Checkbox::new(&mut self.dither, "Dither"));
+                });
+            }
+            if ui.button("Copy to Clipboard").clicked() {
+                match self.copy_frame_to_clipboard() {
+                    Ok(()) => {
+                        log::info!("Copied the frame to the clipboard");
+                        self.error = None;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to copy the frame to the clipboard: {}", e);
+                        self.error = Some(format!("Failed to copy the image: {}", e));
+                    }
+                }
+            }
+            // Heightmap import
+            ui.separator();
+            ui.label("Heightmap: ");
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Max Height: ");
+                ui.add(egui::DragValue::new(&mut self.heightmap_height).speed(1).clamp_range(1..=64));
+            });
+            ui.checkbox(&mut self.heightmap_colorize, "Colorize");
+            if ui.button("Import Heightmap").clicked() {
                 // Open file dialogue
-                if let Some(path) = rfd::FileDialog::new().save_file() {
-                    // Save the file
-                    let save_path = path.display().to_string();
-                    // Save the image to a file
-                    block_on(self.tracer.frame_to_image(&save_path, &self.render_context)).unwrap();
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("Image", &["png", "jpg", "jpeg", "bmp"])
+                    .pick_file()
+                {
+                    // Load the grid from the heightmap
+                    match VoxelGrid::from_heightmap(&path, self.heightmap_height, self.heightmap_colorize) {
+                        Ok(grid) => {
+                            let edit = Edit::Replace(self.grid.clone(), grid);
+                            self.apply_edit(edit.clone());
+                            self.history.push(edit);
+                        }
+                        Err(e) => {
+                            log::error!("Failed to import the heightmap {}: {}", path.display(), e);
+                            self.error = Some(format!("Failed to import heightmap: {}", e));
+                        }
+                    }
+                }
+            }
+            // MagicaVoxel import
+            if ui.button("Import VOX").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("MagicaVoxel", &["vox"])
+                    .pick_file()
+                {
+                    match VoxelGrid::from_vox_file(&path) {
+                        Ok(grid) => {
+                            let edit = Edit::Replace(self.grid.clone(), grid);
+                            self.apply_edit(edit.clone());
+                            self.history.push(edit);
+                        }
+                        Err(e) => {
+                            log::error!("Failed to import {}: {}", path.display(), e);
+                            self.error = Some(format!("Failed to import vox: {}", e));
+                        }
+                    }
+                }
+            }
+            // Csv import and export
+            ui.horizontal(|ui| {
+                if ui.button("Import CSV").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("CSV", &["csv", "txt"])
+                        .pick_file()
+                    {
+                        match VoxelGrid::from_csv(&path) {
+                            Ok(grid) => {
+                                let edit = Edit::Replace(self.grid.clone(), grid);
+                                self.apply_edit(edit.clone());
+                                self.history.push(edit);
+                            }
+                            Err(e) => {
+                                log::error!("Failed to import {}: {}", path.display(), e);
+                                self.error = Some(format!("Failed to import csv: {}", e));
+                            }
+                        }
+                    }
+                }
+                if ui.button("Export CSV").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("CSV", &["csv", "txt"])
+                        .save_file()
+                    {
+                        if let Err(e) = self.grid.to_csv(&path) {
+                            log::error!("Failed to export {}: {}", path.display(), e);
+                            self.error = Some(format!("Failed to export csv: {}", e));
+                        }
+                    }
                 }
+            });
+            // Render region config
+            ui.separator();
+            ui.label("Render Region: ");
+            ui.separator();
+            let resolution = self.uniforms.resolution;
+            ui.horizontal(|ui| {
+                ui.label("Offset: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.region_offset.x).clamp_range(0..=resolution.x));
+                ui.add(egui::DragValue::new(&mut self.uniforms.region_offset.y).clamp_range(0..=resolution.y));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Size: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.region_size.x).clamp_range(0..=resolution.x));
+                ui.add(egui::DragValue::new(&mut self.uniforms.region_size.y).clamp_range(0..=resolution.y));
+            });
+            if ui.button("Full Frame").clicked() {
+                self.uniforms.region_offset = glam::UVec2::ZERO;
+                self.uniforms.region_size = resolution;
+            }
+            // Tiling config
+            ui.separator();
+            ui.label("Tiling: ");
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Count: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.tile_count.x).clamp_range(1..=64));
+                ui.add(egui::DragValue::new(&mut self.uniforms.tile_count.y).clamp_range(1..=64));
+                ui.add(egui::DragValue::new(&mut self.uniforms.tile_count.z).clamp_range(1..=64));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Spacing: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.tile_spacing).speed(0.01).clamp_range(0.0..=f32::MAX));
+            });
+            // Clip box config
+            ui.separator();
+            ui.label("Clip Box: ");
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Min: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.clip_min.x).speed(0.01));
+                ui.add(egui::DragValue::new(&mut self.uniforms.clip_min.y).speed(0.01));
+                ui.add(egui::DragValue::new(&mut self.uniforms.clip_min.z).speed(0.01));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Max: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.clip_max.x).speed(0.01));
+                ui.add(egui::DragValue::new(&mut self.uniforms.clip_max.y).speed(0.01));
+                ui.add(egui::DragValue::new(&mut self.uniforms.clip_max.z).speed(0.01));
+            });
+            if ui
+                .button("Reset to Grid Bounds")
+                .on_hover_text("Fit the clip box around the grid, so every voxel is drawn")
+                .clicked()
+            {
+                let (min, max) = self.grid.world_bounds();
+                self.uniforms.clip_min = min;
+                self.uniforms.clip_max = max;
+            }
+            // Selected voxel config
+            ui.separator();
+            ui.label("Voxel: ");
+            ui.separator();
+            let mut animate = self.uniforms.animate != 0;
+            ui.checkbox(&mut animate, "Animate");
+            self.uniforms.animate = animate as i32;
+            if !self.grid.is_empty() {
+                let last = self.grid.len() - 1;
+                self.selected_voxel = self.selected_voxel.min(last);
+                ui.horizontal(|ui| {
+                    ui.label("Selected: ");
+                    ui.add(egui::DragValue::new(&mut self.selected_voxel).clamp_range(0..=last));
+                });
+                let palette_size = self.grid.1.len();
+                let voxel = self.grid.voxel_mut(self.selected_voxel).unwrap();
+                ui.label(format!("Position: {}", voxel.position()));
+                ui.horizontal(|ui| {
+                    ui.label("Color: ");
+                    let mut color = [voxel.color.x, voxel.color.y, voxel.color.z];
+                    ui.color_edit_button_rgb(&mut color);
+                    voxel.color = color.into();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Palette Index: ");
+                    ui.add(egui::DragValue::new(&mut voxel.palette_index).clamp_range(0..=palette_size))
+                        .on_hover_text("The palette color the voxel uses, 0 uses the color above");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Emission: ");
+                    ui.add(egui::DragValue::new(&mut voxel.emission).speed(0.01).clamp_range(0.0..=f32::MAX));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Roughness: ");
+                    ui.add(egui::Slider::new(&mut voxel.roughness, 0.0..=1.0));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Metallic: ");
+                    ui.add(egui::Slider::new(&mut voxel.metallic, 0.0..=1.0));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Pattern: ");
+                    let patterns = [
+                        (PATTERN_SOLID, "Solid"),
+                        (PATTERN_CHECKER, "Checker"),
+                        (PATTERN_NOISE, "Noise"),
+                    ];
+                    let selected = patterns
+                        .iter()
+                        .find(|(pattern, _)| *pattern == voxel.pattern)
+                        .map_or("Solid", |(_, name)| name);
+                    egui::ComboBox::from_id_source("pattern")
+                        .selected_text(selected)
+                        .show_ui(ui, |ui| {
+                            for (pattern, name) in patterns {
+                                ui.selectable_value(&mut voxel.pattern, pattern, name);
+                            }
+                        });
+                    if voxel.pattern != PATTERN_SOLID {
+                        ui.label("Scale: ");
+                        ui.add(egui::DragValue::new(&mut voxel.pattern_scale).speed(0.001).clamp_range(0.001..=f32::MAX))
+                            .on_hover_text("The size of a cell of the pattern in world units");
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Pulse: ");
+                    ui.add(egui::DragValue::new(&mut voxel.amplitude).speed(0.01).clamp_range(0.0..=1.0));
+                    ui.label("Hz: ");
+                    ui.add(egui::DragValue::new(&mut voxel.frequency).speed(0.01).clamp_range(0.0..=f32::MAX));
+                });
             }
+            // Palette config
+            ui.separator();
+            ui.label("Palette: ");
+            ui.separator();
+            ui.horizontal_wrapped(|ui| {
+                for (i, color) in self.grid.1.iter_mut().enumerate() {
+                    let mut rgb = color.to_array();
+                    ui.color_edit_button_rgb(&mut rgb)
+                        .on_hover_text(format!("Palette index {}", i + 1));
+                    *color = rgb.into();
+                }
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Add Color").clicked() {
+                    self.grid.1.push(self.uniforms.object_color);
+                }
+                if ui
+                    .add_enabled(!self.grid.1.is_empty(), egui::Button::new("Remove Color"))
+                    .clicked()
+                {
+                    self.grid.1.pop();
+                }
+            });
             // Camera config
             ui.separator();
             ui.label("Camera: ");
@@ -200,27 +1643,166 @@ impl App {
                 ui.add(egui::DragValue::new(&mut self.camera.look_at[2]).speed(0.01));
             });
             ui.horizontal(|ui| {
-                ui.label("Zoom: ");
-                ui.add(egui::DragValue::new(&mut self.camera.zoom).speed(0.01));
+                ui.label("Projection: ");
+                let projections = [
+                    (PROJECTION_PERSPECTIVE, "Perspective"),
+                    (PROJECTION_ORTHOGRAPHIC, "Orthographic"),
+                ];
+                let selected = projections
+                    .iter()
+                    .find(|(projection, _)| *projection == self.camera.projection)
+                    .map_or("Perspective", |(_, name)| name);
+                egui::ComboBox::from_id_source("projection")
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        for (projection, name) in projections {
+                            ui.selectable_value(&mut self.camera.projection, projection, name);
+                        }
+                    });
+            });
+            if self.camera.projection == PROJECTION_ORTHOGRAPHIC {
+                ui.horizontal(|ui| {
+                    ui.label("Ortho Scale: ");
+                    ui.add(egui::DragValue::new(&mut self.camera.ortho_scale).speed(0.01).clamp_range(0.01..=f32::MAX))
+                        .on_hover_text("The height of the view in world units");
+                });
+            } else {
+                ui.horizontal(|ui| {
+                    ui.label("Zoom: ");
+                    ui.add(egui::DragValue::new(&mut self.camera.zoom).speed(0.01));
+                });
+            }
+            ui.horizontal(|ui| {
+                ui.label("Up: ");
+                ui.add(egui::DragValue::new(&mut self.camera.up[0]).speed(0.01));
+                ui.add(egui::DragValue::new(&mut self.camera.up[1]).speed(0.01));
+                ui.add(egui::DragValue::new(&mut self.camera.up[2]).speed(0.01));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Roll: ");
+                ui.drag_angle(&mut self.camera.roll);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Aperture: ");
+                ui.add(egui::DragValue::new(&mut self.camera.aperture).speed(0.001).clamp_range(0.0..=f32::MAX))
+                    .on_hover_text("The radius of the lens, 0 keeps everything sharp");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Focal Distance: ");
+                ui.add(egui::DragValue::new(&mut self.camera.focal_distance).speed(0.01).clamp_range(0.01..=f32::MAX));
+                // Focus on the point the camera looks at
+                if ui.button("Focus Look At").clicked() {
+                    self.camera.focal_distance = self.camera.position.distance(self.camera.look_at).max(0.01);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Fly Speed: ");
+                ui.add(egui::DragValue::new(&mut self.fly_speed).speed(0.1).clamp_range(0.0..=f32::MAX))
+                    .on_hover_text("Move with WASD, Q and E");
             });
+            if ui.button("Frame Scene").clicked() {
+                self.frame_scene();
+            }
+            ui.checkbox(&mut self.show_overlay, "Show Bounds");
             ui.horizontal(|ui| {
                 ui.label("MaxSteps: ");
                 ui.add(egui::DragValue::new(&mut self.uniforms.max_steps).speed(1));
             });
+            ui.horizontal(|ui| {
+                ui.label("Output: ");
+                // The debug outputs show what the tracer computes for each pixel
+                let outputs = [
+                    (OUTPUT_SHADED, "Shaded"),
+                    (OUTPUT_NORMALS, "Normals"),
+                    (OUTPUT_DEPTH, "Depth"),
+                    (OUTPUT_STEPS, "Step count"),
+                ];
+                let selected = outputs
+                    .iter()
+                    .find(|(mode, _)| *mode == self.uniforms.output_mode)
+                    .map_or("Shaded", |(_, name)| name);
+                egui::ComboBox::from_id_source("output_mode")
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        for (mode, name) in outputs {
+                            ui.selectable_value(&mut self.uniforms.output_mode, mode, name);
+                        }
+                    });
+            });
+            ui.horizontal(|ui| {
+                ui.label("Tonemap: ");
+                ui.selectable_value(&mut self.uniforms.tonemap_mode, TONEMAP_NONE, "None");
+                ui.selectable_value(&mut self.uniforms.tonemap_mode, TONEMAP_REINHARD, "Reinhard");
+                ui.selectable_value(&mut self.uniforms.tonemap_mode, TONEMAP_ACES, "ACES");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Exposure: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.exposure).speed(0.01).clamp_range(0.0..=f32::MAX));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Acceleration: ");
+                ui.selectable_value(&mut self.uniforms.acceleration, ACCELERATION_LINEAR, "Linear");
+                ui.selectable_value(&mut self.uniforms.acceleration, ACCELERATION_BVH, "BVH");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Near: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.near).speed(0.01).clamp_range(0.0..=self.uniforms.far));
+                ui.label("Far: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.far).speed(1.0).clamp_range(self.uniforms.near..=f32::MAX));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Hit Epsilon: ");
+                ui.add(
+                    egui::DragValue::new(&mut self.uniforms.hit_epsilon)
+                        .speed(0.0001)
+                        .clamp_range(0.000001..=0.1)
+                        .max_decimals(6),
+                )
+                .on_hover_text("Larger values round the edges, smaller values need more steps to reach the surfaces");
+            });
             ui.horizontal(|ui| {
                 ui.label("Smoothing: ");
                 ui.add(egui::DragValue::new(&mut self.uniforms.smoothing).speed(0.01));
             });
             ui.horizontal(|ui| {
-                ui.label("Background Color: ");
-                let mut color = [
-                    self.uniforms.background_color.x,
-                    self.uniforms.background_color.y,
-                    self.uniforms.background_color.z,
-                    self.uniforms.background_color.w
-                ]; 
-                ui.color_edit_button_rgba_premultiplied(&mut color);
-                self.uniforms.background_color = color.into();
+                ui.label("Sky: ");
+                ui.selectable_value(&mut self.uniforms.sky_mode, SKY_FLAT, "Flat");
+                ui.selectable_value(&mut self.uniforms.sky_mode, SKY_GRADIENT, "Gradient");
+                ui.selectable_value(&mut self.uniforms.sky_mode, SKY_PROCEDURAL, "Procedural");
+            });
+            if self.uniforms.sky_mode == SKY_FLAT {
+                ui.horizontal(|ui| {
+                    ui.label("Background Color: ");
+                    let mut color = self.uniforms.background_color.to_array();
+                    ui.color_edit_button_rgba_premultiplied(&mut color);
+                    self.uniforms.background_color = color.into();
+                });
+            } else {
+                ui.horizontal(|ui| {
+                    ui.label("Sky Top: ");
+                    let mut color = self.uniforms.sky_top_color.to_array();
+                    ui.color_edit_button_rgba_premultiplied(&mut color);
+                    self.uniforms.sky_top_color = color.into();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Sky Bottom: ");
+                    let mut color = self.uniforms.sky_bottom_color.to_array();
+                    ui.color_edit_button_rgba_premultiplied(&mut color);
+                    self.uniforms.sky_bottom_color = color.into();
+                });
+            }
+            let mut transparent = self.uniforms.alpha_background != 0;
+            ui.checkbox(&mut transparent, "Transparent background");
+            self.uniforms.alpha_background = transparent as i32;
+            ui.horizontal(|ui| {
+                ui.label("Floor: ");
+                let mut enabled = self.uniforms.floor_enabled != 0;
+                ui.checkbox(&mut enabled, "Enabled");
+                self.uniforms.floor_enabled = enabled as i32;
+            });
+            ui.horizontal(|ui| {
+                ui.label("Floor Height: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.floor_height).speed(0.01));
             });
             ui.horizontal(|ui| {
                 ui.label("Floor Color: ");
@@ -248,29 +1830,154 @@ impl App {
             ui.separator();
             // Scene config
             ui.horizontal(|ui| {
-                ui.label("Sun Intensity: ");
-                ui.add(egui::DragValue::new(&mut self.uniforms.sun_intensity).speed(0.01));
+                ui.label("AO Samples: ");
+                ui.add(egui::Slider::new(&mut self.uniforms.ambient_occlusion, 0..=8))
+                    .on_hover_text("4 darkens the edges, 8 also darkens the corners");
+            });
+            ui.horizontal(|ui| {
+                ui.label("AO Strength: ");
+                ui.add(egui::Slider::new(&mut self.uniforms.ao_strength, 0.0..=1.0))
+                    .on_hover_text("Around 0.5 looks natural");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Shadow Softness: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.shadow_softness).speed(0.001).clamp_range(0.0..=1.0))
+                    .on_hover_text("0 disables the shadows, larger values give wider penumbras");
             });
             ui.horizontal(|ui| {
-                ui.label("Ambient Occlusion");
-                ui.add(egui::DragValue::new(&mut self.uniforms.ambient_occlusion).speed(1));
+                ui.label("Bounces: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.max_bounces).speed(0.1).clamp_range(0..=MAX_BOUNCES))
+                    .on_hover_text("How often metallic voxels reflect the rays, 0 disables the reflections");
             });
             ui.horizontal(|ui| {
-                ui.label("LightPosition: ");
-                ui.add(egui::DragValue::new(&mut self.uniforms.light_position[0]).speed(0.01));
-                ui.add(egui::DragValue::new(&mut self.uniforms.light_position[1]).speed(0.01));
-                ui.add(egui::DragValue::new(&mut self.uniforms.light_position[2]).speed(0.01));
+                ui.label("Fog Density: ");
+                ui.add(egui::DragValue::new(&mut self.uniforms.fog_density).speed(0.001).clamp_range(0.0..=f32::MAX))
+                    .on_hover_text("0 disables the fog");
             });
+            if self.uniforms.fog_density > 0.0 {
+                ui.horizontal(|ui| {
+                    ui.label("Fog Color: ");
+                    let mut color = self.uniforms.fog_color.to_array();
+                    ui.color_edit_button_rgba_premultiplied(&mut color);
+                    self.uniforms.fog_color = color.into();
+                });
+            }
+            // Draw the light list
+            let mut removed = None;
+            for (i, light) in self.lights.iter_mut().enumerate() {
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label(format!("Light {}: ", i));
+                    if ui.button("Remove").clicked() {
+                        removed = Some(i);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Type: ");
+                    ui.selectable_value(&mut light.light_type, LIGHT_POINT, "Point");
+                    ui.selectable_value(&mut light.light_type, LIGHT_DIRECTIONAL, "Directional");
+                });
+                ui.horizontal(|ui| {
+                    // A directional light uses the position as the direction towards the light
+                    if light.light_type == LIGHT_DIRECTIONAL {
+                        ui.label("Direction: ");
+                    } else {
+                        ui.label("Position: ");
+                    }
+                    ui.add(egui::DragValue::new(&mut light.position[0]).speed(0.01));
+                    ui.add(egui::DragValue::new(&mut light.position[1]).speed(0.01));
+                    ui.add(egui::DragValue::new(&mut light.position[2]).speed(0.01));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Color: ");
+                    let mut color = [light.color.x, light.color.y, light.color.z];
+                    ui.color_edit_button_rgb(&mut color);
+                    light.color = color.into();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Intensity: ");
+                    ui.add(egui::DragValue::new(&mut light.intensity).speed(0.01));
+                });
+            }
+            if let Some(i) = removed {
+                self.lights.remove(i);
+            }
+            ui.separator();
+            if self.lights.len() < MAX_LIGHTS && ui.button("Add Light").clicked() {
+                self.lights.push(Light::default());
+            }
+            self.uniforms.light_count = self.lights.len() as u32;
         });
 
         // Draw the central panel
         egui::CentralPanel::default().show(ctx, |ui| {
-            // Trace de image
-            let image =
-                egui::Image::new(self.frame, (ui.available_height(), ui.available_height()));
-                    //.uv([egui::Pos2::new(0.0, 1.0), egui::Pos2::new(1.0, 0.0)]);
-            ui.add(image);
+            let available = ui.available_size();
+            // Match the resolution of the frame to the pixels of the panel
+            // While the window edge is dragged the frame is only resized once it stops, the final size is always applied
+            let settled = self.resized_at.filter(|at| at.elapsed() < RESIZE_DELAY).is_none();
+            if self.resize_pending && self.fit_window && settled {
+                self.resize_pending = false;
+                self.resized_at = None;
+                let pixels = available * ctx.pixels_per_point();
+                self.resize_frame(pixels.x.round() as u32, pixels.y.round() as u32);
+            }
+            // Scale the frame to fit the panel, keeping its aspect ratio
+            let resolution = self.tracer.resolution().as_vec2();
+            let scale = (available.x / resolution.x).min(available.y / resolution.y);
+            // Trace de image, the shader already flips the y axis so the uv isn't flipped here
+            let image = egui::Image::new(self.frame, (resolution.x * scale, resolution.y * scale))
+                .sense(egui::Sense::click_and_drag());
+            // Reserve a shape behind the image for the checkerboard, the size of the image is known after adding it
+            let background = ui.painter().add(egui::Shape::Noop);
+            let response = ui.add(image);
+            if self.uniforms.alpha_background != 0 {
+                ui.painter().set(background, checkerboard(response.rect));
+            }
+            // Draw the overlay on top of the image
+            if self.show_overlay {
+                self.draw_overlay(&ui.painter_at(response.rect), response.rect);
+            }
+
+            // Convert the cursor position to the uv used by the shader
+            let uv = response.hover_pos().map(|pos| {
+                let uv = (pos - response.rect.min) / response.rect.size();
+                glam::vec2((uv.x - 0.5) * self.aspect_ratio(), 0.5 - uv.y)
+            });
+            // Find the grid cell under the cursor
+            self.hovered_cell = uv.and_then(|uv| self.grid.pick_cpu(self.camera, self.uniforms, uv));
+
+            // Add a voxel in front of the clicked face, or remove the clicked voxel
+            if let Some(pick) = self.hovered_cell {
+                if response.clicked_by(egui::PointerButton::Primary) {
+                    self.add_voxel_at(pick.adjacent);
+                } else if response.clicked_by(egui::PointerButton::Secondary) {
+                    if let Some(cell) = pick.voxel {
+                        self.remove_voxel_at(cell);
+                    }
+                }
+            }
+
+            // Orbit the camera while the image is dragged with the left mouse button
+            if response.dragged_by(egui::PointerButton::Primary) {
+                let drag = response.drag_delta();
+                self.orbit(glam::vec2(drag.x, drag.y));
+            }
+
+            // Zoom with the scroll wheel when the pointer is over the central panel
+            let scroll = ctx.input().scroll_delta.y;
+            if scroll != 0.0 && ui.ui_contains_pointer() {
+                self.zoom_to_cursor(scroll, uv);
+                self.zoomed_at = Some(Instant::now());
+            }
         });
+
+        // Record the edits made this frame
+        self.record_edits(ctx);
+    }
+
+    /// Draw the ui at a scale factor instead of the one of the display, used for testing the HiDPI layout
+    pub fn set_scale_factor(&mut self, scale: Option<f32>) {
+        self.window.set_scale_factor(scale);
     }
 
     /// Start the main loop
@@ -282,6 +1989,15 @@ impl App {
             // Execute one frame
             self.execute(start_time)?;
         }
+        // Save the settings for the next session
+        let config = Config {
+            uniforms: self.uniforms,
+            camera: self.camera,
+            realtime: self.realtime,
+        };
+        if let Err(e) = config.save() {
+            log::error!("Failed to save the config: {}", e);
+        }
         Ok(())
     }
 
@@ -290,3 +2006,37 @@ impl App {
         self.should_run
     }
 }
+
+/// Get a checkerboard covering a rect, drawn behind the frame to show where it's transparent
+fn checkerboard(rect: egui::Rect) -> egui::Shape {
+    let (light, dark) = (egui::Color32::from_gray(200), egui::Color32::from_gray(150));
+    let columns = (rect.width() / CHECKER_SIZE).ceil() as usize;
+    let rows = (rect.height() / CHECKER_SIZE).ceil() as usize;
+    let mut shapes = vec![egui::Shape::rect_filled(rect, 0.0, light)];
+    // Only the dark cells are drawn on top of the light background, clipped to the rect
+    for (x, y) in (0..columns).flat_map(|x| (0..rows).map(move |y| (x, y))) {
+        if (x + y) % 2 == 1 {
+            let min = rect.min + egui::vec2(x as f32, y as f32) * CHECKER_SIZE;
+            let cell = egui::Rect::from_min_size(min, egui::Vec2::splat(CHECKER_SIZE)).intersect(rect);
+            shapes.push(egui::Shape::rect_filled(cell, 0.0, dark));
+        }
+    }
+    egui::Shape::Vec(shapes)
+}
+
+/// Get the camera for a frame of a turntable
+/// The camera orbits the look at point around the up axis at the given radius, keeping its height
+fn turntable_camera(camera: Camera, radius: f32, frame: u32, frames: u32) -> Camera {
+    let up = camera.up.try_normalize().unwrap_or(glam::Vec3::Y);
+    let offset = camera.position - camera.look_at;
+    let height = offset.dot(up);
+    // Start from the current direction, or any direction along the floor when looking straight down
+    let direction = (offset - up * height)
+        .try_normalize()
+        .unwrap_or_else(|| up.any_orthonormal_vector());
+    let angle = std::f32::consts::TAU * frame as f32 / frames.max(1) as f32;
+    Camera {
+        position: camera.look_at + glam::Quat::from_axis_angle(up, angle) * direction * radius + up * height,
+        ..camera
+    }
+}
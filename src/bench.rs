@@ -0,0 +1,120 @@
+use std::time::Instant;
+
+use crate::{
+    render::{AdapterOptions, GpuContext},
+    tracer::{
+        Camera, Light, ShaderOptions, Tracer, Uniforms, Voxel, VoxelGrid, ACCELERATION_BVH,
+        ACCELERATION_LINEAR,
+    },
+};
+
+/// The voxel counts that get benchmarked
+const VOXEL_COUNTS: [usize; 5] = [10, 100, 1_000, 10_000, 50_000];
+/// The largest grid that gets traced without acceleration, larger grids can stall the gpu
+const MAX_LINEAR_VOXELS: usize = 10_000;
+/// The acceleration modes that get benchmarked
+const ACCELERATIONS: [(&str, u32); 2] = [("linear", ACCELERATION_LINEAR), ("bvh", ACCELERATION_BVH)];
+/// The filled cube of 32x32x32 voxels that is traced at every resolution
+const CUBE_VOXELS: usize = 32 * 32 * 32;
+/// The resolutions the cube gets benchmarked at
+const RESOLUTIONS: [(u32, u32); 3] = [(640, 360), (1280, 720), (1920, 1080)];
+
+/// Create a cube shaped grid with the given amount of voxels
+pub fn create_bench_grid(count: usize) -> VoxelGrid {
+    // The length of a side of the cube
+    let side = (count as f32).cbrt().ceil() as usize;
+    let voxels = (0..count)
+        .map(|i| {
+            let position = glam::uvec3(
+                (i % side) as u32,
+                (i / side % side) as u32,
+                (i / (side * side)) as u32,
+            );
+            Voxel::new(
+                position.as_ivec3() - glam::IVec3::splat(side as i32 / 2),
+                position.as_vec3() / side as f32,
+            )
+        });
+    VoxelGrid::from_voxels(voxels)
+}
+
+/// Trace grids of increasing size, then the cube at increasing resolutions, and print the frame times
+pub fn bench_trace(iterations: u32, options: AdapterOptions) -> anyhow::Result<()> {
+    // Create the gpu context, the benchmark doesn't need a window
+    let gpu = pollster::block_on(GpuContext::new(&options))?;
+
+    let lights = vec![Light::default()];
+    let camera = Camera::default();
+    let uniforms = Uniforms {
+        light_count: lights.len() as u32,
+        ..Default::default()
+    };
+    let mut tracer = Tracer::new(&gpu, &uniforms, &ShaderOptions::default())?;
+
+    println!("voxels\tacceleration\tmean (ms)\tmedian (ms)");
+    for (count, (name, acceleration)) in VOXEL_COUNTS
+        .into_iter()
+        .flat_map(|count| ACCELERATIONS.map(|mode| (count, mode)))
+        .filter(|(count, (_, acceleration))| {
+            *acceleration != ACCELERATION_LINEAR || *count <= MAX_LINEAR_VOXELS
+        })
+    {
+        let grid = create_bench_grid(count);
+        let uniforms = Uniforms {
+            voxel_amount: grid.len() as u32,
+            acceleration,
+            ..uniforms
+        };
+
+        let (mean, median) = time_traces(&mut tracer, &gpu, iterations, uniforms, &grid, &lights, camera)?;
+        println!("{}\t{}\t{:.3}\t{:.3}", count, name, mean, median);
+    }
+
+    // Trace the cube at every resolution, only with the bvh since checking every voxel is too slow
+    let grid = create_bench_grid(CUBE_VOXELS);
+    let uniforms = Uniforms {
+        voxel_amount: grid.len() as u32,
+        acceleration: ACCELERATION_BVH,
+        ..uniforms
+    };
+    println!();
+    println!("resolution\tmean (ms)\tmedian (ms)");
+    for (width, height) in RESOLUTIONS {
+        tracer.resize(&gpu, width, height)?;
+        let uniforms = Uniforms {
+            resolution: glam::uvec2(width, height),
+            region_size: glam::uvec2(width, height),
+            ..uniforms
+        };
+        let (mean, median) = time_traces(&mut tracer, &gpu, iterations, uniforms, &grid, &lights, camera)?;
+        println!("{}x{}\t{:.3}\t{:.3}", width, height, mean, median);
+    }
+    Ok(())
+}
+
+/// Trace a grid multiple times, waiting for the gpu to finish each trace
+/// Returns the mean and the median frame time in milliseconds
+fn time_traces(
+    tracer: &mut Tracer,
+    gpu: &GpuContext,
+    iterations: u32,
+    uniforms: Uniforms,
+    grid: &VoxelGrid,
+    lights: &[Light],
+    camera: Camera,
+) -> anyhow::Result<(f32, f32)> {
+    let mut frame_times = (0..iterations)
+        .map(|_| {
+            let before = Instant::now();
+            tracer.dispatch(gpu, uniforms, grid, lights, camera)?;
+            gpu.device.poll(wgpu::Maintain::Wait);
+            Ok((before.elapsed().as_secs_f64() * 1000.0) as f32)
+        })
+        .collect::<anyhow::Result<Vec<f32>>>()?;
+
+    // Calculate the mean and median
+    frame_times.sort_by(|a, b| a.total_cmp(b));
+    let mean = frame_times.iter().sum::<f32>() / frame_times.len().max(1) as f32;
+    let median = frame_times.get(frame_times.len() / 2).copied().unwrap_or(0.0);
+    Ok((mean, median))
+}
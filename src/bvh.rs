@@ -0,0 +1,278 @@
+use encase::ShaderType;
+
+use crate::tracer::{Voxel, VoxelGrid, VOXEL_SIZE};
+
+// The maximum amount of voxels in a leaf
+const LEAF_SIZE: usize = 4;
+
+/// Represents a node of the bounding volume hierarchy
+/// The bounds are in world space and include the size of the voxels
+#[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+pub struct BvhNode {
+    pub min: glam::Vec3,
+    // The index of the first child for inner nodes, or the index of the first voxel for leaves
+    // The second child of an inner node directly follows the first
+    pub start: u32,
+    pub max: glam::Vec3,
+    // The amount of voxels in a leaf, 0 for inner nodes
+    pub count: u32,
+}
+
+/// Represents a bounding volume hierarchy over the voxels of a grid
+/// It's built by splitting the voxels in half along the longest axis until the leaves are small enough
+#[derive(Debug, Clone)]
+pub struct VoxelBvh {
+    // The nodes, the first node is the root
+    pub nodes: Vec<BvhNode>,
+    // The voxels, reordered so each leaf covers a range of them
+    pub voxels: Vec<Voxel>,
+}
+
+impl VoxelBvh {
+    /// Construct a new [`VoxelBvh`] over the voxels of a grid
+    pub fn new(grid: &VoxelGrid) -> Self {
+        let mut bvh = Self {
+            nodes: vec![BvhNode {
+                min: glam::Vec3::ZERO,
+                start: 0,
+                max: glam::Vec3::ZERO,
+                count: 0,
+            }],
+            voxels: grid.voxels().to_vec(),
+        };
+        if !bvh.voxels.is_empty() {
+            bvh.build(0, 0, bvh.voxels.len());
+        }
+        bvh
+    }
+
+    /// Build a node over a range of the voxels
+    fn build(&mut self, node: usize, start: usize, end: usize) {
+        let voxels = &mut self.voxels[start..end];
+        // Calculate the bounds of the voxels
+        let (min, max) = voxels.iter().fold(
+            (glam::IVec3::splat(i32::MAX), glam::IVec3::splat(i32::MIN)),
+            |(min, max), voxel| (min.min(voxel.position()), max.max(voxel.position())),
+        );
+        self.nodes[node].min = min.as_vec3() * VOXEL_SIZE - glam::Vec3::splat(VOXEL_SIZE / 2.0);
+        self.nodes[node].max = max.as_vec3() * VOXEL_SIZE + glam::Vec3::splat(VOXEL_SIZE / 2.0);
+
+        // Stop splitting once the leaf is small enough
+        if voxels.len() <= LEAF_SIZE {
+            self.nodes[node].start = start as u32;
+            self.nodes[node].count = voxels.len() as u32;
+            return;
+        }
+
+        // Split the voxels in half along the longest axis
+        let size = max - min;
+        let axis = if size.x >= size.y && size.x >= size.z {
+            0
+        } else if size.y >= size.z {
+            1
+        } else {
+            2
+        };
+        let middle = voxels.len() / 2;
+        voxels.select_nth_unstable_by_key(middle, |voxel| voxel.position()[axis]);
+
+        // Add the children next to each other
+        let left = self.nodes.len();
+        self.nodes.push(self.nodes[node]);
+        self.nodes.push(self.nodes[node]);
+        self.nodes[node].start = left as u32;
+        self.nodes[node].count = 0;
+        self.build(left, start, start + middle);
+        self.build(left + 1, start + middle, end);
+    }
+
+    /// Get the distance from a point to the closest voxel and the index of that voxel in [`VoxelBvh::voxels`]
+    /// The nodes that are further away than the closest voxel so far are skipped, this mirrors `draw_voxels_bvh` in the shader
+    /// Returns [`None`] if there are no voxels
+    pub fn closest(&self, point: glam::Vec3) -> Option<(f32, usize)> {
+        // The root of an empty bvh isn't a leaf, so it can't be visited
+        if self.voxels.is_empty() {
+            return None;
+        }
+        let mut closest: Option<(f32, usize)> = None;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = self.nodes[index];
+            // Skip the node if it can't contain a closer voxel
+            let outside = (node.min - point).max(point - node.max).max(glam::Vec3::ZERO);
+            if closest.is_some_and(|(distance, _)| outside.length() >= distance) {
+                continue;
+            }
+            if node.count > 0 {
+                let start = node.start as usize;
+                for (i, voxel) in self.voxels[start..start + node.count as usize].iter().enumerate() {
+                    let distance = voxel.distance(point);
+                    if !closest.is_some_and(|(closest, _)| distance >= closest) {
+                        closest = Some((distance, start + i));
+                    }
+                }
+            } else {
+                stack.push(node.start as usize);
+                stack.push(node.start as usize + 1);
+            }
+        }
+        closest
+    }
+
+    /// Check if a grid cell contains a voxel
+    /// Only the nodes with the centre of the cell inside them are visited, this mirrors `is_occupied_bvh` in the shader
+    pub fn contains(&self, cell: glam::IVec3) -> bool {
+        if self.voxels.is_empty() {
+            return false;
+        }
+        let centre = cell.as_vec3() * VOXEL_SIZE;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = self.nodes[index];
+            if centre.cmplt(node.min).any() || centre.cmpgt(node.max).any() {
+                continue;
+            }
+            if node.count > 0 {
+                let start = node.start as usize;
+                if self.voxels[start..start + node.count as usize]
+                    .iter()
+                    .any(|voxel| voxel.position() == cell)
+                {
+                    return true;
+                }
+            } else {
+                stack.push(node.start as usize);
+                stack.push(node.start as usize + 1);
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A xorshift generator, so the tests are repeatable without a rand dependency
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as f32 / u32::MAX as f32
+        }
+
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            min + (max - min) * self.next()
+        }
+
+        fn vec3(&mut self, min: f32, max: f32) -> glam::Vec3 {
+            glam::vec3(self.range(min, max), self.range(min, max), self.range(min, max))
+        }
+    }
+
+    // Scatter voxels through a cube of cells, the duplicate cells are merged by the grid
+    fn random_grid(rng: &mut Rng, count: usize) -> VoxelGrid {
+        VoxelGrid::from_voxels((0..count).map(|_| {
+            let cell = rng.vec3(-10.0, 10.0).round().as_ivec3();
+            Voxel::new(cell, glam::Vec3::ONE)
+        }))
+    }
+
+    // The distance to the closest voxel without the bvh, like the linear loop in the shader
+    fn linear_distance(grid: &VoxelGrid, point: glam::Vec3) -> f32 {
+        grid.voxels().iter().map(|voxel| voxel.distance(point)).fold(f32::INFINITY, f32::min)
+    }
+
+    // Sphere trace a ray, returns where it hit a voxel
+    fn march(origin: glam::Vec3, direction: glam::Vec3, distance: impl Fn(glam::Vec3) -> f32) -> Option<glam::Vec3> {
+        let mut travelled = 0.0;
+        for _ in 0..512 {
+            let point = origin + direction * travelled;
+            let step = distance(point);
+            if step < 0.001 {
+                return Some(point);
+            }
+            travelled += step;
+            if travelled > 100.0 {
+                break;
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn every_voxel_is_in_one_leaf() {
+        let grid = random_grid(&mut Rng(7), 500);
+        let bvh = VoxelBvh::new(&grid);
+        let mut covered = vec![0; bvh.voxels.len()];
+        for node in bvh.nodes.iter().filter(|node| node.count > 0) {
+            for count in &mut covered[node.start as usize..(node.start + node.count) as usize] {
+                *count += 1;
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+        assert_eq!(bvh.voxels.len(), grid.len());
+    }
+
+    #[test]
+    fn closest_matches_linear_scan() {
+        let mut rng = Rng(42);
+        let grid = random_grid(&mut rng, 300);
+        let bvh = VoxelBvh::new(&grid);
+        for _ in 0..1000 {
+            let point = rng.vec3(-6.0, 6.0);
+            let (distance, index) = bvh.closest(point).unwrap();
+            assert_eq!(distance, linear_distance(&grid, point));
+            assert_eq!(bvh.voxels[index].distance(point), distance);
+        }
+    }
+
+    #[test]
+    fn random_rays_hit_the_same_voxel() {
+        let mut rng = Rng(1234);
+        let grid = random_grid(&mut rng, 200);
+        let bvh = VoxelBvh::new(&grid);
+        let mut hits = 0;
+        for _ in 0..500 {
+            // Aim near a random voxel so most rays hit something
+            let origin = rng.vec3(-8.0, 8.0);
+            let target = grid.voxels()[(rng.next() * (grid.len() - 1) as f32) as usize].position().as_vec3() * VOXEL_SIZE
+                + rng.vec3(-VOXEL_SIZE, VOXEL_SIZE);
+            let direction = (target - origin).normalize();
+            let linear = march(origin, direction, |point| linear_distance(&grid, point));
+            let accelerated = march(origin, direction, |point| bvh.closest(point).unwrap().0);
+            assert_eq!(linear, accelerated);
+            hits += linear.is_some() as u32;
+        }
+        // Make sure the test isn't passing because every ray missed
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn empty_grid_has_an_empty_root() {
+        let bvh = VoxelBvh::new(&VoxelGrid::default());
+        // The shader needs at least one node to read
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].count, 0);
+        assert!(bvh.voxels.is_empty());
+        assert_eq!(bvh.closest(glam::Vec3::ZERO), None);
+    }
+
+    #[test]
+    fn contains_matches_the_grid() {
+        let mut rng = Rng(99);
+        let grid = random_grid(&mut rng, 400);
+        let bvh = VoxelBvh::new(&grid);
+        // Every voxel is found
+        assert!(grid.voxels().iter().all(|voxel| bvh.contains(voxel.position())));
+        // And the random cells around them only when they're occupied
+        for _ in 0..2000 {
+            let cell = rng.vec3(-12.0, 12.0).round().as_ivec3();
+            assert_eq!(bvh.contains(cell), grid.get(cell).is_some(), "{}", cell);
+        }
+        assert!(!VoxelBvh::new(&VoxelGrid::default()).contains(glam::IVec3::ZERO));
+    }
+}
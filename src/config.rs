@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tracer::{Camera, Uniforms};
+
+/// The app settings that are kept between sessions
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub uniforms: Uniforms,
+    pub camera: Camera,
+    pub realtime: bool,
+}
+
+impl Config {
+    /// Get the path of the config file, next to the executable
+    pub fn path() -> anyhow::Result<PathBuf> {
+        Ok(std::env::current_exe()?.with_file_name("config.json"))
+    }
+
+    /// Load the config file
+    /// Returns [`None`] if the file is missing or malformed
+    pub fn load() -> Option<Self> {
+        let source = std::fs::read_to_string(Self::path().ok()?).ok()?;
+        serde_json::from_str(&source).ok()
+    }
+
+    /// Save the config file
+    pub fn save(&self) -> anyhow::Result<()> {
+        let source = serde_json::to_string_pretty(self)?;
+        std::fs::write(Self::path()?, source)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use encase::{ShaderSize, ShaderType, UniformBuffer};
+
+use crate::{
+    error::Result,
+    render::GpuContext,
+    tracer::{check_storage_buffer_size, create_pipeline, dispatch_size},
+};
+
+// The source of the denoise shader, it's bundled so the executable can run from anywhere
+const SHADER_SOURCE: &str = include_str!("../shaders/denoise.wgsl");
+// The amount of filter iterations, this is even so the result ends up in the frame again
+const ITERATIONS: u32 = 4;
+// How strongly differences in color, normal and depth stop the blur in the first iteration
+const COLOR_PHI: f32 = 0.5;
+const NORMAL_PHI: f32 = 0.1;
+const DEPTH_PHI: f32 = 0.1;
+
+/// The settings of a single denoise iteration, this mirrors `DenoiseParams` in the shader
+#[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+struct DenoiseParams {
+    resolution: glam::UVec2,
+    step_width: i32,
+    color_phi: f32,
+    normal_phi: f32,
+    depth_phi: f32,
+}
+
+// Check that the layout matches the shader
+const _: () = {
+    assert!(<DenoiseParams as ShaderSize>::SHADER_SIZE.get() == 24);
+    assert!(<DenoiseParams as ShaderType>::METADATA.offset(1) == 8); // step_width
+    assert!(<DenoiseParams as ShaderType>::METADATA.offset(4) == 20); // depth_phi
+};
+
+/// Handles the edge avoiding a-trous wavelet filter that runs after the trace
+/// The filter is guided by the normal and the distance the tracer writes for each pixel
+pub struct Denoiser {
+    bind_group_layout: wgpu::BindGroupLayout,
+    compute_pipeline: wgpu::ComputePipeline,
+    // One buffer per iteration, since the queue writes all happen before the passes run
+    params: Vec<wgpu::Buffer>,
+    // The frame the iterations ping-pong with, the texture is only kept for the view
+    _scratch_texture: wgpu::Texture,
+    scratch_view: wgpu::TextureView,
+    // The normal and the distance from the camera for each pixel
+    guide: wgpu::Buffer,
+    workgroup_size: u32,
+    resolution: glam::UVec2,
+}
+
+impl Denoiser {
+    /// Construct a new [`Denoiser`] for a resolution
+    pub fn new(gpu: &GpuContext, resolution: glam::UVec2, workgroup_size: u32) -> Result<Self> {
+        // Create the bind group layout
+        let bind_group_layout =
+            gpu.device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: None,
+                    entries: &[
+                        // The settings
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(DenoiseParams::min_size()),
+                            },
+                            count: None,
+                        },
+                        // The input frame
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        // The output frame
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::StorageTexture {
+                                access: wgpu::StorageTextureAccess::WriteOnly,
+                                format: wgpu::TextureFormat::Rgba8Unorm,
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                            },
+                            count: None,
+                        },
+                        // The guide buffer
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 3,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(glam::Vec4::min_size()),
+                            },
+                            count: None,
+                        },
+                    ],
+                });
+        let compute_pipeline =
+            create_pipeline(gpu, &bind_group_layout, SHADER_SOURCE.to_string(), "main", workgroup_size)?;
+
+        // Create the settings buffers
+        let params = (0..ITERATIONS)
+            .map(|_| {
+                gpu.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: None,
+                    size: DenoiseParams::min_size().get(),
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+
+        let (scratch_texture, scratch_view, guide) = create_targets(gpu, resolution)?;
+        Ok(Self {
+            bind_group_layout,
+            compute_pipeline,
+            params,
+            _scratch_texture: scratch_texture,
+            scratch_view,
+            guide,
+            workgroup_size,
+            resolution,
+        })
+    }
+
+    /// Resize the scratch frame and the guide buffer
+    pub fn resize(&mut self, gpu: &GpuContext, resolution: glam::UVec2) -> Result<()> {
+        let (scratch_texture, scratch_view, guide) = create_targets(gpu, resolution)?;
+        self._scratch_texture = scratch_texture;
+        self.scratch_view = scratch_view;
+        self.guide = guide;
+        self.resolution = resolution;
+        Ok(())
+    }
+
+    /// Get the guide buffer the tracer writes the normals and distances to
+    pub fn guide(&self) -> &wgpu::Buffer {
+        &self.guide
+    }
+
+    /// Record the filter iterations, the filtered result replaces the frame
+    /// The frame has to be the size the denoiser was created or resized for
+    pub fn encode(&self, gpu: &GpuContext, encoder: &mut wgpu::CommandEncoder, frame: &wgpu::TextureView) -> Result<()> {
+        for (i, params) in self.params.iter().enumerate() {
+            // Spread the taps further apart and lower the color weight every iteration
+            let mut buffer = UniformBuffer::new(vec![]);
+            buffer.write(&DenoiseParams {
+                resolution: self.resolution,
+                step_width: 1 << i,
+                color_phi: COLOR_PHI / (1 << i) as f32,
+                normal_phi: NORMAL_PHI,
+                depth_phi: DEPTH_PHI * (1 << i) as f32,
+            })?;
+            gpu.queue.write_buffer(params, 0, &buffer.into_inner());
+
+            // Ping-pong between the frame and the scratch frame
+            let (input, output) = if i % 2 == 0 {
+                (frame, &self.scratch_view)
+            } else {
+                (&self.scratch_view, frame)
+            };
+            let bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: params.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(input),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(output),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: self.guide.as_entire_binding(),
+                    },
+                ],
+            });
+
+            // Every iteration needs its own pass, since it reads the output of the previous one
+            let mut compute_pass = encoder.begin_compute_pass(&Default::default());
+            compute_pass.set_pipeline(&self.compute_pipeline);
+            compute_pass.set_bind_group(0, &bind_group, &[]);
+            let workgroups = dispatch_size(self.resolution, self.workgroup_size);
+            compute_pass.dispatch_workgroups(workgroups.x, workgroups.y, 1);
+        }
+        Ok(())
+    }
+}
+
+/// Create the scratch frame and the guide buffer for a resolution
+fn create_targets(
+    gpu: &GpuContext,
+    resolution: glam::UVec2,
+) -> Result<(wgpu::Texture, wgpu::TextureView, wgpu::Buffer)> {
+    let scratch_texture = gpu.device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size: wgpu::Extent3d {
+            width: resolution.x,
+            height: resolution.y,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+    });
+    let scratch_view = scratch_texture.create_view(&Default::default());
+
+    let size = resolution.x as u64 * resolution.y as u64 * glam::Vec4::min_size().get();
+    check_storage_buffer_size("guide", size, &gpu.device.limits())?;
+    let guide = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    });
+    Ok((scratch_texture, scratch_view, guide))
+}
@@ -0,0 +1,50 @@
+/// Represents the errors of the tracer and the render context
+#[derive(Debug, thiserror::Error)]
+pub enum VoxelError {
+    /// The shader failed to compile
+    #[error("Failed to compile the shader: {0}")]
+    ShaderCompile(String),
+    /// The surface is lost or outdated and has to be reconfigured
+    #[error("The surface is lost")]
+    SurfaceLost,
+    /// Reading or writing a file failed
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The image format isn't supported, contains the extension of the file
+    #[error("Unsupported image format '{0}', use png, jpg or bmp")]
+    UnsupportedFormat(String),
+    /// A resolution or a buffer exceeds a limit of the gpu
+    #[error("{0}")]
+    LimitExceeded(String),
+    /// Wgpu returned an error
+    #[error("{0}")]
+    Wgpu(String),
+    /// An instance refers to a grid that wasn't given, contains the instance, its grid and the amount of grids
+    #[error("Instance {0} uses grid {1}, but there are only {2} grids")]
+    InvalidGrid(usize, u32, usize),
+    /// The gpu rejected the commands of a trace, for example because a buffer doesn't match the layout
+    #[error("The trace failed validation: {0}")]
+    Validation(String),
+    /// Encoding or saving an image failed
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    /// Encoding a png with metadata failed
+    #[error(transparent)]
+    Png(#[from] png::EncodingError),
+    /// Serializing the settings to json failed
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// Writing the data for a buffer failed
+    #[error(transparent)]
+    Encode(#[from] encase::internal::Error),
+}
+
+#[cfg(feature = "gui")]
+impl From<egui_wgpu_backend::BackendError> for VoxelError {
+    fn from(e: egui_wgpu_backend::BackendError) -> Self {
+        Self::Wgpu(e.to_string())
+    }
+}
+
+/// A result with a [`VoxelError`]
+pub type Result<T> = std::result::Result<T, VoxelError>;
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+use crate::tracer::{Camera, Uniforms, Voxel, VoxelGrid};
+
+/// The settings that are tracked by the history
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub uniforms: Uniforms,
+    pub camera: Camera,
+}
+
+impl Settings {
+    /// Construct new [`Settings`]
+    /// The fields that change every frame or follow from the scene are zeroed so they don't count as edits
+    pub fn new(uniforms: Uniforms, camera: Camera) -> Self {
+        Self {
+            uniforms: Uniforms {
+                time: 0.0,
+                frames: 0,
+                voxel_amount: 0,
+                light_count: 0,
+                palette_size: 0,
+                instance_count: 0,
+                ..uniforms
+            },
+            camera,
+        }
+    }
+}
+
+/// Represents a reversible edit
+#[derive(Debug, Clone)]
+pub enum Edit {
+    /// A voxel was inserted at an index of the grid
+    Add(usize, Voxel),
+    /// A voxel was removed from an index of the grid
+    Remove(usize, Voxel),
+    /// A voxel at an index of the grid changed from the first to the second voxel
+    Change(usize, Voxel, Voxel),
+    /// The whole grid was replaced, used for imports
+    Replace(VoxelGrid, VoxelGrid),
+    /// The settings changed from the first to the second settings
+    /// The settings are boxed, they're much larger than the other edits
+    Settings(Box<Settings>, Box<Settings>),
+    /// The palette of the grid changed from the first to the second palette
+    /// The palette is small, so it's stored whole instead of the grid
+    Palette(Vec<glam::Vec3>, Vec<glam::Vec3>),
+}
+
+impl Edit {
+    /// Get the edit that reverts this edit
+    pub fn inverse(self) -> Self {
+        match self {
+            Self::Add(index, voxel) => Self::Remove(index, voxel),
+            Self::Remove(index, voxel) => Self::Add(index, voxel),
+            Self::Change(index, before, after) => Self::Change(index, after, before),
+            Self::Replace(before, after) => Self::Replace(after, before),
+            Self::Settings(before, after) => Self::Settings(after, before),
+            Self::Palette(before, after) => Self::Palette(after, before),
+        }
+    }
+}
+
+/// Keeps track of the edits that can be undone and redone
+pub struct History {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    // The maximum amount of edits that are remembered
+    max_depth: usize,
+}
+
+impl History {
+    /// Construct a new [`History`]
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            max_depth,
+        }
+    }
+
+    /// Record a new edit
+    /// This clears the edits that could be redone
+    pub fn push(&mut self, edit: Edit) {
+        self.redo.clear();
+        self.undo.push_back(edit);
+        // Forget the oldest edits
+        while self.undo.len() > self.max_depth {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Change the maximum amount of edits that are remembered
+    /// The oldest edits are forgotten if there are too many
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+        while self.undo.len() > self.max_depth {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Forget all the edits
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Take the edit that reverts the last edit
+    pub fn undo(&mut self) -> Option<Edit> {
+        let edit = self.undo.pop_back()?;
+        self.redo.push(edit.clone());
+        Some(edit.inverse())
+    }
+
+    /// Take the last undone edit so it can be applied again
+    pub fn redo(&mut self) -> Option<Edit> {
+        let edit = self.redo.pop()?;
+        self.undo.push_back(edit.clone());
+        Some(edit)
+    }
+
+    /// Check if there is an edit to undo
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    /// Check if there is an edit to redo
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+}
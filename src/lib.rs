@@ -1,4 +1,28 @@
+//! A voxel ray tracer running in a wgpu compute shader
+//! Without the `gui` feature only the tracing core is available, see [`GpuContext`] and [`Tracer`]
+
+#[cfg(feature = "gui")]
 pub mod app;
+pub mod bench;
+pub mod bvh;
+pub mod config;
+pub mod denoise;
+pub mod error;
+pub mod history;
+pub mod palette;
+pub mod reference;
 pub mod render;
+pub mod scene;
+pub mod stats;
+pub mod timer;
 pub mod tracer;
-pub mod window;
\ No newline at end of file
+#[cfg(feature = "gui")]
+pub mod window;
+
+#[cfg(feature = "gui")]
+pub use render::RenderContext;
+pub use error::{Result, VoxelError};
+pub use render::{AdapterOptions, GpuContext};
+pub use tracer::{
+    Camera, Instance, Light, ShaderOptions, Tracer, Uniforms, UniformsBuilder, Voxel, VoxelGrid,
+};
@@ -1,8 +1,190 @@
-use voxeltracer::app::App;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use voxeltracer::{
+    app::{App, MAX_HISTORY_DEPTH},
+    bench,
+    render::{parse_backend, parse_power_preference, AdapterOptions, GpuContext},
+    scene::Scene,
+    tracer::{save_with_metadata, Camera, Light, ShaderOptions, Tracer, Uniforms, VoxelGrid},
+};
+
+// The amount of traces per grid when benchmarking
+const BENCH_ITERATIONS: u32 = 20;
+
+/// A voxel renderer using a wgpu compute shader
+#[derive(Debug, Parser)]
+struct Args {
+    /// Log more detailed messages, `RUST_LOG` overrides the level
+    #[clap(long, global = true)]
+    verbose: bool,
+    /// The backend of the gpu (vulkan, dx12, dx11, metal or gl)
+    #[clap(long, global = true, value_parser = parse_backend)]
+    backend: Option<wgpu::Backends>,
+    /// A part of the name of the gpu to use
+    #[clap(long, global = true)]
+    adapter: Option<String>,
+    /// Prefer a dedicated (high) or an integrated (low) gpu when no adapter is given
+    #[clap(long, global = true, value_parser = parse_power_preference)]
+    power_preference: Option<wgpu::PowerPreference>,
+    /// Load the shader from a file, so it can be changed without recompiling
+    #[clap(long)]
+    shader: Option<PathBuf>,
+    /// The compute function of the shader to run
+    #[clap(long, default_value = "main")]
+    entry_point: String,
+    /// Set a constant of the shader as `NAME=VALUE`, can be used multiple times
+    #[clap(long = "define", value_parser = parse_define)]
+    defines: Vec<(String, String)>,
+    /// Measure the frame times instead of running the app
+    #[clap(long)]
+    bench_trace: bool,
+    /// Draw the ui at this scale instead of the scale of the display, for testing HiDPI layouts
+    #[clap(long, value_parser = parse_scale_factor)]
+    scale_factor: Option<f32>,
+    /// The maximum amount of edits that can be undone
+    #[clap(long, default_value_t = MAX_HISTORY_DEPTH)]
+    history_depth: usize,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Render a scene to an image without opening a window
+    Render {
+        /// The scene, a MagicaVoxel `.vox` file or a `.ron` scene
+        #[clap(long)]
+        input: PathBuf,
+        /// The image, the format is inferred from the extension (png, jpg or bmp)
+        #[clap(long)]
+        output: PathBuf,
+        #[clap(long, default_value_t = 1920, value_parser = clap::value_parser!(u32).range(1..))]
+        width: u32,
+        #[clap(long, default_value_t = 1080, value_parser = clap::value_parser!(u32).range(1..))]
+        height: u32,
+        /// The position of the camera as `x,y,z`
+        #[clap(long, value_parser = parse_vec3, allow_hyphen_values = true)]
+        camera_pos: Option<glam::Vec3>,
+    },
+}
 
 fn main() -> anyhow::Result<()> {
+    // Invalid arguments print the usage and exit with an error code
+    let args = Args::parse();
+
+    // Start the logger, `RUST_LOG` overrides the default level
+    let level = if args.verbose {
+        "info,voxeltracer=debug"
+    } else {
+        "warn,voxeltracer=info"
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    // The environment variables of wgpu are used for the options that aren't given
+    let options = AdapterOptions {
+        backend: args.backend,
+        name: args.adapter,
+        power_preference: args.power_preference,
+    }
+    .or_env();
+
+    // Render a single image without the window if requested
+    if let Some(Command::Render {
+        input,
+        output,
+        width,
+        height,
+        camera_pos,
+    }) = args.command
+    {
+        return render(&options, &input, &output, glam::uvec2(width, height), camera_pos);
+    }
+
+    // Run the benchmark instead of the app if requested
+    if args.bench_trace {
+        return bench::bench_trace(BENCH_ITERATIONS, options);
+    }
+
     // Create and run the app
-    let mut app = App::new()?;
+    let shader = ShaderOptions {
+        path: args.shader,
+        entry_point: args.entry_point,
+        defines: args.defines.into_iter().collect(),
+    };
+    let mut app = App::new(options, shader)?;
+    app.set_scale_factor(args.scale_factor);
+    app.set_history_depth(args.history_depth);
     app.run()?;
     Ok(())
 }
+
+/// Render a scene to an image without creating the window and the ui
+fn render(
+    options: &AdapterOptions,
+    input: &Path,
+    output: &Path,
+    resolution: glam::UVec2,
+    camera_pos: Option<glam::Vec3>,
+) -> anyhow::Result<()> {
+    // Load the scene, a vox file only has the voxels so the defaults are used for the rest
+    let extension = input
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let scene = match extension.as_deref() {
+        Some("vox") => Scene {
+            grid: VoxelGrid::from_vox_file(input)?,
+            lights: vec![Light::default()],
+            camera: Camera::default(),
+            uniforms: Uniforms::default(),
+        },
+        Some("ron") => Scene::load(input)?,
+        _ => return Err(anyhow::anyhow!("Unsupported scene format, use vox or ron")),
+    };
+    let camera = Camera {
+        position: camera_pos.unwrap_or(scene.camera.position),
+        ..scene.camera
+    };
+
+    // Trace the frame and save it
+    let gpu = pollster::block_on(GpuContext::new(options))?;
+    let image = Tracer::render_offline(&gpu, resolution, scene.uniforms, &scene.grid, &scene.lights, camera)?;
+    save_with_metadata(&image, output, &camera, &scene.uniforms)?;
+    log::info!("Rendered {} to {}", input.display(), output.display());
+    Ok(())
+}
+
+/// Parse a scale factor, it has to be positive
+fn parse_scale_factor(value: &str) -> anyhow::Result<f32> {
+    let scale = value.parse::<f32>()?;
+    if scale.is_finite() && scale > 0.0 {
+        Ok(scale)
+    } else {
+        Err(anyhow::anyhow!("The scale factor has to be positive"))
+    }
+}
+
+/// Parse a shader constant written as `NAME=VALUE`
+fn parse_define(value: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("The define has to be written as NAME=VALUE"))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(anyhow::anyhow!("'{}' isn't a valid constant name", name));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Parse a vector written as `x,y,z`
+fn parse_vec3(value: &str) -> anyhow::Result<glam::Vec3> {
+    let components = value
+        .split(',')
+        .map(|component| component.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match components[..] {
+        [x, y, z] => Ok(glam::vec3(x, y, z)),
+        _ => Err(anyhow::anyhow!("Expected 3 numbers separated by commas, got '{}'", value)),
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::Path;
+
+// The 4x4 bayer matrix used for ordered dithering
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+// How far a color can be moved by the dithering
+const DITHER_SPREAD: f32 = 32.0;
+
+/// Represents a limited set of colors the frame can be quantized to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette(pub Vec<[u8; 3]>);
+
+impl Palette {
+    /// Load a palette from a file
+    /// Supports `.hex` files with a `RRGGBB` color per line and GIMP `.gpl` files
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        let palette = match extension.as_deref() {
+            Some("hex") => Self::parse_hex(&text)?,
+            Some("gpl") => Self::parse_gpl(&text)?,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported palette format, use hex or gpl"
+                ))
+            }
+        };
+        if palette.0.is_empty() {
+            return Err(anyhow::anyhow!("The palette has no colors"));
+        }
+        Ok(palette)
+    }
+
+    /// Parse a palette with a `RRGGBB` color per line
+    fn parse_hex(text: &str) -> anyhow::Result<Self> {
+        text.lines()
+            .map(str::trim)
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                let hex = line.trim_start_matches('#');
+                let value = u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 6)
+                    .ok_or(anyhow::anyhow!("Invalid color '{}' on line {}", line, i + 1))?;
+                Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+
+    /// Parse a GIMP palette, which has a `R G B name` color per line after the header
+    fn parse_gpl(text: &str) -> anyhow::Result<Self> {
+        text.lines()
+            .map(str::trim)
+            .enumerate()
+            // Skip the header, the comments and the empty lines
+            .filter(|(_, line)| {
+                !line.is_empty()
+                    && !line.starts_with('#')
+                    && !line.starts_with("GIMP Palette")
+                    && !line.starts_with("Name:")
+                    && !line.starts_with("Columns:")
+            })
+            .map(|(i, line)| {
+                let mut channels = line.split_whitespace().map(str::parse::<u8>);
+                let mut channel = || {
+                    channels
+                        .next()
+                        .and_then(Result::ok)
+                        .ok_or(anyhow::anyhow!("Invalid color '{}' on line {}", line, i + 1))
+                };
+                Ok([channel()?, channel()?, channel()?])
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+
+    /// Get the color of the palette that is closest to a color
+    pub fn nearest(&self, color: [f32; 3]) -> [u8; 3] {
+        let distance = |entry: &[u8; 3]| {
+            (0..3)
+                .map(|i| (entry[i] as f32 - color[i]).powi(2))
+                .sum::<f32>()
+        };
+        self.0
+            .iter()
+            .copied()
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+            .unwrap_or([0, 0, 0])
+    }
+
+    /// Replace every pixel of an image with the closest color of the palette
+    /// The alpha channel is kept as is
+    pub fn quantize(&self, image: &mut image::RgbaImage, dither: bool) {
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            // Offset the color by the bayer matrix to spread the error over neighbouring pixels
+            let offset = if dither {
+                (BAYER[y as usize % 4][x as usize % 4] as f32 / 16.0 - 0.5) * DITHER_SPREAD
+            } else {
+                0.0
+            };
+            let color = [
+                pixel[0] as f32 + offset,
+                pixel[1] as f32 + offset,
+                pixel[2] as f32 + offset,
+            ];
+            let [r, g, b] = self.nearest(color);
+            *pixel = image::Rgba([r, g, b, pixel[3]]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::{save_with_metadata, Camera, Uniforms};
+
+    // Get a palette of black, white, red and blue
+    fn palette() -> Palette {
+        Palette(vec![[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 0, 255]])
+    }
+
+    // Get an image with a gradient in every channel and a transparent column
+    fn gradient() -> image::RgbaImage {
+        image::RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, if x == 0 { 0 } else { 255 }])
+        })
+    }
+
+    #[test]
+    fn parse_hex_and_gpl() {
+        let hex = Palette::parse_hex("ff0000\n\n#00FF80\n").unwrap();
+        assert_eq!(hex, Palette(vec![[255, 0, 0], [0, 255, 128]]));
+        assert!(Palette::parse_hex("ff00\n").is_err());
+        let gpl = Palette::parse_gpl("GIMP Palette\nName: Test\nColumns: 2\n# A comment\n255 0 0 Red\n0 255 128\n").unwrap();
+        assert_eq!(gpl, hex);
+        assert!(Palette::parse_gpl("GIMP Palette\n255 0\n").is_err());
+    }
+
+    #[test]
+    fn nearest_color() {
+        assert_eq!(palette().nearest([200.0, 40.0, 30.0]), [255, 0, 0]);
+        assert_eq!(palette().nearest([100.0, 100.0, 100.0]), [0, 0, 0]);
+        assert_eq!(palette().nearest([180.0, 180.0, 180.0]), [255, 255, 255]);
+    }
+
+    #[test]
+    fn quantize_uses_only_palette_colors() {
+        let palette = palette();
+        for dither in [false, true] {
+            let original = gradient();
+            let mut image = original.clone();
+            palette.quantize(&mut image, dither);
+            for (pixel, original) in image.pixels().zip(original.pixels()) {
+                assert!(palette.0.contains(&[pixel[0], pixel[1], pixel[2]]), "{:?} isn't in the palette", pixel);
+                assert_eq!(pixel[3], original[3]);
+            }
+        }
+    }
+
+    #[test]
+    fn dither_mixes_the_closest_colors() {
+        let palette = Palette(vec![[0, 0, 0], [255, 255, 255]]);
+        // A gray just below the middle of the two colors
+        let mut image = image::RgbaImage::from_pixel(4, 4, image::Rgba([120, 120, 120, 255]));
+        palette.quantize(&mut image, true);
+        let white = image.pixels().filter(|pixel| pixel[0] == 255).count();
+        assert!(white > 0 && white < 16);
+        // Without dithering the whole image is black
+        let mut image = image::RgbaImage::from_pixel(4, 4, image::Rgba([120, 120, 120, 255]));
+        palette.quantize(&mut image, false);
+        assert!(image.pixels().all(|pixel| pixel[0] == 0));
+    }
+
+    #[test]
+    fn saved_png_contains_only_palette_colors() {
+        let palette = palette();
+        let mut image = gradient();
+        palette.quantize(&mut image, true);
+        let path = std::env::temp_dir().join(format!("voxeltracer-quantized-{}.png", std::process::id()));
+        save_with_metadata(&image, &path, &Camera::default(), &Uniforms::default()).unwrap();
+        let saved = image::open(&path).unwrap().to_rgba8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, image);
+        assert!(saved.pixels().all(|pixel| palette.0.contains(&[pixel[0], pixel[1], pixel[2]])));
+    }
+}
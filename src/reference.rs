@@ -0,0 +1,701 @@
+//! A cpu implementation of `shaders/voxel.wgsl`
+//! It is slow, but it makes it possible to check single pixels of the gpu output
+
+use crate::tracer::{
+    Camera, Light, Uniforms, VoxelGrid, LIGHT_DIRECTIONAL, OUTPUT_DEPTH, OUTPUT_NORMALS, OUTPUT_SHADED, SKY_GRADIENT,
+    MAX_BOUNCES, PATTERN_CHECKER, PATTERN_NOISE, PATTERN_SOLID, SKY_PROCEDURAL, TONEMAP_ACES, TONEMAP_REINHARD, VOXEL_SIZE,
+};
+
+// The half size of a voxel, this is the size of the boxes in the distance field
+const HALF_VOXEL: f32 = VOXEL_SIZE / 2.0;
+
+/// Represents a rayhit, this mirrors `RayHit` in the shader
+#[derive(Debug, Clone, Copy)]
+struct RayHit {
+    position: glam::Vec3,
+    distance: f32,
+    color: glam::Vec4,
+    emission: f32,
+    roughness: f32,
+    metallic: f32,
+    pattern: u32,
+    pattern_scale: f32,
+}
+
+/// The grid cells under a point on the screen, see [`VoxelGrid::pick_cpu`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PickResult {
+    /// The voxel that was hit, [`None`] if the floor was hit
+    pub voxel: Option<glam::IVec3>,
+    /// The empty cell in front of the hit face, this is where a new voxel would go
+    pub adjacent: glam::IVec3,
+}
+
+impl PickResult {
+    /// Get the voxel that was hit, or the empty cell above the floor
+    pub fn cell(&self) -> glam::IVec3 {
+        self.voxel.unwrap_or(self.adjacent)
+    }
+}
+
+/// The result of marching a ray
+#[derive(Debug, Clone, Copy)]
+struct March {
+    hit: Option<RayHit>,
+    // The distance along the ray where the march stopped
+    travelled: f32,
+    // The amount of steps that were taken
+    steps: i32,
+}
+
+impl VoxelGrid {
+    /// Trace a single pixel on the cpu
+    /// Returns the srgb color the shader writes to the frame before it gets quantized to 8 bits per channel
+    /// The rays go through the centre of the lens, so this only matches the shader for an aperture of 0
+    pub fn trace_cpu(
+        &self,
+        camera: Camera,
+        uniforms: Uniforms,
+        lights: &[Light],
+        pixel: glam::UVec2,
+    ) -> glam::Vec4 {
+        // Only use as many lights as the shader would
+        let lights = &lights[..lights.len().min(uniforms.light_count as usize)];
+        // Get the pixel in the whole image, the frame is only a tile of it when rendering tiled
+        let image_pixel = pixel + uniforms.tile_offset;
+        let image_resolution = if uniforms.full_resolution.cmpgt(glam::UVec2::ZERO).all() {
+            uniforms.full_resolution
+        } else {
+            uniforms.resolution
+        };
+        // Average the rays through the pixel, this mirrors the sample loop in `main` of the shader
+        let samples = uniforms.samples_per_pixel.max(1);
+        let color = (0..samples)
+            .map(|i| {
+                let offset = (i as f32 * glam::vec2(0.7548777, 0.5698403) + glam::Vec2::splat(0.5)).fract()
+                    - glam::Vec2::splat(0.5);
+                // Calculate the uv with the centre as the origin
+                let mut uv = (image_pixel.as_vec2() + offset) / image_resolution.as_vec2() - glam::Vec2::splat(0.5);
+                uv.y *= -1.0;
+                uv.x *= image_resolution.x as f32 / image_resolution.y as f32;
+                self.shade(&uniforms, lights, camera.ray_origin(uv), camera.ray_vector(uv))
+            })
+            .fold(glam::Vec4::ZERO, |sum, color| sum + color);
+        let mut color = color / samples as f32;
+        // Undo the premultiplied alpha of a transparent background, the png stores straight alpha
+        if uniforms.output_mode == OUTPUT_SHADED && uniforms.alpha_background != 0 && color.w > 0.0 {
+            color = (color.truncate() / color.w).extend(color.w);
+        }
+        let rgb = if uniforms.output_mode == OUTPUT_SHADED {
+            tonemap(&uniforms, color.truncate())
+        } else {
+            color.truncate()
+        };
+        srgb_from_linear(rgb).extend(color.w)
+    }
+
+    /// Get the grid cells under a point on the screen
+    /// Returns [`None`] if the ray hit nothing
+    /// The uv is the same as for [`Camera::ray_direction`]
+    pub fn pick_cpu(&self, camera: Camera, uniforms: Uniforms, uv: glam::Vec2) -> Option<PickResult> {
+        let hit = self.march(&uniforms, camera.ray_origin(uv), camera.ray_vector(uv), uniforms.near).hit?;
+        let normal = self.calculate_normal(&uniforms, hit);
+        // The cell behind the hit face is the voxel that was hit
+        let cell = |position: glam::Vec3| {
+            (tile_position(&uniforms, position) / VOXEL_SIZE)
+                .round()
+                .as_ivec3()
+        };
+        let behind = cell(hit.position - normal * HALF_VOXEL);
+        Some(PickResult {
+            // Otherwise the floor was hit
+            voxel: self.get(behind).is_some().then_some(behind),
+            adjacent: cell(hit.position + normal * HALF_VOXEL),
+        })
+    }
+
+    /// Shade a ray including the reflections, this mirrors `shade` in the shader
+    fn shade(
+        &self,
+        uniforms: &Uniforms,
+        lights: &[Light],
+        origin: glam::Vec3,
+        direction: glam::Vec3,
+    ) -> glam::Vec4 {
+        let (first, first_hit) = self.cast_ray(uniforms, lights, origin, direction, uniforms.near);
+        if uniforms.output_mode != OUTPUT_SHADED {
+            return first;
+        }
+
+        // The sum of the colors so far and how much the next bounce contributes to it
+        let mut color = glam::Vec3::ZERO;
+        let mut weight = 1.0;
+        let (mut current, mut hit) = (first, first_hit);
+        let mut direction = direction.normalize();
+        for _ in 0..uniforms.max_bounces.min(MAX_BOUNCES) {
+            // Stop at misses and at surfaces that don't reflect
+            let surface = match hit {
+                Some(surface) if surface.metallic > 0.0 => surface,
+                _ => break,
+            };
+            color += weight * (1.0 - surface.metallic) * current.truncate();
+            weight *= surface.metallic;
+            // Reflect the ray, starting just off the surface so it doesn't hit itself
+            let normal = self.calculate_normal(uniforms, surface);
+            direction -= 2.0 * direction.dot(normal) * normal;
+            (current, hit) = self.cast_ray(uniforms, lights, surface.position + normal * 0.01, direction, 0.02);
+        }
+        let mut color = color + weight * current.truncate();
+        // Fade the hits into the fog, the background is left as is
+        if let Some(first_hit) = first_hit.filter(|_| uniforms.fog_density > 0.0) {
+            let amount = 1.0 - (-uniforms.fog_density * (first_hit.position - origin).length()).exp();
+            color = color.lerp(uniforms.fog_color.truncate(), amount);
+        }
+        // Premultiply a transparent background, so averaging the samples doesn't blend in the sky
+        if uniforms.alpha_background != 0 {
+            color *= first.w;
+        }
+        color.extend(first.w)
+    }
+
+    /// Cast a ray, this mirrors `cast_ray` in the shader
+    /// Returns the color and the hit, the hit is [`None`] for misses
+    fn cast_ray(
+        &self,
+        uniforms: &Uniforms,
+        lights: &[Light],
+        origin: glam::Vec3,
+        direction: glam::Vec3,
+        near: f32,
+    ) -> (glam::Vec4, Option<RayHit>) {
+        let march = self.march(uniforms, origin, direction, near);
+        if uniforms.output_mode != OUTPUT_SHADED {
+            return (self.debug_color(uniforms, &march), march.hit);
+        }
+        // Shade the hit
+        if let Some(hit) = march.hit {
+            let color = self
+                .lighting(uniforms, lights, hit, direction.normalize())
+                .extend(1.0);
+            return (color, Some(hit));
+        }
+
+        let mut color = sky(uniforms, direction.normalize());
+        if uniforms.alpha_background != 0 {
+            color.w = 0.0;
+        }
+        (color, None)
+    }
+
+    /// Get the color of a debug output mode, this mirrors `debug_color` in the shader
+    fn debug_color(&self, uniforms: &Uniforms, march: &March) -> glam::Vec4 {
+        let black = glam::vec4(0.0, 0.0, 0.0, 1.0);
+        match (uniforms.output_mode, march.hit) {
+            (OUTPUT_NORMALS, Some(hit)) => {
+                (self.calculate_normal(uniforms, hit) * 0.5 + 0.5).extend(1.0)
+            }
+            (OUTPUT_DEPTH, Some(_)) => glam::Vec3::splat(1.0 / (1.0 + march.travelled)).extend(1.0),
+            (OUTPUT_NORMALS | OUTPUT_DEPTH, None) => black,
+            _ => glam::Vec3::splat(march.steps as f32 / uniforms.max_steps.max(1) as f32).extend(1.0),
+        }
+    }
+
+    /// March a ray through the distance field
+    /// The hit is [`None`] if the ray didn't hit anything before the far plane or the step limit
+    /// The march starts at the near distance along the ray
+    fn march(&self, uniforms: &Uniforms, origin: glam::Vec3, direction: glam::Vec3, near: f32) -> March {
+        let max_iters = uniforms.max_steps as i32;
+        // The potential hit
+        let mut hit = RayHit {
+            position: glam::Vec3::ZERO,
+            distance: max_iters as f32,
+            color: uniforms.background_color,
+            emission: 0.0,
+            roughness: 1.0,
+            metallic: 0.0,
+            pattern: PATTERN_SOLID,
+            pattern_scale: 1.0,
+        };
+
+        // Start marching at the near plane
+        let mut travelled = near;
+        hit = self.map(uniforms, origin + travelled * direction, hit);
+        travelled += hit.distance;
+
+        let mut steps = 0;
+        let mut i = 0;
+        while i < max_iters && travelled > 0.01 {
+            steps = i + 1;
+            // Update the potential hit
+            hit = self.map(uniforms, origin + travelled * direction, hit);
+            // Check if the potential hit is close enough
+            if hit.distance < uniforms.hit_epsilon {
+                return March {
+                    hit: Some(hit),
+                    travelled,
+                    steps,
+                };
+            }
+            // Stop at the far plane
+            if travelled > uniforms.far {
+                break;
+            }
+            travelled += hit.distance;
+            i += 1;
+        }
+        March {
+            hit: None,
+            travelled,
+            steps,
+        }
+    }
+
+    /// Get the closest object, this mirrors `map` in the shader
+    fn map(&self, uniforms: &Uniforms, raypos: glam::Vec3, hit: RayHit) -> RayHit {
+        let mut result = hit;
+        // Draw the floor
+        if uniforms.floor_enabled != 0 {
+            let distance = raypos.y - uniforms.floor_height;
+            if distance < result.distance {
+                result = RayHit {
+                    position: raypos,
+                    distance,
+                    color: uniforms.floor_color,
+                    emission: 0.0,
+                    roughness: 1.0,
+                    metallic: 0.0,
+                    pattern: PATTERN_SOLID,
+                    pattern_scale: 1.0,
+                };
+            }
+        }
+        // Draw each voxel
+        let tiled = tile_position(uniforms, raypos);
+        for voxel in self.voxels().iter().filter(|voxel| !is_clipped(uniforms, voxel.position())) {
+            let distance = voxel.distance(tiled);
+            if distance < result.distance {
+                result = RayHit {
+                    position: raypos,
+                    distance,
+                    color: voxel_color(uniforms, self.color(voxel), voxel.amplitude, voxel.frequency).extend(1.0),
+                    emission: voxel.emission,
+                    roughness: voxel.roughness,
+                    metallic: voxel.metallic,
+                    pattern: voxel.pattern,
+                    pattern_scale: voxel.pattern_scale,
+                };
+            }
+        }
+        result.position = raypos;
+        result
+    }
+
+    /// Calculate the normal from the gradient of the distance field
+    fn calculate_normal(&self, uniforms: &Uniforms, hit: RayHit) -> glam::Vec3 {
+        let step = 0.001;
+        let distance = |offset: glam::Vec3| self.map(uniforms, hit.position + offset, hit).distance;
+        glam::vec3(
+            distance(glam::Vec3::X * step) - distance(-glam::Vec3::X * step),
+            distance(glam::Vec3::Y * step) - distance(-glam::Vec3::Y * step),
+            distance(glam::Vec3::Z * step) - distance(-glam::Vec3::Z * step),
+        )
+        .normalize()
+    }
+
+    /// Calculate the ambient occlusion, this mirrors `ambient_occlusion` in the shader
+    fn ambient_occlusion(&self, uniforms: &Uniforms, normal: glam::Vec3, hit: RayHit) -> f32 {
+        // The empty cell in front of the hit face
+        let cell = (tile_position(uniforms, hit.position + normal * HALF_VOXEL) / VOXEL_SIZE)
+            .round()
+            .as_ivec3();
+        // The directions along the hit face
+        let mut tangent = glam::IVec3::X;
+        let mut bitangent = glam::IVec3::Z;
+        if normal.x.abs() > normal.y.abs() && normal.x.abs() > normal.z.abs() {
+            tangent = glam::IVec3::Y;
+        } else if normal.z.abs() > normal.y.abs() {
+            bitangent = glam::IVec3::Y;
+        }
+
+        // The neighbours along the face, edges first
+        let offsets = [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)];
+        let count = uniforms.ambient_occlusion.clamp(0, 8) as usize;
+        if count == 0 {
+            return 1.0;
+        }
+        let occluded = offsets[..count]
+            .iter()
+            .map(|(x, y)| cell + tangent * *x + bitangent * *y)
+            .filter(|neighbour| {
+                !is_clipped(uniforms, *neighbour) && self.get(*neighbour).is_some()
+            })
+            .count();
+        1.0 - occluded as f32 / count as f32 * uniforms.ao_strength
+    }
+
+    /// Calculate how much of a light reaches a point, this mirrors `soft_shadow` in the shader
+    fn soft_shadow(&self, uniforms: &Uniforms, origin: glam::Vec3, direction: glam::Vec3, max_distance: f32) -> f32 {
+        let k = 1.0 / uniforms.shadow_softness;
+        let mut result: f32 = 1.0;
+        let mut travelled = 0.02;
+        let mut i = 0;
+        while i < uniforms.max_steps as i32 && travelled < max_distance {
+            // Start each step without a hit
+            let probe = RayHit {
+                position: glam::Vec3::ZERO,
+                distance: max_distance,
+                color: glam::Vec4::ZERO,
+                emission: 0.0,
+                roughness: 1.0,
+                metallic: 0.0,
+                pattern: PATTERN_SOLID,
+                pattern_scale: 1.0,
+            };
+            let distance = self.map(uniforms, origin + direction * travelled, probe).distance;
+            if distance < uniforms.hit_epsilon {
+                return 0.0;
+            }
+            result = result.min(k * distance / travelled);
+            travelled += distance;
+            i += 1;
+        }
+        result.clamp(0.0, 1.0)
+    }
+
+    /// Calculate the lighting, this mirrors `lighting` in the shader
+    fn lighting(
+        &self,
+        uniforms: &Uniforms,
+        lights: &[Light],
+        hit: RayHit,
+        view_dir: glam::Vec3,
+    ) -> glam::Vec3 {
+        let normal = self.calculate_normal(uniforms, hit);
+        let mut diffuse = glam::Vec3::ZERO;
+        let mut specular = glam::Vec3::ZERO;
+        let shininess = 64.0 + (1.0 - 64.0) * hit.roughness;
+        let tint = glam::Vec3::ONE.lerp(hit.color.truncate(), hit.metallic);
+        // Accumulate the contribution of each light
+        for light in lights {
+            let (light_dir, light_distance) = if light.light_type == LIGHT_DIRECTIONAL {
+                (light.position.normalize(), uniforms.far)
+            } else {
+                let to_light = light.position - hit.position;
+                (to_light.normalize(), to_light.length())
+            };
+            let shadow = if uniforms.shadow_softness > 0.0 {
+                self.soft_shadow(uniforms, hit.position + normal * 0.01, light_dir, light_distance)
+            } else {
+                1.0
+            };
+            diffuse += light.color * light.intensity * (0.1 + normal.dot(light_dir).clamp(0.0, 1.0) * shadow);
+            let half_dir = (light_dir - view_dir).normalize();
+            specular += tint
+                * light.color
+                * light.intensity
+                * normal.dot(half_dir).clamp(0.0, 1.0).powf(shininess)
+                * (1.0 - hit.roughness)
+                * shadow;
+        }
+        // Accumulate the glow of the emissive voxels
+        for voxel in self
+            .voxels()
+            .iter()
+            .filter(|voxel| voxel.emission > 0.0 && !is_clipped(uniforms, voxel.position()))
+        {
+            let to_voxel = voxel.position().as_vec3() * VOXEL_SIZE - hit.position;
+            let distance = to_voxel.length();
+            diffuse += self.color(voxel) * voxel.emission * normal.dot(to_voxel / distance).clamp(0.0, 1.0)
+                / (1.0 + distance * distance);
+        }
+        let ambient = self.ambient_occlusion(uniforms, normal, hit);
+
+        hit.color.truncate() * pattern_factor(hit, normal) * (diffuse * ambient * (1.0 - hit.metallic) + hit.emission) + specular
+    }
+}
+
+/// Wrap a position into the first copy of the tiled grid, this mirrors `tile_position` in the shader
+fn tile_position(uniforms: &Uniforms, position: glam::Vec3) -> glam::Vec3 {
+    if uniforms.tile_spacing <= 0.0 {
+        return position;
+    }
+    let last = (uniforms.tile_count.max(glam::UVec3::ONE) - glam::UVec3::ONE).as_vec3();
+    let tile = (position / uniforms.tile_spacing)
+        .round()
+        .clamp(glam::Vec3::ZERO, last);
+    position - tile * uniforms.tile_spacing
+}
+
+/// Check if the centre of a voxel is outside of the clip box, this mirrors `is_clipped` in the shader
+fn is_clipped(uniforms: &Uniforms, position: glam::IVec3) -> bool {
+    let centre = position.as_vec3() * VOXEL_SIZE;
+    centre.cmplt(uniforms.clip_min).any() || centre.cmpgt(uniforms.clip_max).any()
+}
+
+/// Hash a cell of a pattern to a pseudo random number between 0 and 1, this mirrors `cell_hash` in the shader
+fn cell_hash(cell: glam::IVec2) -> f32 {
+    let mut h = (cell.x as u32).wrapping_mul(1973).wrapping_add((cell.y as u32).wrapping_mul(9277));
+    h = (h ^ (h >> 16)).wrapping_mul(0x7feb352d);
+    h = (h ^ (h >> 15)).wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    (h & 0xffff) as f32 / 65535.0
+}
+
+/// Smoothly interpolate the random values of the corners of the cells, this mirrors `value_noise` in the shader
+fn value_noise(position: glam::Vec2) -> f32 {
+    let cell = position.floor();
+    let f = position - cell;
+    let s = f * f * (glam::Vec2::splat(3.0) - 2.0 * f);
+    let c = cell.as_ivec2();
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let bottom = lerp(cell_hash(c), cell_hash(c + glam::ivec2(1, 0)), s.x);
+    let top = lerp(cell_hash(c + glam::ivec2(0, 1)), cell_hash(c + glam::ivec2(1, 1)), s.x);
+    lerp(bottom, top, s.y)
+}
+
+/// Get how much the pattern of the hit darkens its color, this mirrors `pattern_factor` in the shader
+fn pattern_factor(hit: RayHit, normal: glam::Vec3) -> f32 {
+    if hit.pattern == PATTERN_SOLID {
+        return 1.0;
+    }
+    let scaled = hit.position / hit.pattern_scale.max(0.001);
+    let n = normal.abs();
+    let uv = if n.x >= n.y && n.x >= n.z {
+        glam::vec2(scaled.y, scaled.z)
+    } else if n.y >= n.z {
+        glam::vec2(scaled.x, scaled.z)
+    } else {
+        glam::vec2(scaled.x, scaled.y)
+    };
+    match hit.pattern {
+        PATTERN_CHECKER => {
+            let cell = uv.floor().as_ivec2();
+            if (cell.x + cell.y) & 1 != 0 {
+                0.5
+            } else {
+                1.0
+            }
+        }
+        PATTERN_NOISE => 0.5 + 0.5 * value_noise(uv),
+        _ => 1.0,
+    }
+}
+
+/// Get the color of a voxel, this mirrors `voxel_color` in the shader
+fn voxel_color(uniforms: &Uniforms, color: glam::Vec3, amplitude: f32, frequency: f32) -> glam::Vec3 {
+    if uniforms.animate == 0 {
+        return color;
+    }
+    let pulse = (uniforms.time * frequency * std::f32::consts::TAU).sin();
+    color * (1.0 + amplitude * pulse).max(0.0)
+}
+
+/// Encode a linear color as srgb, this mirrors `srgb_from_linear` in the shader
+fn srgb_from_linear(color: glam::Vec3) -> glam::Vec3 {
+    let encode = |channel: f32| {
+        let linear = channel.clamp(0.0, 1.0);
+        if linear <= 0.0031308 {
+            linear * 12.92
+        } else {
+            1.055 * linear.powf(1.0 / 2.4) - 0.055
+        }
+    };
+    glam::vec3(encode(color.x), encode(color.y), encode(color.z))
+}
+
+/// Map a linear color to the range of the frame, this mirrors `tonemap` in the shader
+fn tonemap(uniforms: &Uniforms, color: glam::Vec3) -> glam::Vec3 {
+    let exposed = color * uniforms.exposure;
+    match uniforms.tonemap_mode {
+        TONEMAP_REINHARD => exposed / (glam::Vec3::ONE + exposed),
+        TONEMAP_ACES => {
+            let numerator = exposed * (2.51 * exposed + glam::Vec3::splat(0.03));
+            let denominator = exposed * (2.43 * exposed + glam::Vec3::splat(0.59)) + glam::Vec3::splat(0.14);
+            (numerator / denominator).clamp(glam::Vec3::ZERO, glam::Vec3::ONE)
+        }
+        _ => exposed,
+    }
+}
+
+/// Get the color of the sky, this mirrors `sky` in the shader
+fn sky(uniforms: &Uniforms, direction: glam::Vec3) -> glam::Vec4 {
+    let height = direction.y * 0.5 + 0.5;
+    match uniforms.sky_mode {
+        SKY_GRADIENT => uniforms.sky_bottom_color.lerp(uniforms.sky_top_color, height),
+        SKY_PROCEDURAL => {
+            let mut color = uniforms
+                .sky_bottom_color
+                .lerp(uniforms.sky_top_color, direction.y.clamp(0.0, 1.0).sqrt());
+            if direction.y < 0.0 {
+                color = (uniforms.sky_bottom_color.truncate() * (1.0 + direction.y * 0.5))
+                    .extend(uniforms.sky_bottom_color.w);
+            }
+            let sun = direction
+                .dot(glam::vec3(0.5, 0.6, -0.6).normalize())
+                .max(0.0)
+                .powf(256.0);
+            color + glam::Vec3::splat(sun).extend(0.0)
+        }
+        _ => uniforms.background_color,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::{Voxel, OUTPUT_NORMALS};
+
+    // Get the scene the app starts with
+    fn default_scene() -> VoxelGrid {
+        VoxelGrid::from_voxels(vec![
+            Voxel::new(glam::ivec3(0, 0, 0), glam::vec3(1.0, 1.0, 1.0)),
+            Voxel::new(glam::ivec3(1, 1, 0), glam::vec3(0.0, 1.0, 0.0)),
+            Voxel::new(glam::ivec3(1, 2, 0), glam::vec3(1.0, 1.0, 0.0)),
+            Voxel::new(glam::ivec3(-1, 1, 0), glam::vec3(0.0, 0.0, 1.0)),
+            Voxel::new(glam::ivec3(0, 1, -1), glam::vec3(1.0, 0.0, 0.0)),
+        ])
+    }
+
+    // Get the difference of two colors in the largest channel
+    fn difference(a: glam::Vec4, b: glam::Vec4) -> f32 {
+        (a - b).abs().max_element()
+    }
+
+    // Quantize a color to 8 bits per channel like the frame does
+    fn bytes(color: glam::Vec4) -> [u8; 4] {
+        (color * 255.0).round().to_array().map(|channel| channel as u8)
+    }
+
+    #[test]
+    fn srgb_encoding_of_known_values() {
+        assert_eq!(srgb_from_linear(glam::Vec3::ZERO), glam::Vec3::ZERO);
+        assert!((srgb_from_linear(glam::Vec3::ONE) - glam::Vec3::ONE).abs().max_element() < 1e-6);
+        // Below 0.0031308 the encoding is linear
+        assert!((srgb_from_linear(glam::Vec3::splat(0.001)).x - 0.01292).abs() < 1e-6);
+        // The colors outside of the range are clamped
+        let clamped = srgb_from_linear(glam::vec3(-1.0, 2.0, 0.0));
+        assert!((clamped - glam::vec3(0.0, 1.0, 0.0)).abs().max_element() < 1e-6);
+    }
+
+    #[test]
+    fn flat_color_is_encoded_as_srgb() {
+        let uniforms = Uniforms::builder()
+            .resolution(4, 4)
+            .floor(None)
+            .background_color(glam::vec4(0.001, 0.2, 0.5, 1.0))
+            .build();
+        let color = VoxelGrid::default().trace_cpu(Camera::default(), uniforms, &[], glam::uvec2(2, 2));
+        assert_eq!(bytes(color), [3, 124, 188, 255]);
+    }
+
+    #[test]
+    fn empty_grid_traces_the_background() {
+        let uniforms = Uniforms::builder()
+            .resolution(8, 8)
+            .floor(None)
+            .background_color(glam::vec4(0.0, 0.0, 1.0, 1.0))
+            .build();
+        let grid = VoxelGrid::default();
+        for pixel in [glam::uvec2(0, 0), glam::uvec2(4, 4), glam::uvec2(7, 7)] {
+            assert_eq!(bytes(grid.trace_cpu(Camera::default(), uniforms, &[], pixel)), [0, 0, 255, 255]);
+        }
+    }
+
+    #[test]
+    fn empty_grid_picks_nothing() {
+        let uniforms = Uniforms::builder().floor(None).build();
+        assert_eq!(VoxelGrid::default().pick_cpu(Camera::default(), uniforms, glam::Vec2::ZERO), None);
+    }
+
+    #[test]
+    fn centre_ray_picks_the_front_voxel() {
+        // The default camera looks at the voxel in the origin along the z axis
+        let pick = default_scene().pick_cpu(Camera::default(), Uniforms::default(), glam::Vec2::ZERO);
+        assert_eq!(
+            pick,
+            Some(PickResult {
+                voxel: Some(glam::IVec3::ZERO),
+                adjacent: glam::ivec3(0, 0, 1),
+            })
+        );
+    }
+
+    #[test]
+    fn centre_ray_sees_the_front_face() {
+        let uniforms = Uniforms::builder().resolution(9, 9).build();
+        let centre = glam::uvec2(4, 4);
+        // The front face points towards the camera
+        let normals = Uniforms { output_mode: OUTPUT_NORMALS, ..uniforms };
+        let color = default_scene().trace_cpu(Camera::default(), normals, &[], centre);
+        assert!(difference(color, srgb_from_linear(glam::vec3(0.5, 0.5, 1.0)).extend(1.0)) < 1e-3);
+        // The front face is half a voxel in front of the origin
+        // The depth is measured in lengths of the ray vector, which isn't normalized
+        let depth = Uniforms { output_mode: OUTPUT_DEPTH, ..uniforms };
+        let color = default_scene().trace_cpu(Camera::default(), depth, &[], centre);
+        let travelled = (2.0 - HALF_VOXEL) / Camera::default().ray_vector(glam::Vec2::ZERO).length();
+        let expected = srgb_from_linear(glam::Vec3::splat(1.0 / (1.0 + travelled))).extend(1.0);
+        assert!(difference(color, expected) < 1e-3);
+    }
+
+    #[test]
+    fn lit_voxel_is_brighter_than_an_unlit_one() {
+        // The light count is normally set by the tracer
+        let uniforms = Uniforms { light_count: 1, ..Uniforms::builder().resolution(9, 9).build() };
+        let centre = glam::uvec2(4, 4);
+        let scene = default_scene();
+        let lit = scene.trace_cpu(Camera::default(), uniforms, &[Light::default()], centre);
+        let unlit = scene.trace_cpu(Camera::default(), uniforms, &[], centre);
+        assert_eq!(lit.w, 1.0);
+        assert!(lit.truncate().length() > unlit.truncate().length());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn cpu_matches_the_gpu() {
+        let gpu = pollster::block_on(crate::render::GpuContext::new(&Default::default())).unwrap();
+        let scene = default_scene();
+        let lights = [Light::default()];
+        let uniforms = Uniforms { light_count: 1, ..Uniforms::builder().resolution(64, 64).build() };
+        let image = crate::tracer::Tracer::render_offline(
+            &gpu,
+            uniforms.resolution,
+            uniforms,
+            &scene,
+            &lights,
+            Camera::default(),
+        )
+        .unwrap();
+        for pixel in [glam::uvec2(32, 32), glam::uvec2(40, 20), glam::uvec2(24, 24), glam::uvec2(2, 60)] {
+            let cpu = bytes(scene.trace_cpu(Camera::default(), uniforms, &lights, pixel));
+            let gpu = image.get_pixel(pixel.x, pixel.y).0;
+            // Allow for the rounding of the 8 bit channels and the float precision of the gpu
+            for (cpu, gpu) in cpu.into_iter().zip(gpu) {
+                assert!(cpu.abs_diff(gpu) <= 2, "pixel {} is {:?} on the cpu and {:?} on the gpu", pixel, cpu, gpu);
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_background_keeps_the_color_of_the_edges() {
+        // An emissive voxel without lights is shaded with exactly its own color
+        let color = glam::vec3(0.5, 0.2, 0.1);
+        let grid = VoxelGrid::from_voxels([Voxel::with_material(glam::IVec3::ZERO, color, 1.0, 1.0, 0.0)]);
+        let uniforms = Uniforms {
+            alpha_background: 1,
+            ..Uniforms::builder().resolution(32, 32).floor(None).samples_per_pixel(8).build()
+        };
+        let expected = srgb_from_linear(color);
+        // Find the pixels along a row that are only partly covered by the voxel
+        let edges: Vec<_> = (0..32)
+            .map(|x| grid.trace_cpu(Camera::default(), uniforms, &[], glam::uvec2(x, 16)))
+            .filter(|pixel| pixel.w > 0.0 && pixel.w < 1.0)
+            .collect();
+        assert!(!edges.is_empty());
+        // The sky isn't blended in, the coverage only ends up in the alpha
+        for pixel in edges {
+            assert!((pixel.truncate() - expected).abs().max_element() < 1e-3, "{}", pixel);
+        }
+        // A pixel that misses the voxel is fully transparent
+        assert_eq!(grid.trace_cpu(Camera::default(), uniforms, &[], glam::uvec2(0, 0)).w, 0.0);
+    }
+}
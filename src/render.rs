@@ -1,66 +1,219 @@
+use crate::error::{Result, VoxelError};
+#[cfg(feature = "gui")]
 use crate::window::Window;
 
-/// The wgpu context for rendering
-pub struct RenderContext {
+/// The options for picking the gpu adapter
+#[derive(Debug, Clone, Default)]
+pub struct AdapterOptions {
+    // The backend the adapter has to use, any backend is allowed if not set
+    pub backend: Option<wgpu::Backends>,
+    // A part of the name of the adapter to use
+    pub name: Option<String>,
+    // The kind of gpu to prefer when no adapter is requested by name, a high performance gpu if not set
+    pub power_preference: Option<wgpu::PowerPreference>,
+}
+
+impl AdapterOptions {
+    /// Fill in the options that aren't set from the environment
+    /// Uses `WGPU_BACKEND`, `WGPU_ADAPTER_NAME` and `WGPU_POWER_PREF` like the wgpu examples
+    pub fn or_env(self) -> Self {
+        Self {
+            backend: self.backend.or_else(wgpu::util::backend_bits_from_env),
+            name: self.name.or_else(|| std::env::var("WGPU_ADAPTER_NAME").ok()),
+            power_preference: self.power_preference.or_else(wgpu::util::power_preference_from_env),
+        }
+    }
+
+    /// Check if an adapter matches the options
+    fn matches(&self, info: &wgpu::AdapterInfo) -> bool {
+        let backend = self
+            .backend
+            .is_none_or(|backend| backend.contains(wgpu::Backends::from(info.backend)));
+        let name = self.name.as_ref().is_none_or(|name| {
+            info.name.to_lowercase().contains(&name.to_lowercase())
+        });
+        backend && name
+    }
+}
+
+/// Parse a power preference, high for a dedicated gpu or low for an integrated gpu
+pub fn parse_power_preference(name: &str) -> anyhow::Result<wgpu::PowerPreference> {
+    match name.to_lowercase().as_str() {
+        "high" => Ok(wgpu::PowerPreference::HighPerformance),
+        "low" => Ok(wgpu::PowerPreference::LowPower),
+        _ => Err(anyhow::anyhow!("Unknown power preference '{}', use high or low", name)),
+    }
+}
+
+/// Parse the name of a wgpu backend
+/// Supports vulkan, dx12, dx11, metal and gl
+pub fn parse_backend(name: &str) -> anyhow::Result<wgpu::Backends> {
+    match name.to_lowercase().as_str() {
+        "vulkan" => Ok(wgpu::Backends::VULKAN),
+        "dx12" => Ok(wgpu::Backends::DX12),
+        "dx11" => Ok(wgpu::Backends::DX11),
+        "metal" => Ok(wgpu::Backends::METAL),
+        "gl" => Ok(wgpu::Backends::GL),
+        _ => Err(anyhow::anyhow!(
+            "Unknown backend '{}', use vulkan, dx12, dx11, metal or gl",
+            name
+        )),
+    }
+}
+
+/// The wgpu device without a window, this is enough for tracing
+pub struct GpuContext {
     pub instance: wgpu::Instance,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
+    // The name, backend and type of the gpu that is used
+    pub adapter_info: wgpu::AdapterInfo,
+}
+
+impl GpuContext {
+    /// Construct a new [`GpuContext`] without a surface, used for rendering without a window
+    pub async fn new(options: &AdapterOptions) -> Result<Self> {
+        // Only the requested backend is initialized, so the fallback adapter uses it as well
+        let instance = wgpu::Instance::new(options.backend.unwrap_or(wgpu::Backends::all()));
+        let (adapter, device, queue) = request_device(&instance, options, None).await?;
+        Ok(Self {
+            instance,
+            device,
+            queue,
+            adapter_info: adapter.get_info(),
+        })
+    }
+}
+
+/// Pick an adapter matching the options and request its device and queue
+/// The adapter has to support the surface if one is given
+async fn request_device(
+    instance: &wgpu::Instance,
+    options: &AdapterOptions,
+    surface: Option<&wgpu::Surface>,
+) -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+    // Look for an adapter matching the options
+    let requested = if options.backend.is_some() || options.name.is_some() {
+        let adapter = instance
+            .enumerate_adapters(options.backend.unwrap_or(wgpu::Backends::all()))
+            .filter(|adapter| surface.is_none_or(|surface| adapter.is_surface_supported(surface)))
+            .find(|adapter| options.matches(&adapter.get_info()));
+        if adapter.is_none() {
+            log::warn!("The requested adapter is not available, falling back to the default adapter");
+        }
+        adapter
+    } else {
+        None
+    };
+    // Fall back to the adapter with the preferred power use, the fastest adapter by default
+    let adapter = match requested {
+        Some(adapter) => adapter,
+        None => instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: options
+                    .power_preference
+                    .unwrap_or(wgpu::PowerPreference::HighPerformance),
+                force_fallback_adapter: false,
+                compatible_surface: surface,
+            })
+            .await
+            .ok_or_else(|| VoxelError::Wgpu("Failed to request the adapter.".to_string()))?,
+    };
+    let info = adapter.get_info();
+    log::info!("Using adapter {} ({:?}, {:?})", info.name, info.backend, info.device_type);
+    log::debug!("Adapter info: {:?}", info);
+    // The tracer binds more storage buffers than the default limit allows, fail early if the adapter can't bind them all
+    #[cfg(not(target_arch = "wasm32"))]
+    crate::tracer::check_storage_buffer_count(&info.name, &adapter.limits())?;
+    // Enable the timestamp queries if the adapter supports them, they are only used for timing
+    let features = wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+        | (adapter.features() & wgpu::Features::TIMESTAMP_QUERY);
+    // Request the device and queue
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                features,
+                // The tracer binds more storage buffers than the default limit allows, so ask for what the adapter supports
+                limits: if cfg!(target_arch = "wasm32") {
+                    wgpu::Limits::downlevel_webgl2_defaults()
+                } else {
+                    wgpu::Limits {
+                        max_storage_buffers_per_shader_stage: adapter.limits().max_storage_buffers_per_shader_stage,
+                        ..wgpu::Limits::default()
+                    }
+                },
+                label: None,
+            },
+            None,
+        )
+        .await
+        .map_err(|e| VoxelError::Wgpu(e.to_string()))?;
+    Ok((adapter, device, queue))
+}
+
+/// The wgpu context for rendering
+#[cfg(feature = "gui")]
+pub struct RenderContext {
+    pub gpu: GpuContext,
     pub surface: wgpu::Surface,
     pub surface_config: wgpu::SurfaceConfiguration,
+    // The present modes the surface supports, the automatic modes are always supported
+    pub present_modes: Vec<wgpu::PresentMode>,
 
     // The egui render pass
     pub egui_pass: egui_wgpu_backend::RenderPass,
 }
 
+#[cfg(feature = "gui")]
 impl RenderContext {
     /// Construct a new [`Renderer`]
-    pub async fn new(window: &Window) -> anyhow::Result<Self> {
-        // Create a wgpu instance
-        let instance = wgpu::Instance::new(wgpu::Backends::all());
+    pub async fn new(window: &Window, options: &AdapterOptions) -> Result<Self> {
+        // Create a wgpu instance, only the requested backend is initialized
+        let instance = wgpu::Instance::new(options.backend.unwrap_or(wgpu::Backends::all()));
         // Create the surface
         let surface = window.create_surface(&instance);
-        // Request the adapter
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions::default())
-            .await
-            .ok_or(anyhow::anyhow!("Failed to request the adapter."))?;
-        // Request the device and queue
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
-                    limits: if cfg!(target_arch = "wasm32") {
-                        wgpu::Limits::downlevel_webgl2_defaults()
-                    } else {
-                        wgpu::Limits::default()
-                    },
-                    label: None,
-                },
-                None,
-            )
-            .await?;
+        // Pick the adapter and request the device and queue
+        let (adapter, device, queue) = request_device(&instance, options, Some(&surface)).await?;
 
-        // Get the surface format
-        let surface_format = surface.get_supported_formats(&adapter)[0];
+        // Get the surface format, preferring an srgb format
+        let formats = surface.get_supported_formats(&adapter);
+        let surface_format = formats
+            .iter()
+            .copied()
+            .find(|format| format.describe().srgb)
+            .or_else(|| formats.first().copied())
+            .ok_or_else(|| {
+                VoxelError::Wgpu(format!(
+                    "The adapter {} doesn't support any format for the window surface",
+                    adapter.get_info().name
+                ))
+            })?;
+        log::debug!("Using surface format {:?}", surface_format);
         // Create the surface config
         let surface_config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_DST,
             format: surface_format,
-            width: window.size().0,
-            height: window.size().1,
+            width: window.drawable_size().0,
+            height: window.drawable_size().1,
             present_mode: wgpu::PresentMode::AutoNoVsync,
         };
         surface.configure(&device, &surface_config);
+        let present_modes = surface.get_supported_modes(&adapter);
+        log::debug!("Supported present modes: {:?}", present_modes);
 
         // Create the egui render pass
         let egui_pass = egui_wgpu_backend::RenderPass::new(&device, surface_format, 1);
 
         Ok(Self {
-            instance,
-            device,
-            queue,
+            gpu: GpuContext {
+                instance,
+                device,
+                queue,
+                adapter_info: adapter.get_info(),
+            },
             surface,
             surface_config,
+            present_modes,
             egui_pass,
         })
     }
@@ -71,26 +224,36 @@ impl RenderContext {
         window: &Window,
         full_output: egui::FullOutput,
         paint_jobs: Vec<egui::ClippedPrimitive>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<()> {
         // Get the output texture
-        let output = self.surface.get_current_texture()?;
+        let output = match self.surface.get_current_texture() {
+            Ok(output) => output,
+            // The surface has to be reconfigured by the caller
+            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                return Err(VoxelError::SurfaceLost);
+            }
+            Err(e) => {
+                log::error!("Failed to get the surface texture: {}", e);
+                return Err(VoxelError::Wgpu(e.to_string()));
+            }
+        };
         // Create the output view
         let view = output.texture.create_view(&Default::default());
 
         // Create the command encoder
-        let mut encoder = self.device.create_command_encoder(&Default::default());
+        let mut encoder = self.gpu.device.create_command_encoder(&Default::default());
         // Upload all the egui resources to the gpu
         let screen_descriptor = egui_wgpu_backend::ScreenDescriptor {
-            physical_width: window.size().0,
-            physical_height: window.size().1,
-            scale_factor: 1.0,
+            physical_width: self.surface_config.width,
+            physical_height: self.surface_config.height,
+            scale_factor: window.scale_factor(),
         };
         // Add the textures to the render pass
         let tdelta = full_output.textures_delta;
         self.egui_pass
-            .add_textures(&self.device, &self.queue, &tdelta)?;
+            .add_textures(&self.gpu.device, &self.gpu.queue, &tdelta)?;
         self.egui_pass
-            .update_buffers(&self.device, &self.queue, &paint_jobs, &screen_descriptor);
+            .update_buffers(&self.gpu.device, &self.gpu.queue, &paint_jobs, &screen_descriptor);
 
         // Execute the render pass
         self.egui_pass.execute(
@@ -102,7 +265,7 @@ impl RenderContext {
         )?;
 
         // Submit the encoder to the queue and present the output
-        self.queue.submit([encoder.finish()]);
+        self.gpu.queue.submit([encoder.finish()]);
         output.present();
 
         // Clean the egui pass
@@ -111,12 +274,33 @@ impl RenderContext {
         Ok(())
     }
 
+    /// Check if the surface supports a present mode
+    pub fn supports_present_mode(&self, mode: wgpu::PresentMode) -> bool {
+        matches!(mode, wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync)
+            || self.present_modes.contains(&mode)
+    }
+
+    /// Change how the frames are presented, the vsync modes limit the frame rate to the display
+    /// The current mode is kept if the surface doesn't support the mode
+    pub fn set_present_mode(&mut self, mode: wgpu::PresentMode) -> Result<()> {
+        if !self.supports_present_mode(mode) {
+            return Err(VoxelError::Wgpu(format!(
+                "The surface doesn't support the present mode {:?}",
+                mode
+            )));
+        }
+        self.surface_config.present_mode = mode;
+        self.surface.configure(&self.gpu.device, &self.surface_config);
+        log::info!("Using present mode {:?}", mode);
+        Ok(())
+    }
+
     /// Resize the renderer
     pub fn resize(&mut self, width: u32, height: u32) {
         if width > 0 && height > 0 {
             self.surface_config.width = width;
             self.surface_config.height = height;
-            self.surface.configure(&self.device, &self.surface_config);
+            self.surface.configure(&self.gpu.device, &self.surface_config);
         }
     }
 }
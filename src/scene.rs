@@ -0,0 +1,31 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tracer::{Camera, Light, Uniforms, VoxelGrid};
+
+/// Represents everything needed to trace a scene again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scene {
+    pub grid: VoxelGrid,
+    // Older scenes without lights load without lights
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    pub camera: Camera,
+    pub uniforms: Uniforms,
+}
+
+impl Scene {
+    /// Load a scene from a ron file
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let source = std::fs::read_to_string(path)?;
+        Ok(ron::from_str(&source)?)
+    }
+
+    /// Save the scene to a ron file
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let source = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, source)?;
+        Ok(())
+    }
+}
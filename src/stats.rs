@@ -0,0 +1,126 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::render::GpuContext;
+
+// The size of the counters, this mirrors `Stats` in the shader
+const COUNTERS_SIZE: u64 = 4 * std::mem::size_of::<u32>() as u64;
+
+/// The counters the shader collected during a trace
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RenderStats {
+    // The amount of pixels that were traced, only the render region is traced
+    pub pixels: u32,
+    // The amount of camera rays, this is the pixels times the samples per pixel
+    pub rays: u32,
+    // The amount of camera rays that hit a surface instead of the background
+    pub hits: u32,
+    // The amount of march steps of all the rays, including the reflections but not the shadows
+    pub steps: u32,
+}
+
+impl RenderStats {
+    /// Get the average amount of march steps per pixel
+    pub fn steps_per_pixel(&self) -> f32 {
+        self.steps as f32 / self.pixels.max(1) as f32
+    }
+
+    /// Get the part of the camera rays that hit a surface, from 0 to 1
+    pub fn hit_rate(&self) -> f32 {
+        self.hits as f32 / self.rays.max(1) as f32
+    }
+}
+
+/// Collects the counters the shader increments with atomics
+/// The result is read back without waiting, so it arrives a few frames after the trace
+pub struct StatsCollector {
+    // The buffer the shader adds to
+    counters: wgpu::Buffer,
+    // The buffer the counters are copied to and read from
+    read_buffer: wgpu::Buffer,
+    // The flag for if the read buffer is waiting to be mapped or read
+    pending: bool,
+    // Set by the map callback once the read buffer can be read
+    mapped: Arc<AtomicBool>,
+    // The last collected counters
+    last: Option<RenderStats>,
+}
+
+impl StatsCollector {
+    /// Construct a new [`StatsCollector`]
+    pub fn new(gpu: &GpuContext) -> Self {
+        let counters = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: COUNTERS_SIZE,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let read_buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: COUNTERS_SIZE,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            counters,
+            read_buffer,
+            pending: false,
+            mapped: Arc::new(AtomicBool::new(false)),
+            last: None,
+        }
+    }
+
+    /// Get the buffer the shader adds the counters to
+    pub fn counters(&self) -> &wgpu::Buffer {
+        &self.counters
+    }
+
+    /// Check if new counters can be collected, the previous ones have to be read first
+    pub fn is_idle(&self) -> bool {
+        !self.pending
+    }
+
+    /// Reset the counters before the trace, the queue writes happen before the next submit
+    pub fn begin(&self, gpu: &GpuContext) {
+        gpu.queue.write_buffer(&self.counters, 0, &[0; COUNTERS_SIZE as usize]);
+    }
+
+    /// Copy the counters to the read buffer after the trace
+    pub fn end(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.copy_buffer_to_buffer(&self.counters, 0, &self.read_buffer, 0, COUNTERS_SIZE);
+    }
+
+    /// Start reading the counters back, call this after submitting the encoder
+    pub fn read_back(&mut self) {
+        self.pending = true;
+        let mapped = self.mapped.clone();
+        self.read_buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| match result {
+                Ok(()) => mapped.store(true, Ordering::Release),
+                Err(e) => log::warn!("Failed to read the render stats: {}", e),
+            });
+    }
+
+    /// Get the last collected counters
+    /// Picks up the counters of the running trace if the gpu is done with it
+    pub fn poll(&mut self) -> Option<RenderStats> {
+        if self.pending && self.mapped.swap(false, Ordering::Acquire) {
+            {
+                let data = self.read_buffer.slice(..).get_mapped_range();
+                let counters: &[u32] = bytemuck::cast_slice(&data);
+                self.last = Some(RenderStats {
+                    steps: counters[0],
+                    hits: counters[1],
+                    rays: counters[2],
+                    pixels: counters[3],
+                });
+            }
+            self.read_buffer.unmap();
+            self.pending = false;
+        }
+        self.last
+    }
+}
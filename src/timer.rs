@@ -0,0 +1,98 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::render::GpuContext;
+
+// The size of a resolved timestamp
+const TIMESTAMP_SIZE: u64 = std::mem::size_of::<u64>() as u64;
+
+/// Measures the time the gpu spends on the commands between two timestamps
+/// The result is read back without waiting, so it arrives a few frames after the trace
+pub struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    // The buffer the timestamps are resolved to and read from
+    read_buffer: wgpu::Buffer,
+    // The amount of nanoseconds per timestamp tick
+    period: f32,
+    // The flag for if the read buffer is waiting to be mapped or read
+    pending: bool,
+    // Set by the map callback once the read buffer can be read
+    mapped: Arc<AtomicBool>,
+    // The last measured time in milliseconds
+    last: Option<f32>,
+}
+
+impl GpuTimer {
+    /// Construct a new [`GpuTimer`]
+    /// Returns [`None`] if the device doesn't support timestamp queries
+    pub fn new(gpu: &GpuContext) -> Option<Self> {
+        if !gpu.device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+        let query_set = gpu.device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: None,
+            ty: wgpu::QueryType::Timestamp,
+            count: 2,
+        });
+        let read_buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: TIMESTAMP_SIZE * 2,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Some(Self {
+            query_set,
+            read_buffer,
+            period: gpu.queue.get_timestamp_period(),
+            pending: false,
+            mapped: Arc::new(AtomicBool::new(false)),
+            last: None,
+        })
+    }
+
+    /// Check if a new measurement can be started, the previous one has to be read first
+    pub fn is_idle(&self) -> bool {
+        !self.pending
+    }
+
+    /// Write the timestamp before the measured commands
+    pub fn begin(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.write_timestamp(&self.query_set, 0);
+    }
+
+    /// Write the timestamp after the measured commands and resolve both to the read buffer
+    pub fn end(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.write_timestamp(&self.query_set, 1);
+        encoder.resolve_query_set(&self.query_set, 0..2, &self.read_buffer, 0);
+    }
+
+    /// Start reading the timestamps back, call this after submitting the encoder
+    pub fn read_back(&mut self) {
+        self.pending = true;
+        let mapped = self.mapped.clone();
+        self.read_buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| match result {
+                Ok(()) => mapped.store(true, Ordering::Release),
+                Err(e) => log::warn!("Failed to read the gpu timestamps: {}", e),
+            });
+    }
+
+    /// Get the last measured time in milliseconds
+    /// Picks up the result of the running measurement if the gpu is done with it
+    pub fn poll(&mut self) -> Option<f32> {
+        if self.pending && self.mapped.swap(false, Ordering::Acquire) {
+            {
+                let data = self.read_buffer.slice(..).get_mapped_range();
+                let timestamps: &[u64] = bytemuck::cast_slice(&data);
+                let ticks = timestamps[1].saturating_sub(timestamps[0]);
+                self.last = Some(ticks as f32 * self.period / 1_000_000.0);
+            }
+            self.read_buffer.unmap();
+            self.pending = false;
+        }
+        self.last
+    }
+}
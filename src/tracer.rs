@@ -1,67 +1,1254 @@
-use crate::render::RenderContext;
-use encase::{ShaderType, UniformBuffer, StorageBuffer};
+use crate::{
+    bvh::{BvhNode, VoxelBvh},
+    denoise::Denoiser,
+    error::{Result, VoxelError},
+    render::GpuContext,
+    stats::{RenderStats, StatsCollector},
+    timer::GpuTimer,
+};
+use encase::{ShaderSize, ShaderType, UniformBuffer, StorageBuffer};
+use serde::{Deserialize, Serialize};
 use pollster::block_on;
-use std::{num::{NonZeroU32, NonZeroU64}, path::Path};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 use wgpu::util::DeviceExt;
 
-const WORKGROUP_SIZE: u32 = 16;
+// The preferred width and height of a workgroup, smaller workgroups are used if the gpu doesn't support it
+const MAX_WORKGROUP_SIZE: u32 = 16;
+// The half size of the default clip box, this is far outside any grid the tracer can handle
+const DEFAULT_CLIP_EXTENT: f32 = 1.0e6;
+// The shader that is bundled with the binary
+const SHADER_SOURCE: &str = include_str!("../shaders/voxel.wgsl");
+/// The size of a single voxel in world space, this has to match `shaders/voxel.wgsl`
+pub const VOXEL_SIZE: f32 = 0.32;
+// The keyword of the png text chunk the camera and the uniforms are stored in
+const METADATA_KEYWORD: &str = "voxeltracer";
+// The maximum width and depth of a heightmap before it gets downsampled
+const MAX_HEIGHTMAP_SIZE: u32 = 64;
+// The largest heightmap image that will be accepted at all
+const MAX_HEIGHTMAP_INPUT_SIZE: u32 = 8192;
+// The maximum height of a heightmap column
+const MAX_HEIGHTMAP_HEIGHT: u32 = 64;
+/// The maximum amount of lights in a scene
+pub const MAX_LIGHTS: usize = 16;
+/// The maximum amount of reflection bounces, the shader clamps [`Uniforms::max_bounces`] to this
+pub const MAX_BOUNCES: u32 = 8;
+/// The amount of storage buffers the tracer binds, bindings 2 and 4 to 12 of the bind group
+pub const STORAGE_BUFFERS: u32 = 10;
+/// Every voxel is checked for every step of the ray, see [`Uniforms::acceleration`]
+pub const ACCELERATION_LINEAR: u32 = 0;
+/// Only the voxels in the nodes of a [`VoxelBvh`] near the ray are checked
+pub const ACCELERATION_BVH: u32 = 1;
+/// The sky is a single flat color, see [`Uniforms::sky_mode`]
+pub const SKY_FLAT: u32 = 0;
+/// The sky is a vertical gradient between two colors
+pub const SKY_GRADIENT: u32 = 1;
+/// The sky is a gradient with a bright horizon and a sun
+pub const SKY_PROCEDURAL: u32 = 2;
+/// The frame shows the lit scene, see [`Uniforms::output_mode`]
+pub const OUTPUT_SHADED: u32 = 0;
+/// The frame shows the surface normals mapped to colors
+pub const OUTPUT_NORMALS: u32 = 1;
+/// The frame shows the distance along the rays, brighter is closer
+pub const OUTPUT_DEPTH: u32 = 2;
+/// The frame shows the amount of march steps, brighter took more steps
+pub const OUTPUT_STEPS: u32 = 3;
+/// The rays spread out from the camera position, see [`Camera::projection`]
+pub const PROJECTION_PERSPECTIVE: u32 = 0;
+/// The rays are parallel, so objects keep their size regardless of the distance
+pub const PROJECTION_ORTHOGRAPHIC: u32 = 1;
+/// The voxel has a single flat color, see [`Voxel::pattern`]
+pub const PATTERN_SOLID: u32 = 0;
+/// The faces of the voxel are covered in a checkerboard
+pub const PATTERN_CHECKER: u32 = 1;
+/// The faces of the voxel are covered in smooth value noise
+pub const PATTERN_NOISE: u32 = 2;
+/// The light shines in every direction from its position, see [`Light::light_type`]
+pub const LIGHT_POINT: u32 = 0;
+/// The light shines with parallel rays like the sun, its position is the direction towards the light
+pub const LIGHT_DIRECTIONAL: u32 = 1;
+/// The colors are only scaled by the exposure and clamped, see [`Uniforms::tonemap_mode`]
+pub const TONEMAP_NONE: u32 = 0;
+/// The colors are compressed with the Reinhard curve
+pub const TONEMAP_REINHARD: u32 = 1;
+/// The colors are compressed with a fit of the ACES filmic curve
+pub const TONEMAP_ACES: u32 = 2;
 
 /// Represents the uniforms for the shader
-#[derive(Debug, ShaderType, Clone, Copy)]
+/// The colors are linear like the colors of the egui color pickers, the shader encodes the frame as srgb
+#[derive(Debug, ShaderType, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Uniforms {
     pub time: f32,
     pub frames: u32,
     pub max_steps: u32,
+    /// The amount of voxels in the grid, this is set by the tracer
     pub voxel_amount: u32,
     pub resolution: glam::UVec2,
+    /// The color of the background, the colors are linear and only converted to srgb when the frame is written
     pub background_color: glam::Vec4,
     pub floor_color: glam::Vec4,
     pub object_color: glam::Vec3,
-    pub light_position: glam::Vec3,
-    pub sun_intensity: f32,
+    pub light_count: u32,
     pub smoothing: f32,
+    /// The amount of neighbouring cells checked for ambient occlusion (0 to 8)
+    /// 4 only darkens along the edges, 8 also darkens the corners
     pub ambient_occlusion: i32,
+    pub floor_enabled: i32,
+    pub floor_height: f32,
+    pub alpha_background: i32,
+    /// How much an occupied neighbour darkens the surface (0 to 1), around 0.5 looks natural
+    pub ao_strength: f32,
+    /// The corner of the rectangle of the frame that gets traced, the rest keeps the previous frame
+    pub region_offset: glam::UVec2,
+    /// The size of the rectangle of the frame that gets traced
+    pub region_size: glam::UVec2,
+    /// The flag for if the voxel colors are animated over time
+    pub animate: i32,
+    /// The distance along a ray where the march starts
+    pub near: f32,
+    /// The maximum distance of the march, the background is shown past it
+    pub far: f32,
+    /// How the background is shaded ([`SKY_FLAT`], [`SKY_GRADIENT`] or [`SKY_PROCEDURAL`])
+    /// The flat sky uses the background color, the others blend between the top and bottom colors
+    pub sky_mode: u32,
+    pub sky_top_color: glam::Vec4,
+    pub sky_bottom_color: glam::Vec4,
+    /// The amount of copies of the grid along each axis, 1 disables the tiling on that axis
+    pub tile_count: glam::UVec3,
+    /// The distance between the copies in world space, this should be larger than the grid
+    pub tile_spacing: f32,
+    /// How the closest voxel is found ([`ACCELERATION_LINEAR`] or [`ACCELERATION_BVH`])
+    pub acceleration: u32,
+    /// The flag for if the samples of consecutive traces are averaged, the rays are jittered to smooth the edges
+    pub accumulate: i32,
+    /// The amount of samples that were accumulated before this trace, this is set by the tracer
+    pub sample_count: u32,
+    /// What the frame shows ([`OUTPUT_SHADED`], [`OUTPUT_NORMALS`], [`OUTPUT_DEPTH`] or [`OUTPUT_STEPS`])
+    pub output_mode: u32,
+    /// How soft the edges of the shadows are, 0 disables the shadows
+    pub shadow_softness: f32,
+    /// The amount of rays traced through each pixel and averaged, 1 traces a single ray through the centre
+    pub samples_per_pixel: u32,
+    /// The seed of the random numbers in the shader, the same seed and scene always give the same frame
+    pub seed: u32,
+    /// The factor the shaded colors are multiplied by before tonemapping
+    pub exposure: f32,
+    /// How the shaded colors are mapped to the frame ([`TONEMAP_NONE`], [`TONEMAP_REINHARD`] or [`TONEMAP_ACES`])
+    pub tonemap_mode: u32,
+    /// The amount of times a ray is reflected by metallic voxels, up to [`MAX_BOUNCES`]
+    pub max_bounces: u32,
+    /// How quickly the fog thickens with the distance from the camera, 0 disables the fog
+    pub fog_density: f32,
+    pub fog_color: glam::Vec4,
+    /// The flag for if the shaded frame is filtered by the [`Denoiser`] after tracing
+    pub denoise: i32,
+    /// The amount of colors in the palette of the grid, this is set by the tracer
+    pub palette_size: u32,
+    /// The minimum corner of the box in world space the voxels are drawn in, the voxels with their centre outside of it are skipped
+    /// The box is applied to the grid before tiling, so every copy is clipped the same way
+    pub clip_min: glam::Vec3,
+    /// The maximum corner of the box the voxels are drawn in
+    pub clip_max: glam::Vec3,
+    /// The amount of instances, this is set by the tracer, see [`Tracer::set_instances`]
+    pub instance_count: u32,
+    /// How close the march has to get to a surface to count as a hit
+    /// A large epsilon rounds the edges and merges close surfaces, a small one spends more of `max_steps` near surfaces
+    pub hit_epsilon: f32,
+    /// Count the march steps and the hits of the traces, see [`Tracer::stats`]
+    /// The counters are atomics that all invocations add to, so this costs a bit of time
+    pub collect_stats: u32,
+    /// The position of the frame in the whole image, see [`Tracer::render_tiled`]
+    pub tile_offset: glam::UVec2,
+    /// The size of the whole image, 0 uses the resolution so the frame is the whole image
+    pub full_resolution: glam::UVec2,
+}
+
+impl Default for Uniforms {
+    fn default() -> Self {
+        Self {
+            time: 0.0,
+            resolution: glam::uvec2(1080, 1080),
+            frames: 0,
+            max_steps: 50,
+            voxel_amount: 0,
+            light_count: 0,
+            background_color: glam::vec4(0.0, 0.0, 0.0, 1.0),
+            floor_color: glam::vec4(0.1, 0.1, 0.1, 1.0),
+            object_color: glam::vec3(1.0, 1.0, 1.0),
+            smoothing: 0.0,
+            ambient_occlusion: 8,
+            floor_enabled: 1,
+            floor_height: -0.16,
+            alpha_background: 0,
+            ao_strength: 0.5,
+            region_offset: glam::uvec2(0, 0),
+            region_size: glam::uvec2(1080, 1080),
+            animate: 0,
+            near: 0.0,
+            far: 1000.0,
+            sky_mode: SKY_FLAT,
+            sky_top_color: glam::vec4(0.3, 0.5, 0.9, 1.0),
+            sky_bottom_color: glam::vec4(0.9, 0.9, 1.0, 1.0),
+            tile_count: glam::uvec3(1, 1, 1),
+            tile_spacing: 2.0,
+            acceleration: ACCELERATION_LINEAR,
+            accumulate: 0,
+            sample_count: 0,
+            output_mode: OUTPUT_SHADED,
+            shadow_softness: 0.0,
+            samples_per_pixel: 1,
+            seed: 0,
+            exposure: 1.0,
+            tonemap_mode: TONEMAP_NONE,
+            max_bounces: 0,
+            fog_density: 0.0,
+            fog_color: glam::vec4(0.5, 0.6, 0.7, 1.0),
+            denoise: 0,
+            palette_size: 0,
+            clip_min: glam::Vec3::splat(-DEFAULT_CLIP_EXTENT),
+            clip_max: glam::Vec3::splat(DEFAULT_CLIP_EXTENT),
+            instance_count: 0,
+            hit_epsilon: 0.001,
+            collect_stats: 0,
+            tile_offset: glam::UVec2::ZERO,
+            full_resolution: glam::UVec2::ZERO,
+        }
+    }
+}
+
+impl Uniforms {
+    /// Start building [`Uniforms`] from the defaults
+    pub fn builder() -> UniformsBuilder {
+        UniformsBuilder::default()
+    }
+}
+
+/// Builds [`Uniforms`] with chainable setters, [`UniformsBuilder::build`] clamps the values to ranges that render
+/// The fields that are set by the tracer, like the voxel amount, don't have a setter
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UniformsBuilder(Uniforms);
+
+impl From<Uniforms> for UniformsBuilder {
+    fn from(uniforms: Uniforms) -> Self {
+        Self(uniforms)
+    }
+}
+
+impl UniformsBuilder {
+    /// Set the resolution of the frame, the whole frame is traced
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+        self.0.resolution = glam::uvec2(width, height);
+        self.0.region_offset = glam::UVec2::ZERO;
+        self.0.region_size = self.0.resolution;
+        self
+    }
+
+    /// Set the maximum amount of march steps per ray
+    pub fn max_steps(mut self, max_steps: u32) -> Self {
+        self.0.max_steps = max_steps;
+        self
+    }
+
+    /// Set the distances along the rays where the march starts and gives up
+    pub fn near_far(mut self, near: f32, far: f32) -> Self {
+        self.0.near = near;
+        self.0.far = far;
+        self
+    }
+
+    /// Set the color of the flat sky
+    pub fn background_color(mut self, color: glam::Vec4) -> Self {
+        self.0.background_color = color;
+        self
+    }
+
+    /// Set the floor, [`None`] disables it
+    pub fn floor(mut self, floor: Option<(f32, glam::Vec4)>) -> Self {
+        self.0.floor_enabled = floor.is_some() as i32;
+        if let Some((height, color)) = floor {
+            self.0.floor_height = height;
+            self.0.floor_color = color;
+        }
+        self
+    }
+
+    /// Set the amount of rays traced through each pixel
+    pub fn samples_per_pixel(mut self, samples: u32) -> Self {
+        self.0.samples_per_pixel = samples;
+        self
+    }
+
+    /// Set if the samples of consecutive traces are averaged
+    pub fn accumulate(mut self, accumulate: bool) -> Self {
+        self.0.accumulate = accumulate as i32;
+        self
+    }
+
+    /// Set if the shaded frame is denoised
+    pub fn denoise(mut self, denoise: bool) -> Self {
+        self.0.denoise = denoise as i32;
+        self
+    }
+
+    /// Set the seed of the random numbers in the shader
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.0.seed = seed;
+        self
+    }
+
+    /// Set the exposure and how the colors are mapped to the frame
+    pub fn tonemap(mut self, exposure: f32, tonemap_mode: u32) -> Self {
+        self.0.exposure = exposure;
+        self.0.tonemap_mode = tonemap_mode;
+        self
+    }
+
+    /// Set how soft the shadows are, 0 disables them
+    pub fn shadow_softness(mut self, softness: f32) -> Self {
+        self.0.shadow_softness = softness;
+        self
+    }
+
+    /// Set the amount of reflections of the metallic voxels
+    pub fn max_bounces(mut self, bounces: u32) -> Self {
+        self.0.max_bounces = bounces;
+        self
+    }
+
+    /// Set the fog, a density of 0 disables it
+    pub fn fog(mut self, density: f32, color: glam::Vec4) -> Self {
+        self.0.fog_density = density;
+        self.0.fog_color = color;
+        self
+    }
+
+    /// Set how the closest voxel is found
+    pub fn acceleration(mut self, acceleration: u32) -> Self {
+        self.0.acceleration = acceleration;
+        self
+    }
+
+    /// Set what the frame shows
+    pub fn output_mode(mut self, output_mode: u32) -> Self {
+        self.0.output_mode = output_mode;
+        self
+    }
+
+    /// Set how close the march has to get to a surface to count as a hit
+    pub fn hit_epsilon(mut self, epsilon: f32) -> Self {
+        self.0.hit_epsilon = epsilon;
+        self
+    }
+
+    /// Get the uniforms, the values that would render a black or broken frame are clamped
+    pub fn build(self) -> Uniforms {
+        let uniforms = self.0;
+        let resolution = uniforms.resolution.max(glam::UVec2::ONE);
+        let near = uniforms.near.max(0.0);
+        Uniforms {
+            // A ray without steps never hits anything
+            max_steps: uniforms.max_steps.max(1),
+            resolution,
+            region_size: uniforms.region_size.min(resolution),
+            near,
+            far: uniforms.far.max(near),
+            samples_per_pixel: uniforms.samples_per_pixel.max(1),
+            exposure: uniforms.exposure.max(0.0),
+            shadow_softness: uniforms.shadow_softness.max(0.0),
+            max_bounces: uniforms.max_bounces.min(MAX_BOUNCES),
+            fog_density: uniforms.fog_density.max(0.0),
+            ao_strength: uniforms.ao_strength.clamp(0.0, 1.0),
+            ambient_occlusion: uniforms.ambient_occlusion.clamp(0, 8),
+            hit_epsilon: uniforms.hit_epsilon.max(f32::EPSILON),
+            ..uniforms
+        }
+    }
 }
 
 /// Represents the camera
-#[derive(Debug, ShaderType, Clone, Copy)]
+#[derive(Debug, ShaderType, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Camera {
     pub position: glam::Vec3,
     pub look_at: glam::Vec3,
     pub zoom: f32,
+    /// The direction that is up on the screen, another direction is used when looking along it
+    pub up: glam::Vec3,
+    /// The rotation around the view direction in radians
+    pub roll: f32,
+    /// How the rays are cast ([`PROJECTION_PERSPECTIVE`] or [`PROJECTION_ORTHOGRAPHIC`])
+    pub projection: u32,
+    /// The height of the view in world units for the orthographic projection
+    pub ortho_scale: f32,
+    /// The radius of the lens, the rays start across it to blur what isn't at the focal distance
+    /// An aperture of 0 is a pinhole camera where everything is sharp
+    pub aperture: f32,
+    /// The distance from the camera along the view direction where the image is sharp
+    pub focal_distance: f32,
+}
+
+impl Camera {
+    /// Get the direction of the ray through a point on the screen
+    /// The uv ranges from -0.5 to 0.5 on the y axis pointing up, the x axis is scaled by the aspect ratio of the frame
+    /// This mirrors `create_camera_ray` in the shader
+    pub fn ray_direction(&self, uv: glam::Vec2) -> glam::Vec3 {
+        self.ray_vector(uv).normalize()
+    }
+
+    /// Get the start of the ray through a point on the screen
+    /// This is the camera position, except for the orthographic projection where every ray starts somewhere else
+    pub fn ray_origin(&self, uv: glam::Vec2) -> glam::Vec3 {
+        if self.projection != PROJECTION_ORTHOGRAPHIC {
+            return self.position;
+        }
+        let (_, r, u) = self.basis();
+        self.position + (uv.x * r + uv.y * u) * self.ortho_scale
+    }
+
+    /// Get the unnormalized direction of the ray through a point on the screen
+    /// The shader marches along this vector, so its length scales the march distances
+    pub(crate) fn ray_vector(&self, uv: glam::Vec2) -> glam::Vec3 {
+        let (f, r, u) = self.basis();
+        // The orthographic rays are parallel
+        if self.projection == PROJECTION_ORTHOGRAPHIC {
+            return f;
+        }
+
+        let c = self.position + f * (self.zoom - 0.1);
+        let i = c + uv.x * r + uv.y * u;
+        i - self.position
+    }
+
+    /// Project a point in world space to the uv of the screen
+    /// This is the inverse of [`Camera::ray_direction`], returns [`None`] if the point is behind the camera
+    pub fn project(&self, point: glam::Vec3) -> Option<glam::Vec2> {
+        let (f, r, u) = self.basis();
+
+        // Check if the point is in front of the camera
+        let p = point - self.position;
+        let depth = p.dot(f);
+        if depth <= 0.0 {
+            return None;
+        }
+        // The orthographic projection doesn't shrink with the distance
+        if self.projection == PROJECTION_ORTHOGRAPHIC {
+            return Some(glam::vec2(p.dot(r), p.dot(u)) / self.ortho_scale);
+        }
+        // Scale the point onto the image plane
+        let t = depth / (self.zoom - 0.1);
+        Some(glam::vec2(p.dot(r) / t, p.dot(u) / t))
+    }
+
+    /// Get the forward, right and up directions of the camera
+    /// This mirrors `camera_basis` in the shader
+    pub(crate) fn basis(&self) -> (glam::Vec3, glam::Vec3, glam::Vec3) {
+        let f = (self.look_at - self.position).normalize();
+        // Fall back to another up direction when looking along the up direction
+        let mut up = self.up;
+        if up.cross(f).length() < 0.001 {
+            up = if f.z.abs() > 0.9 {
+                glam::Vec3::X
+            } else {
+                glam::Vec3::Z
+            };
+        }
+        let r = up.cross(f).normalize();
+        let u = f.cross(r);
+        // Rotate the right and up directions around the view direction
+        let (sin, cos) = self.roll.sin_cos();
+        (f, r * cos + u * sin, u * cos - r * sin)
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: glam::vec3(0.0, 0.0, 2.0),
+            look_at: glam::vec3(0.0, 0.0, 0.0),
+            zoom: 1.0,
+            up: glam::Vec3::Y,
+            roll: 0.0,
+            projection: PROJECTION_PERSPECTIVE,
+            ortho_scale: 2.0,
+            aperture: 0.0,
+            focal_distance: 2.0,
+        }
+    }
 }
 
 /// Represents a voxel
-#[derive(Debug, Clone, ShaderType)]
+/// The material fields are placed in the padding after the vec3s to keep the layout tight
+#[derive(Debug, Clone, Copy, PartialEq, ShaderType, Serialize, Deserialize)]
 pub struct Voxel {
     position: glam::IVec3,
-    color: glam::Vec3,
+    pub emission: f32,
+    pub color: glam::Vec3,
+    pub roughness: f32,
+    /// How much the brightness pulses when animated, 0 disables the animation
+    pub amplitude: f32,
+    /// How many times per second the brightness pulses
+    pub frequency: f32,
+    /// How metallic the surface is (0 to 1), metals only reflect the lights in their own color
+    #[serde(default)]
+    pub metallic: f32,
+    /// The color of the grid palette the voxel uses starting at 1, 0 uses the color of the voxel instead
+    #[serde(default)]
+    pub palette_index: u32,
+    /// The pattern that darkens parts of the faces ([`PATTERN_SOLID`], [`PATTERN_CHECKER`] or [`PATTERN_NOISE`])
+    /// The pattern is evaluated in world space, so neighbouring voxels continue each other's pattern
+    #[serde(default)]
+    pub pattern: u32,
+    /// The size of a checker cell or a noise cell in world units, a voxel is `VOXEL_SIZE` wide
+    #[serde(default = "default_pattern_scale")]
+    pub pattern_scale: f32,
+}
+
+/// Get the pattern scale of the voxels that were saved without one, this fits 2x2 cells on a face
+fn default_pattern_scale() -> f32 {
+    VOXEL_SIZE / 2.0
 }
 
 impl Voxel {
     /// Construct a new [`Voxel`]
+    /// The voxel is non-emissive and fully rough
     pub fn new(
         position: glam::IVec3,
         color: glam::Vec3,
     ) -> Self {
-        Self  {
+        Self::with_material(position, color, 0.0, 1.0, 0.0)
+    }
+
+    /// Construct a new [`Voxel`] with material properties
+    pub fn with_material(
+        position: glam::IVec3,
+        color: glam::Vec3,
+        emission: f32,
+        roughness: f32,
+        metallic: f32,
+    ) -> Self {
+        Self {
             position,
-            color
+            emission,
+            color,
+            roughness,
+            amplitude: 0.0,
+            frequency: 0.0,
+            metallic,
+            palette_index: 0,
+            pattern: PATTERN_SOLID,
+            pattern_scale: default_pattern_scale(),
+        }
+    }
+
+    /// Construct a new [`Voxel`] with a brightness that pulses over time
+    pub fn animated(
+        position: glam::IVec3,
+        color: glam::Vec3,
+        amplitude: f32,
+        frequency: f32,
+    ) -> Self {
+        Self {
+            amplitude,
+            frequency,
+            ..Self::new(position, color)
+        }
+    }
+
+    /// Get the position of the voxel in the grid
+    pub fn position(&self) -> glam::IVec3 {
+        self.position
+    }
+
+    /// Get the distance from a point in world space to the surface of the voxel, negative inside of it
+    /// This is the box distance of `draw_rectangle` in the shader
+    pub fn distance(&self, point: glam::Vec3) -> f32 {
+        let d = (point - self.position.as_vec3() * VOXEL_SIZE).abs() - glam::Vec3::splat(VOXEL_SIZE / 2.0);
+        d.max_element().min(0.0) + d.max(glam::Vec3::ZERO).length()
+    }
+}
+
+/// Represents a point light or a directional light
+#[derive(Debug, ShaderType, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Light {
+    /// The position of a point light, or the direction towards a directional light
+    pub position: glam::Vec3,
+    pub color: glam::Vec3,
+    pub intensity: f32,
+    /// How the light shines ([`LIGHT_POINT`] or [`LIGHT_DIRECTIONAL`]), older scenes load as point lights
+    #[serde(default)]
+    pub light_type: u32,
+}
+
+impl Light {
+    /// Construct a new point [`Light`]
+    pub fn new(position: glam::Vec3, color: glam::Vec3, intensity: f32) -> Self {
+        Self {
+            position,
+            color,
+            intensity,
+            light_type: LIGHT_POINT,
         }
     }
+
+    /// Construct a new directional [`Light`] shining from a direction
+    pub fn directional(direction: glam::Vec3, color: glam::Vec3, intensity: f32) -> Self {
+        Self {
+            light_type: LIGHT_DIRECTIONAL,
+            ..Self::new(direction, color, intensity)
+        }
+    }
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self::new(glam::vec3(0.0, 0.25, 0.0), glam::vec3(1.0, 1.0, 1.0), 1.0)
+    }
+}
+
+/// Represents a copy of one of the grids given to [`Tracer::set_instances`]
+/// The voxels of a grid are only uploaded once, no matter how many instances use it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Instance {
+    /// Places the grid in the world, only rotations, translations and uniform scales are supported
+    pub transform: glam::Mat4,
+    /// The index of the grid in the grids given to [`Tracer::set_instances`]
+    pub grid_id: u32,
 }
 
+impl Instance {
+    /// Construct a new [`Instance`]
+    pub fn new(transform: glam::Mat4, grid_id: u32) -> Self {
+        Self { transform, grid_id }
+    }
+}
+
+/// An instance as the shader uses it, this mirrors `Instance` in the shader
+#[derive(Debug, ShaderType, Clone, Copy, PartialEq)]
+struct GpuInstance {
+    // Transforms a world position into the space of the grid
+    inverse: glam::Mat4,
+    // The bounds of the grid and the range of its voxels in the instance voxel buffer
+    min: glam::Vec3,
+    start: u32,
+    max: glam::Vec3,
+    count: u32,
+    // The smallest scale of the transform, the distances in the grid are multiplied by it
+    scale: f32,
+}
+
+// Compile time checks for the gpu layouts of the shader types.
+// These have to match the structs in `shaders/voxel.wgsl`, so reordering a field breaks the build
+// instead of silently garbling the render.
+const _: () = {
+    // The uniforms
+    assert!(<Uniforms as ShaderSize>::SHADER_SIZE.get() == 336);
+    assert!(<Uniforms as ShaderType>::METADATA.offset(4) == 16); // resolution
+    assert!(<Uniforms as ShaderType>::METADATA.offset(5) == 32); // background_color
+    assert!(<Uniforms as ShaderType>::METADATA.offset(7) == 64); // object_color
+    assert!(<Uniforms as ShaderType>::METADATA.offset(8) == 76); // light_count
+    assert!(<Uniforms as ShaderType>::METADATA.offset(10) == 84); // ambient_occlusion
+    assert!(<Uniforms as ShaderType>::METADATA.offset(12) == 92); // floor_height
+    assert!(<Uniforms as ShaderType>::METADATA.offset(13) == 96); // alpha_background
+    assert!(<Uniforms as ShaderType>::METADATA.offset(14) == 100); // ao_strength
+    assert!(<Uniforms as ShaderType>::METADATA.offset(15) == 104); // region_offset
+    assert!(<Uniforms as ShaderType>::METADATA.offset(16) == 112); // region_size
+    assert!(<Uniforms as ShaderType>::METADATA.offset(17) == 120); // animate
+    assert!(<Uniforms as ShaderType>::METADATA.offset(18) == 124); // near
+    assert!(<Uniforms as ShaderType>::METADATA.offset(19) == 128); // far
+    assert!(<Uniforms as ShaderType>::METADATA.offset(20) == 132); // sky_mode
+    assert!(<Uniforms as ShaderType>::METADATA.offset(21) == 144); // sky_top_color
+    assert!(<Uniforms as ShaderType>::METADATA.offset(22) == 160); // sky_bottom_color
+    assert!(<Uniforms as ShaderType>::METADATA.offset(23) == 176); // tile_count
+    assert!(<Uniforms as ShaderType>::METADATA.offset(24) == 188); // tile_spacing
+    assert!(<Uniforms as ShaderType>::METADATA.offset(25) == 192); // acceleration
+    assert!(<Uniforms as ShaderType>::METADATA.offset(26) == 196); // accumulate
+    assert!(<Uniforms as ShaderType>::METADATA.offset(27) == 200); // sample_count
+    assert!(<Uniforms as ShaderType>::METADATA.offset(28) == 204); // output_mode
+    assert!(<Uniforms as ShaderType>::METADATA.offset(29) == 208); // shadow_softness
+    assert!(<Uniforms as ShaderType>::METADATA.offset(30) == 212); // samples_per_pixel
+    assert!(<Uniforms as ShaderType>::METADATA.offset(31) == 216); // seed
+    assert!(<Uniforms as ShaderType>::METADATA.offset(32) == 220); // exposure
+    assert!(<Uniforms as ShaderType>::METADATA.offset(33) == 224); // tonemap_mode
+    assert!(<Uniforms as ShaderType>::METADATA.offset(34) == 228); // max_bounces
+    assert!(<Uniforms as ShaderType>::METADATA.offset(35) == 232); // fog_density
+    assert!(<Uniforms as ShaderType>::METADATA.offset(36) == 240); // fog_color
+    assert!(<Uniforms as ShaderType>::METADATA.offset(37) == 256); // denoise
+    assert!(<Uniforms as ShaderType>::METADATA.offset(38) == 260); // palette_size
+    assert!(<Uniforms as ShaderType>::METADATA.offset(39) == 272); // clip_min
+    assert!(<Uniforms as ShaderType>::METADATA.offset(40) == 288); // clip_max
+    assert!(<Uniforms as ShaderType>::METADATA.offset(41) == 300); // instance_count
+    assert!(<Uniforms as ShaderType>::METADATA.offset(42) == 304); // hit_epsilon
+    assert!(<Uniforms as ShaderType>::METADATA.offset(43) == 308); // collect_stats
+    assert!(<Uniforms as ShaderType>::METADATA.offset(44) == 312); // tile_offset
+    assert!(<Uniforms as ShaderType>::METADATA.offset(45) == 320); // full_resolution
+    // The camera
+    assert!(<Camera as ShaderSize>::SHADER_SIZE.get() == 64);
+    assert!(<Camera as ShaderType>::METADATA.offset(1) == 16); // look_at
+    assert!(<Camera as ShaderType>::METADATA.offset(2) == 28); // zoom
+    assert!(<Camera as ShaderType>::METADATA.offset(3) == 32); // up
+    assert!(<Camera as ShaderType>::METADATA.offset(4) == 44); // roll
+    assert!(<Camera as ShaderType>::METADATA.offset(5) == 48); // projection
+    assert!(<Camera as ShaderType>::METADATA.offset(6) == 52); // ortho_scale
+    assert!(<Camera as ShaderType>::METADATA.offset(7) == 56); // aperture
+    assert!(<Camera as ShaderType>::METADATA.offset(8) == 60); // focal_distance
+    // The voxel
+    assert!(<Voxel as ShaderSize>::SHADER_SIZE.get() == 64);
+    assert!(<Voxel as ShaderType>::METADATA.offset(1) == 12); // emission
+    assert!(<Voxel as ShaderType>::METADATA.offset(2) == 16); // color
+    assert!(<Voxel as ShaderType>::METADATA.offset(3) == 28); // roughness
+    assert!(<Voxel as ShaderType>::METADATA.offset(4) == 32); // amplitude
+    assert!(<Voxel as ShaderType>::METADATA.offset(5) == 36); // frequency
+    assert!(<Voxel as ShaderType>::METADATA.offset(6) == 40); // metallic
+    assert!(<Voxel as ShaderType>::METADATA.offset(7) == 44); // palette_index
+    assert!(<Voxel as ShaderType>::METADATA.offset(8) == 48); // pattern
+    assert!(<Voxel as ShaderType>::METADATA.offset(9) == 52); // pattern_scale
+    // The instance
+    assert!(<GpuInstance as ShaderSize>::SHADER_SIZE.get() == 112);
+    assert!(<GpuInstance as ShaderType>::METADATA.offset(1) == 64); // min
+    assert!(<GpuInstance as ShaderType>::METADATA.offset(2) == 76); // start
+    assert!(<GpuInstance as ShaderType>::METADATA.offset(3) == 80); // max
+    assert!(<GpuInstance as ShaderType>::METADATA.offset(4) == 92); // count
+    assert!(<GpuInstance as ShaderType>::METADATA.offset(5) == 96); // scale
+    // The bvh node
+    assert!(<BvhNode as ShaderSize>::SHADER_SIZE.get() == 32);
+    assert!(<BvhNode as ShaderType>::METADATA.offset(1) == 12); // start
+    assert!(<BvhNode as ShaderType>::METADATA.offset(2) == 16); // max
+    assert!(<BvhNode as ShaderType>::METADATA.offset(3) == 28); // count
+    // The light
+    assert!(<Light as ShaderSize>::SHADER_SIZE.get() == 48);
+    assert!(<Light as ShaderType>::METADATA.offset(1) == 16); // color
+    assert!(<Light as ShaderType>::METADATA.offset(2) == 28); // intensity
+    assert!(<Light as ShaderType>::METADATA.offset(3) == 32); // light_type
+};
+
 /// Represents the voxel grid
+/// Every cell holds at most one voxel, the voxels are stored contiguously for the upload to the gpu
+/// The voxels with a palette index take their color from the palette, so a model can be recolored at once
+/// The voxels are private so the index of the cells stays valid, use [`VoxelGrid::from_voxels`] and [`VoxelGrid::voxels`]
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "VoxelGridData", into = "VoxelGridData")]
+pub struct VoxelGrid(
+    Vec<Voxel>,
+    pub(crate) Vec<glam::Vec3>,
+    // The index of the voxel in every occupied cell, so a cell is found without checking every voxel
+    HashMap<glam::IVec3, usize>,
+);
+
+/// The saved form of a [`VoxelGrid`], the index of the cells is rebuilt when it's loaded
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "VoxelGrid")]
+struct VoxelGridData(
+    Vec<Voxel>,
+    // Older scenes without a palette load with an empty palette
+    #[serde(default)] Vec<glam::Vec3>,
+);
+
+impl From<VoxelGridData> for VoxelGrid {
+    fn from(data: VoxelGridData) -> Self {
+        let mut grid = Self::from_voxels(data.0);
+        grid.1 = data.1;
+        grid
+    }
+}
+
+impl From<VoxelGrid> for VoxelGridData {
+    fn from(grid: VoxelGrid) -> Self {
+        Self(grid.0, grid.1)
+    }
+}
+
+// The index follows from the voxels, so it's left out of the comparison and the debug output
+impl PartialEq for VoxelGrid {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0 && self.1 == other.1
+    }
+}
+
+impl std::fmt::Debug for VoxelGrid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("VoxelGrid").field(&self.0).field(&self.1).finish()
+    }
+}
+
+impl VoxelGrid {
+    /// Construct a [`VoxelGrid`] from a list of voxels
+    /// A voxel replaces the earlier voxels in the same cell
+    pub fn from_voxels(voxels: impl IntoIterator<Item = Voxel>) -> Self {
+        let mut grid = Self::default();
+        for voxel in voxels {
+            match grid.2.entry(voxel.position()) {
+                // Replace the duplicates in place
+                Entry::Occupied(entry) => grid.0[*entry.get()] = voxel,
+                Entry::Vacant(entry) => {
+                    entry.insert(grid.0.len());
+                    grid.0.push(voxel);
+                }
+            }
+        }
+        grid
+    }
+
+    /// Get the voxels as a contiguous slice, in the order they are uploaded to the gpu
+    pub fn voxels(&self) -> &[Voxel] {
+        &self.0
+    }
+
+    /// Get the colors the palette indices of the voxels refer to
+    pub fn palette(&self) -> &[glam::Vec3] {
+        &self.1
+    }
+
+    /// Replace the colors of the palette, palette index 1 uses the first color
+    pub fn set_palette(&mut self, palette: Vec<glam::Vec3>) {
+        self.1 = palette;
+    }
+
+    /// Get the color a voxel is drawn with, this mirrors `base_color` in the shader
+    /// Falls back to the color of the voxel if the palette index isn't in the palette
+    pub fn color(&self, voxel: &Voxel) -> glam::Vec3 {
+        (voxel.palette_index as usize)
+            .checked_sub(1)
+            .and_then(|index| self.1.get(index))
+            .copied()
+            .unwrap_or(voxel.color)
+    }
+
+    /// Get the amount of voxels in the grid
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check if the grid doesn't have any voxels
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get the voxel in a cell
+    pub fn get(&self, position: glam::IVec3) -> Option<&Voxel> {
+        self.2.get(&position).map(|&index| &self.0[index])
+    }
+
+    /// Get the index of the voxel in a cell, this is its index in [`VoxelGrid::voxels`]
+    pub fn index_of(&self, position: glam::IVec3) -> Option<usize> {
+        self.2.get(&position).copied()
+    }
+
+    /// Place a voxel in a cell, this replaces the voxel that was already there
+    pub fn set(&mut self, position: glam::IVec3, color: glam::Vec3) {
+        let voxel = Voxel::new(position, color);
+        match self.2.get(&position) {
+            Some(&index) => self.0[index] = voxel,
+            None => {
+                self.2.insert(position, self.0.len());
+                self.0.push(voxel);
+            }
+        }
+    }
+
+    /// Remove the voxel in a cell
+    /// Returns false if the cell was empty
+    /// The later voxels move down by one, so this takes longer than [`VoxelGrid::get`] and [`VoxelGrid::set`]
+    pub fn remove(&mut self, position: glam::IVec3) -> bool {
+        match self.2.get(&position) {
+            Some(&index) => {
+                self.remove_at(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Insert a voxel at an index, the later voxels move up by one
+    /// The editor history refers to the voxels by index, so undoing a removal puts the voxel back in its place
+    /// Returns false if the cell is already occupied
+    pub fn insert_at(&mut self, index: usize, voxel: Voxel) -> bool {
+        if self.2.contains_key(&voxel.position()) {
+            return false;
+        }
+        let index = index.min(self.0.len());
+        for later in self.2.values_mut().filter(|later| **later >= index) {
+            *later += 1;
+        }
+        self.2.insert(voxel.position(), index);
+        self.0.insert(index, voxel);
+        true
+    }
+
+    /// Remove the voxel at an index, the later voxels move down by one
+    /// Returns [`None`] if the index is past the last voxel
+    pub fn remove_at(&mut self, index: usize) -> Option<Voxel> {
+        if index >= self.0.len() {
+            return None;
+        }
+        let voxel = self.0.remove(index);
+        self.2.remove(&voxel.position());
+        for later in self.2.values_mut().filter(|later| **later > index) {
+            *later -= 1;
+        }
+        Some(voxel)
+    }
+
+    /// Replace the voxel at an index
+    /// Returns false if the index is past the last voxel, or if the new voxel moves into a cell that is already occupied
+    pub fn replace_at(&mut self, index: usize, voxel: Voxel) -> bool {
+        let previous = match self.0.get(index) {
+            Some(previous) => previous.position(),
+            None => return false,
+        };
+        if voxel.position() != previous {
+            if self.2.contains_key(&voxel.position()) {
+                return false;
+            }
+            self.2.remove(&previous);
+            self.2.insert(voxel.position(), index);
+        }
+        self.0[index] = voxel;
+        true
+    }
+
+    /// Get the voxel at an index to change its material
+    /// The voxel has to stay in its cell, use [`VoxelGrid::replace_at`] to move it
+    pub fn voxel_mut(&mut self, index: usize) -> Option<&mut Voxel> {
+        self.0.get_mut(index)
+    }
+
+    /// Get the minimum and maximum voxel coordinates of the grid
+    /// Returns zero for both if the grid is empty
+    pub fn bounds(&self) -> (glam::IVec3, glam::IVec3) {
+        if self.0.is_empty() {
+            return (glam::IVec3::ZERO, glam::IVec3::ZERO);
+        }
+        self.0.iter().fold(
+            (glam::IVec3::splat(i32::MAX), glam::IVec3::splat(i32::MIN)),
+            |(min, max), voxel| (min.min(voxel.position), max.max(voxel.position)),
+        )
+    }
+
+    /// Get the minimum and maximum corners of the grid in world space
+    pub fn world_bounds(&self) -> (glam::Vec3, glam::Vec3) {
+        let (min, max) = self.bounds();
+        (
+            min.as_vec3() * VOXEL_SIZE - VOXEL_SIZE / 2.0,
+            max.as_vec3() * VOXEL_SIZE + VOXEL_SIZE / 2.0,
+        )
+    }
+
+    /// Construct a [`VoxelGrid`] from a grayscale heightmap image
+    /// The luminance of each pixel decides the height of the voxel column at that position
+    pub fn from_heightmap(
+        path: impl AsRef<Path>,
+        max_height: u32,
+        colorize: bool,
+    ) -> anyhow::Result<Self> {
+        // Check if the height is in range
+        if max_height == 0 || max_height > MAX_HEIGHTMAP_HEIGHT {
+            return Err(anyhow::anyhow!(
+                "The heightmap height must be between 1 and {}, got {}",
+                MAX_HEIGHTMAP_HEIGHT,
+                max_height
+            ));
+        }
+
+        // Load the image
+        let mut image = image::open(path)?;
+        // Guard against absurdly large images
+        if image.width() > MAX_HEIGHTMAP_INPUT_SIZE || image.height() > MAX_HEIGHTMAP_INPUT_SIZE {
+            return Err(anyhow::anyhow!(
+                "The heightmap is {}x{}, the maximum supported size is {}x{}",
+                image.width(),
+                image.height(),
+                MAX_HEIGHTMAP_INPUT_SIZE,
+                MAX_HEIGHTMAP_INPUT_SIZE
+            ));
+        }
+        // Downsample the image so we don't generate millions of voxels
+        if image.width() > MAX_HEIGHTMAP_SIZE || image.height() > MAX_HEIGHTMAP_SIZE {
+            image = image.thumbnail(MAX_HEIGHTMAP_SIZE, MAX_HEIGHTMAP_SIZE);
+        }
+        // Convert the image to grayscale
+        let image = image.to_luma8();
+
+        // Offset the columns so the terrain is centered around the origin
+        let offset_x = image.width() as i32 / 2;
+        let offset_z = image.height() as i32 / 2;
+
+        // Create a column of voxels for each pixel
+        let mut voxels = vec![];
+        for (x, z, pixel) in image.enumerate_pixels() {
+            let height = (pixel.0[0] as f32 / 255.0 * max_height as f32).round() as u32;
+            for y in 0..height {
+                // Color the voxel by its height
+                let color = if colorize {
+                    let t = y as f32 / max_height as f32;
+                    glam::vec3(0.2, 0.5, 0.2).lerp(glam::vec3(1.0, 1.0, 1.0), t)
+                } else {
+                    glam::vec3(1.0, 1.0, 1.0)
+                };
+                voxels.push(Voxel::new(
+                    glam::ivec3(x as i32 - offset_x, y as i32, z as i32 - offset_z),
+                    color,
+                ));
+            }
+        }
+        Ok(Self::from_voxels(voxels))
+    }
+
+    /// Construct a [`VoxelGrid`] from a text file with a `x,y,z,r,g,b` voxel per line
+    /// The colors are read as 0 to 255 if any of them is above 1, otherwise as 0 to 1
+    /// Empty lines and lines starting with `#` are skipped
+    pub fn from_csv(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+
+        // Parse the rows, keeping the line numbers for the errors
+        let mut rows = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != 6 {
+                return Err(anyhow::anyhow!(
+                    "Line {}: expected 6 values (x,y,z,r,g,b), got {}",
+                    i + 1,
+                    fields.len()
+                ));
+            }
+            let mut position = [0; 3];
+            for (axis, field) in fields[..3].iter().enumerate() {
+                position[axis] = field.parse::<i32>().map_err(|_| {
+                    anyhow::anyhow!("Line {}: invalid position '{}', expected an integer", i + 1, field)
+                })?;
+            }
+            let mut color = [0.0; 3];
+            for (channel, field) in fields[3..].iter().enumerate() {
+                color[channel] = field
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|value| *value >= 0.0 && *value <= 255.0)
+                    .ok_or(anyhow::anyhow!(
+                        "Line {}: invalid color '{}', expected a number from 0 to 255 or 0 to 1",
+                        i + 1,
+                        field
+                    ))?;
+            }
+            rows.push((glam::IVec3::from(position), glam::Vec3::from(color)));
+        }
+
+        // Detect if the colors range from 0 to 255
+        let scale = if rows.iter().any(|(_, color)| color.max_element() > 1.0) {
+            1.0 / 255.0
+        } else {
+            1.0
+        };
+        // A file can list the same cell twice, the last line wins
+        Ok(Self::from_voxels(
+            rows.into_iter()
+                .map(|(position, color)| Voxel::new(position, color * scale)),
+        ))
+    }
+
+    /// Write the grid to a text file with a `x,y,z,r,g,b` voxel per line
+    /// The colors are written as 0 to 255, the material properties aren't saved
+    pub fn to_csv(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut text = String::from("# x,y,z,r,g,b\n");
+        for voxel in &self.0 {
+            let position = voxel.position();
+            let color = (voxel.color.clamp(glam::Vec3::ZERO, glam::Vec3::ONE) * 255.0).round();
+            text += &format!(
+                "{},{},{},{},{},{}\n",
+                position.x, position.y, position.z, color.x, color.y, color.z
+            );
+        }
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Construct a [`VoxelGrid`] from a MagicaVoxel `.vox` file
+    /// Only the first model is loaded, it's centered on the x and z axis and placed on top of y 0
+    /// MagicaVoxel uses z as the up axis, so the y and z axis are swapped
+    pub fn from_vox_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        // Check the header
+        if data.len() < 8 || &data[..4] != b"VOX " {
+            return Err(anyhow::anyhow!("Not a MagicaVoxel file"));
+        }
+
+        // Read a little endian u32 at an offset
+        let read_u32 = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .ok_or(anyhow::anyhow!("Unexpected end of file at byte {}", offset))
+        };
+
+        // The positions and palette indices of the first model
+        let mut voxels: Option<Vec<([u8; 3], u8)>> = None;
+        // The colors of the palette, index 0 is unused by the voxels
+        let mut palette: Option<Vec<glam::Vec3>> = None;
+
+        // Walk the chunks, the children of the main chunk follow its header directly
+        let mut offset = 8;
+        while offset < data.len() {
+            let id = data
+                .get(offset..offset + 4)
+                .ok_or(anyhow::anyhow!("Unexpected end of file at byte {}", offset))?;
+            let content_size = read_u32(offset + 4)? as usize;
+            let children_size = read_u32(offset + 8)? as usize;
+            let content = offset + 12;
+            let content_end = content + content_size;
+            if content_end > data.len() {
+                return Err(anyhow::anyhow!(
+                    "The {} chunk is larger than the file",
+                    String::from_utf8_lossy(id)
+                ));
+            }
+
+            match id {
+                // Step into the children of the main chunk
+                b"MAIN" => {
+                    offset = content_end;
+                    continue;
+                }
+                b"XYZI" if voxels.is_none() => {
+                    let count = read_u32(content)? as usize;
+                    let bytes = data
+                        .get(content + 4..content + 4 + count * 4)
+                        .filter(|_| 4 + count * 4 <= content_size)
+                        .ok_or(anyhow::anyhow!("The XYZI chunk has fewer voxels than it says"))?;
+                    voxels = Some(
+                        bytes
+                            .chunks_exact(4)
+                            .map(|voxel| ([voxel[0], voxel[1], voxel[2]], voxel[3]))
+                            .collect(),
+                    );
+                }
+                b"XYZI" => log::warn!("The file has multiple models, only the first is loaded"),
+                b"RGBA" => {
+                    let bytes = data
+                        .get(content..content + 256 * 4)
+                        .filter(|_| content_size >= 256 * 4)
+                        .ok_or(anyhow::anyhow!("The RGBA chunk is too small"))?;
+                    // Color `i` of the chunk is used by palette index `i + 1`
+                    let mut colors = vec![glam::Vec3::ZERO];
+                    colors.extend(bytes.chunks_exact(4).take(255).map(|color| {
+                        glam::vec3(color[0] as f32, color[1] as f32, color[2] as f32) / 255.0
+                    }));
+                    palette = Some(colors);
+                }
+                // Skip the chunks that aren't needed
+                _ => {}
+            }
+            offset = content_end + children_size;
+        }
+
+        let voxels = voxels.ok_or(anyhow::anyhow!("The file has no XYZI chunk"))?;
+        // Files that use the default palette of MagicaVoxel don't store it
+        let palette = palette.unwrap_or_else(default_vox_palette);
+
+        // Swap the y and z axis since MagicaVoxel uses z as the up axis
+        let positions = voxels
+            .iter()
+            .map(|([x, y, z], _)| glam::ivec3(*x as i32, *z as i32, *y as i32));
+        let (min, max) = positions.clone().fold(
+            (glam::IVec3::splat(i32::MAX), glam::IVec3::splat(i32::MIN)),
+            |(min, max), position| (min.min(position), max.max(position)),
+        );
+        // Center the model on the x and z axis and place it on top of y 0
+        let offset = glam::ivec3((min.x + max.x) / 2, min.y, (min.z + max.z) / 2);
+
+        // Keep the palette indices so the model can be recolored through the palette
+        let mut grid = Self::from_voxels(positions.zip(&voxels).map(|(position, (_, index))| Voxel {
+            palette_index: *index as u32,
+            ..Voxel::new(position - offset, palette[*index as usize])
+        }));
+        grid.1 = palette[1..].to_vec();
+        Ok(grid)
+    }
+}
+
+/// The options for loading the shader, this allows switching between variants of the shader
 #[derive(Debug, Clone)]
-pub struct VoxelGrid(pub Vec<Voxel>);
+pub struct ShaderOptions {
+    // The file the shader is loaded from, the bundled `shaders/voxel.wgsl` is used if not set
+    pub path: Option<PathBuf>,
+    // The name of the compute function to run
+    pub entry_point: String,
+    // The constants to set before compiling, see [`apply_defines`]
+    pub defines: BTreeMap<String, String>,
+}
+
+impl Default for ShaderOptions {
+    fn default() -> Self {
+        Self {
+            path: None,
+            entry_point: "main".to_string(),
+            defines: BTreeMap::new(),
+        }
+    }
+}
+
+impl ShaderOptions {
+    /// Load the shader and set its constants
+    fn load(&self) -> Result<String> {
+        Ok(apply_defines(&load_shader(self.path.as_deref())?, &self.defines))
+    }
+}
 
 /// Handles executing the compute shader
 pub struct Tracer {
-    compute: wgpu::ShaderModule,
+    // The pipeline only depends on the shader, so it's created once
+    bind_group_layout: wgpu::BindGroupLayout,
+    compute_pipeline: wgpu::ComputePipeline,
+    // Where the shader is loaded from and how it's compiled
+    shader: ShaderOptions,
+    // The width and height of a workgroup, this is substituted into the shader
+    workgroup_size: u32,
+    // The modification time of the shader file when it was last loaded
+    shader_modified: Option<SystemTime>,
 
-    // The resulting frame
+    // The buffers are created once and updated with the queue on every trace
+    uniform_buffer: wgpu::Buffer,
+    camera_buffer: wgpu::Buffer,
+    grid_buffer: wgpu::Buffer,
+    // The amount of voxels that fit in the grid buffer
+    grid_capacity: usize,
+    // The buffers of the lights, the palette, the bvh and the emissive voxels, these are only recreated when the scene changes
+    light_buffer: wgpu::Buffer,
+    palette_buffer: wgpu::Buffer,
+    bvh_buffer: wgpu::Buffer,
+    emissive_buffer: wgpu::Buffer,
+    // The grid and the flag for if the bvh was used as of the last upload, nothing is uploaded yet if not set
+    uploaded_grid: Option<(VoxelGrid, bool)>,
+    // The lights as of the last upload
+    uploaded_lights: Vec<Light>,
+
+    // The resulting frame, the pixels are srgb but the format is linear since storage textures can't be srgb
     texture: wgpu::Texture,
     texture_view: wgpu::TextureView,
+    // The srgb copy of the frame that is shown by egui and the buffer the copy goes through
+    display_texture: wgpu::Texture,
+    display_view: wgpu::TextureView,
+    display_buffer: wgpu::Buffer,
+    // The sum of the accumulated samples for each pixel
+    accumulation: wgpu::Buffer,
+    // The amount of accumulated samples
+    samples: u32,
+    // Filters the noise out of the frame when enabled in the uniforms
+    denoiser: Denoiser,
+    // Measures the gpu time of the traces, not set if the device doesn't support timestamp queries
+    timer: Option<GpuTimer>,
+    // Collects the march steps and the hits when enabled in the uniforms
+    stats: StatsCollector,
+    // The voxels of the instanced grids one after another, and the instances that place them
+    instance_voxel_buffer: wgpu::Buffer,
+    instance_buffer: wgpu::Buffer,
+    instance_count: u32,
 
     // The resolution for the buffer
     resolution: (u32, u32),
@@ -69,88 +1256,22 @@ pub struct Tracer {
 
 impl Tracer {
     /// Construct a new [`Tracer`]
-    pub fn new(ctx: &mut RenderContext, uniforms: &Uniforms) -> anyhow::Result<Self> {
-        // Load the shader source
-        let source = std::fs::read_to_string("shaders/voxel.wgsl")?;
-
-        // Compile the shader
-        let compute = ctx
-            .device
-            .create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: None,
-                source: wgpu::ShaderSource::Wgsl(source.into()),
-            });
-
-        // Create the texture buffer
-        let texture = ctx.device.create_texture(&wgpu::TextureDescriptor {
-            label: None,
-            size: wgpu::Extent3d {
-                width: uniforms.resolution[0],
-                height: uniforms.resolution[1],
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8Unorm,
-            usage: wgpu::TextureUsages::STORAGE_BINDING
-                | wgpu::TextureUsages::TEXTURE_BINDING
-                | wgpu::TextureUsages::COPY_SRC,
-        });
-        // Create a texture view
-        let texture_view = texture.create_view(&Default::default());
-
-        Ok(Self {
-            compute,
-            resolution: (uniforms.resolution[0], uniforms.resolution[1]),
-            texture,
-            texture_view,
-        })
-    }
-
-    /// Trace the texture
-    pub fn trace(
-        &self,
-        ctx: &mut RenderContext,
-        uniforms: Uniforms,
-        grid: &VoxelGrid,
-        camera: Camera,
-    ) -> anyhow::Result<egui::TextureId> {
-        // Create the uniform buffer
-        let mut buffer = UniformBuffer::new(vec![]);
-        buffer.write(&uniforms)?;
-        let uniform_buffer = ctx
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
-                contents: &buffer.into_inner(),
-                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::UNIFORM,
-            });
-        // Create the camera buffer
-        let mut buffer = UniformBuffer::new(vec![]);
-        buffer.write(&camera)?;
-        let camera_buffer = ctx
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
-                contents: &buffer.into_inner(),
-                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::UNIFORM,
-            });
+    /// The shader is read from the path of the options if it's set, so it can be changed without recompiling
+    pub fn new(
+        gpu: &GpuContext,
+        uniforms: &Uniforms,
+        shader: &ShaderOptions,
+    ) -> Result<Self> {
+        // Check if the gpu supports the resolution
+        check_resolution(uniforms.resolution, &gpu.device.limits())?;
 
-        // Create the grid buffer
-        let mut buffer = StorageBuffer::new(vec![]);
-        buffer.write(&grid.0)?;
-        let grid_buffer = ctx
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
-                contents: &buffer.into_inner(),
-                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
-            });
+        // Load the shader source
+        let shader_modified = shader.path.as_deref().and_then(modified_time);
+        let source = shader.load()?;
 
         // Create the bind group layout
         let bind_group_layout =
-            ctx.device
+            gpu.device
                 .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                     label: None,
                     entries: &[
@@ -161,21 +1282,18 @@ impl Tracer {
                             ty: wgpu::BindingType::Buffer {
                                 ty: wgpu::BufferBindingType::Uniform,
                                 has_dynamic_offset: false,
-                                min_binding_size: None, //NonZeroU64::new(
-                                                        //     std::mem::size_of::<Uniforms>() as u64
-                                                        //),
+                                min_binding_size: Some(Uniforms::min_size()),
                             },
                             count: None,
                         },
+                        // The camera
                         wgpu::BindGroupLayoutEntry {
                             binding: 1,
                             visibility: wgpu::ShaderStages::COMPUTE,
                             ty: wgpu::BindingType::Buffer {
                                 ty: wgpu::BufferBindingType::Uniform,
                                 has_dynamic_offset: false,
-                                min_binding_size: None, //NonZeroU64::new(
-                                                        //    std::mem::size_of::<Camera>() as u64
-                                                        //),
+                                min_binding_size: Some(Camera::min_size()),
                             },
                             count: None,
                         },
@@ -186,9 +1304,8 @@ impl Tracer {
                             ty: wgpu::BindingType::Buffer {
                                 ty: wgpu::BufferBindingType::Storage { read_only: true },
                                 has_dynamic_offset: false,
-                                min_binding_size: None,//NonZeroU64::new(
-                                    //std::mem::size_of_val(&*grid.0) as u64
-                                //),
+                                // The grid is runtime-sized, so only require room for one voxel
+                                min_binding_size: Some(Voxel::min_size()),
                             },
                             count: None,
                         },
@@ -203,87 +1320,634 @@ impl Tracer {
                             },
                             count: None,
                         },
-                    ],
-                });
-
-        // Create the bind group
-        let bind_group = ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                        // The light buffer
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 4,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(Light::min_size()),
+                            },
+                            count: None,
+                        },
+                        // The bvh buffer
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 5,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(BvhNode::min_size()),
+                            },
+                            count: None,
+                        },
+                        // The accumulation buffer
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 6,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(glam::Vec4::min_size()),
+                            },
+                            count: None,
+                        },
+                        // The denoise guide buffer
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 7,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(glam::Vec4::min_size()),
+                            },
+                            count: None,
+                        },
+                        // The palette buffer
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 8,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                // A vec3 in an array is padded to 16 bytes, so the size of a single color isn't enough
+                                min_binding_size: Some(<[glam::Vec3; 1]>::min_size()),
+                            },
+                            count: None,
+                        },
+                        // The voxels of the instanced grids
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 9,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(Voxel::min_size()),
+                            },
+                            count: None,
+                        },
+                        // The instances
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 10,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(GpuInstance::min_size()),
+                            },
+                            count: None,
+                        },
+                        // The render stats
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 11,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        // The emissive voxels
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 12,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(Voxel::min_size()),
+                            },
+                            count: None,
+                        },
+                    ],
+                });
+
+        // Compile the shader and create the compute pipeline
+        let workgroup_size = workgroup_size(&gpu.device.limits());
+        log::debug!("Using {0}x{0} workgroups", workgroup_size);
+        let compute_pipeline =
+            create_pipeline(gpu, &bind_group_layout, source, &shader.entry_point, workgroup_size)?;
+
+        // Create the frame texture and the accumulation buffer
+        let (texture, texture_view, accumulation) = create_targets(gpu, uniforms.resolution)?;
+        let (display_texture, display_view, display_buffer) = create_display_targets(gpu, uniforms.resolution);
+        let denoiser = Denoiser::new(gpu, uniforms.resolution, workgroup_size)?;
+        let (instance_voxel_buffer, instance_buffer) = create_instance_buffers(gpu, &[], &[])?;
+        let timer = GpuTimer::new(gpu);
+        if timer.is_none() {
+            log::info!("Timestamp queries aren't supported, only the cpu time of the traces is measured");
+        }
+
+        // Create the uniform and camera buffers, these have a fixed size
+        let uniform_buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: Uniforms::min_size().get(),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+            mapped_at_creation: false,
+        });
+        let camera_buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
-            layout: &bind_group_layout,
+            size: Camera::min_size().get(),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+            mapped_at_creation: false,
+        });
+        // Create the grid buffer with room for the voxels of the uniforms
+        // Storage buffers can't be empty, so there is always room for at least one voxel
+        let grid_capacity = (uniforms.voxel_amount as usize).max(1);
+        let grid_buffer = create_grid_buffer(gpu, grid_capacity)?;
+        // The other scene buffers start out with a dummy element, they're filled by the first trace
+        let light_buffer = create_light_buffer(gpu, &[])?;
+        let palette_buffer = create_palette_buffer(gpu, &[])?;
+        let bvh_buffer = create_bvh_buffer(gpu, &VoxelBvh::new(&VoxelGrid::default()).nodes)?;
+        let emissive_buffer = create_emissive_buffer(gpu, &[])?;
+
+        Ok(Self {
+            bind_group_layout,
+            compute_pipeline,
+            shader: shader.clone(),
+            shader_modified,
+            workgroup_size,
+            uniform_buffer,
+            camera_buffer,
+            grid_buffer,
+            grid_capacity,
+            light_buffer,
+            palette_buffer,
+            bvh_buffer,
+            emissive_buffer,
+            uploaded_grid: None,
+            uploaded_lights: vec![],
+            resolution: (uniforms.resolution[0], uniforms.resolution[1]),
+            texture,
+            texture_view,
+            display_texture,
+            display_view,
+            display_buffer,
+            accumulation,
+            samples: 0,
+            denoiser,
+            timer,
+            stats: StatsCollector::new(gpu),
+            instance_voxel_buffer,
+            instance_buffer,
+            instance_count: 0,
+        })
+    }
+
+    /// Trace the texture
+    /// When accumulating, the trace is averaged with the previous traces until [`Tracer::reset_accumulation`] is called
+    #[cfg(feature = "gui")]
+    pub fn trace(
+        &mut self,
+        ctx: &mut crate::render::RenderContext,
+        uniforms: Uniforms,
+        grid: &VoxelGrid,
+        lights: &[Light],
+        camera: Camera,
+    ) -> Result<egui::TextureId> {
+        self.dispatch(&ctx.gpu, uniforms, grid, lights, camera)?;
+
+        // Copy the frame to the srgb texture, so egui decodes it before drawing it to the window
+        // The formats differ, so the copy goes through a buffer
+        let bytes_per_row = padded_bytes_per_row(self.resolution.0);
+        let layout = wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: NonZeroU32::new(bytes_per_row),
+            rows_per_image: NonZeroU32::new(self.resolution.1),
+        };
+        let size = wgpu::Extent3d {
+            width: self.resolution.0,
+            height: self.resolution.1,
+            depth_or_array_layers: 1,
+        };
+        let mut encoder = ctx.gpu.device.create_command_encoder(&Default::default());
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &self.display_buffer,
+                layout,
+            },
+            size,
+        );
+        encoder.copy_buffer_to_texture(
+            wgpu::ImageCopyBuffer {
+                buffer: &self.display_buffer,
+                layout,
+            },
+            self.display_texture.as_image_copy(),
+            size,
+        );
+        ctx.gpu.queue.submit([encoder.finish()]);
+
+        // Return the texture as an egui image
+        let image = ctx.egui_pass.egui_texture_from_wgpu_texture(
+            &ctx.gpu.device,
+            &self.display_view,
+            wgpu::FilterMode::Nearest,
+        );
+        Ok(image)
+    }
+
+    /// Trace a single frame without a window and return it as an image
+    /// The bundled shader is used, the resolution overrides the resolution and the render region of the uniforms
+    pub fn render_offline(
+        gpu: &GpuContext,
+        resolution: glam::UVec2,
+        uniforms: Uniforms,
+        grid: &VoxelGrid,
+        lights: &[Light],
+        camera: Camera,
+    ) -> Result<image::RgbaImage> {
+        let uniforms = Uniforms {
+            resolution,
+            region_offset: glam::UVec2::ZERO,
+            region_size: resolution,
+            voxel_amount: grid.0.len() as u32,
+            light_count: lights.len() as u32,
+            accumulate: 0,
+            ..uniforms
+        };
+        let mut tracer = Self::new(gpu, &uniforms, &ShaderOptions::default())?;
+        tracer.dispatch(gpu, uniforms, grid, lights, camera)?;
+        block_on(tracer.read_frame(gpu))
+    }
+
+    /// Trace an image that is larger than the gpu can fit in one texture, one tile at a time
+    /// Only a frame of the tile size is allocated on the gpu, the tiles are stitched together on the cpu
+    /// The bundled shader is used, like [`Tracer::render_offline`]
+    /// The denoiser only sees a single tile, so it's disabled to avoid seams
+    pub fn render_tiled(
+        gpu: &GpuContext,
+        resolution: glam::UVec2,
+        tile_size: glam::UVec2,
+        uniforms: Uniforms,
+        grid: &VoxelGrid,
+        lights: &[Light],
+        camera: Camera,
+    ) -> Result<image::RgbaImage> {
+        let tile_size = tile_size.max(glam::UVec2::ONE).min(resolution);
+        let uniforms = Uniforms {
+            resolution: tile_size,
+            region_offset: glam::UVec2::ZERO,
+            full_resolution: resolution,
+            voxel_amount: grid.0.len() as u32,
+            light_count: lights.len() as u32,
+            accumulate: 0,
+            denoise: 0,
+            ..uniforms
+        };
+        let mut tracer = Self::new(gpu, &uniforms, &ShaderOptions::default())?;
+        let mut image = image::RgbaImage::new(resolution.x, resolution.y);
+        for y in (0..resolution.y).step_by(tile_size.y as usize) {
+            for x in (0..resolution.x).step_by(tile_size.x as usize) {
+                // The tiles at the right and bottom edges can be smaller than the frame
+                let offset = glam::uvec2(x, y);
+                let size = tile_size.min(resolution - offset);
+                let uniforms = Uniforms {
+                    region_size: size,
+                    tile_offset: offset,
+                    ..uniforms
+                };
+                tracer.dispatch(gpu, uniforms, grid, lights, camera)?;
+                let tile = block_on(tracer.read_frame(gpu))?;
+                let tile = image::imageops::crop_imm(&tile, 0, 0, size.x, size.y).to_image();
+                image::imageops::replace(&mut image, &tile, x as i64, y as i64);
+                log::debug!("Rendered the tile at {}x{}", x, y);
+            }
+        }
+        Ok(image)
+    }
+
+    /// Record and submit the compute pass that traces the frame
+    /// This traces without a window, use [`Tracer::read_frame`] to get the frame
+    /// Validation errors are returned instead of silently producing a broken frame
+    pub fn dispatch(
+        &mut self,
+        gpu: &GpuContext,
+        uniforms: Uniforms,
+        grid: &VoxelGrid,
+        lights: &[Light],
+        camera: Camera,
+    ) -> Result<()> {
+        // Catch the validation errors of the buffers, the bind group and the compute pass
+        validate(gpu, || self.submit(gpu, uniforms, grid, lights, camera))?;
+        // Count the sample
+        self.samples = if uniforms.accumulate != 0 { self.samples + 1 } else { 0 };
+        Ok(())
+    }
+
+    /// Upload the voxels, the palette and the bvh of a grid
+    /// The bvh reorders the voxels so each leaf covers a range of them, so the voxels are uploaded in that order
+    fn upload_grid(&mut self, gpu: &GpuContext, grid: &VoxelGrid, use_bvh: bool) -> Result<()> {
+        // Build the bvh, a single empty node is uploaded when the bvh isn't used
+        let empty = VoxelGrid::default();
+        let bvh = VoxelBvh::new(if use_bvh { grid } else { &empty });
+        let voxels = if use_bvh { &bvh.voxels } else { &grid.0 };
+
+        // Only recreate the grid buffer when the voxels don't fit anymore
+        if voxels.len() > self.grid_capacity {
+            self.grid_buffer = create_grid_buffer(gpu, voxels.len())?;
+            self.grid_capacity = voxels.len();
+            log::debug!("Grew the grid buffer to {} voxels", self.grid_capacity);
+        }
+        // Update the grid buffer, the voxels past the voxel amount are ignored by the shader
+        // Writing an empty grid is skipped by wgpu, so the buffer keeps its old contents
+        let mut buffer = StorageBuffer::new(vec![]);
+        buffer.write(voxels)?;
+        gpu.queue.write_buffer(&self.grid_buffer, 0, &buffer.into_inner());
+
+        self.palette_buffer = create_palette_buffer(gpu, &grid.1)?;
+        self.bvh_buffer = create_bvh_buffer(gpu, &bvh.nodes)?;
+        self.emissive_buffer = create_emissive_buffer(gpu, voxels)?;
+        self.uploaded_grid = Some((grid.clone(), use_bvh));
+        Ok(())
+    }
+
+    /// Update the buffers, then record and submit the compute pass
+    fn submit(
+        &mut self,
+        gpu: &GpuContext,
+        uniforms: Uniforms,
+        grid: &VoxelGrid,
+        lights: &[Light],
+        camera: Camera,
+    ) -> Result<()> {
+        // Continue from the accumulated samples
+        // The voxel amount always matches the grid, so an empty grid runs no voxel loops and only the background is drawn
+        let uniforms = Uniforms {
+            sample_count: if uniforms.accumulate != 0 { self.samples } else { 0 },
+            voxel_amount: grid.0.len() as u32,
+            palette_size: grid.1.len() as u32,
+            instance_count: self.instance_count,
+            // Skip the counting while the previous counters are still being read back
+            collect_stats: (uniforms.collect_stats != 0 && self.stats.is_idle()) as u32,
+            ..uniforms
+        };
+        // Update the uniform buffer
+        let mut buffer = UniformBuffer::new(vec![]);
+        buffer.write(&uniforms)?;
+        gpu.queue.write_buffer(&self.uniform_buffer, 0, &buffer.into_inner());
+        // Update the camera buffer
+        let mut buffer = UniformBuffer::new(vec![]);
+        buffer.write(&camera)?;
+        gpu.queue.write_buffer(&self.camera_buffer, 0, &buffer.into_inner());
+
+        // Only rebuild the bvh and upload the grid when the grid or the acceleration changed
+        let use_bvh = uniforms.acceleration == ACCELERATION_BVH;
+        let grid_changed =
+            !matches!(&self.uploaded_grid, Some((uploaded, bvh)) if uploaded == grid && *bvh == use_bvh);
+        if grid_changed {
+            self.upload_grid(gpu, grid, use_bvh)?;
+        }
+        if self.uploaded_lights != lights {
+            self.light_buffer = create_light_buffer(gpu, lights)?;
+            self.uploaded_lights = lights.to_vec();
+        }
+
+        // Create the bind group
+        let bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: uniform_buffer.as_entire_binding(),
+                    resource: self.uniform_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: camera_buffer.as_entire_binding(),
+                    resource: self.camera_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: grid_buffer.as_entire_binding(),
+                    resource: self.grid_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
                     resource: wgpu::BindingResource::TextureView(&self.texture_view),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.light_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: self.bvh_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: self.accumulation.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: self.denoiser.guide().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: self.palette_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: self.instance_voxel_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: self.instance_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: self.stats.counters().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: self.emissive_buffer.as_entire_binding(),
+                },
             ],
         });
 
-        // Create the compute pipeline
-        let compute_pipeline_layout =
-            ctx.device
-                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    label: None,
-                    bind_group_layouts: &[&bind_group_layout],
-                    push_constant_ranges: &[],
-                });
-        let compute_pipeline =
-            ctx.device
-                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                    label: None,
-                    layout: Some(&compute_pipeline_layout),
-                    module: &self.compute,
-                    entry_point: "main",
-                });
-
         // Create the command encoder
-        let mut encoder = ctx.device.create_command_encoder(&Default::default());
+        let mut encoder = gpu.device.create_command_encoder(&Default::default());
+        // Time the trace unless the previous time is still being read back
+        let timer = self.timer.as_mut().filter(|timer| timer.is_idle());
+        if let Some(timer) = &timer {
+            timer.begin(&mut encoder);
+        }
+        // Reset the counters, the uniforms only enable them when the previous ones have been read
+        if uniforms.collect_stats != 0 {
+            self.stats.begin(gpu);
+        }
         // Execute the compute shader
         {
             let mut compute_pass = encoder.begin_compute_pass(&Default::default());
-            compute_pass.set_pipeline(&compute_pipeline);
+            compute_pass.set_pipeline(&self.compute_pipeline);
             compute_pass.set_bind_group(0, &bind_group, &[]);
-            compute_pass.dispatch_workgroups(self.resolution.0 / WORKGROUP_SIZE, self.resolution.1 / WORKGROUP_SIZE, 1);
+            // Only dispatch the workgroups covering the render region
+            let width = uniforms.region_size.x.min(self.resolution.0);
+            let height = uniforms.region_size.y.min(self.resolution.1);
+            let workgroups = dispatch_size(glam::uvec2(width, height), self.workgroup_size);
+            compute_pass.dispatch_workgroups(workgroups.x, workgroups.y, 1);
+        }
+
+        // Filter the shaded frame, the debug outputs are shown as is
+        if uniforms.denoise != 0 && uniforms.output_mode == OUTPUT_SHADED {
+            self.denoiser.encode(gpu, &mut encoder, &self.texture_view)?;
+        }
+        if let Some(timer) = &timer {
+            timer.end(&mut encoder);
+        }
+        if uniforms.collect_stats != 0 {
+            self.stats.end(&mut encoder);
         }
 
         // Submut the encoder to the queue
-        ctx.queue.submit([encoder.finish()]);
+        gpu.queue.submit([encoder.finish()]);
+        if let Some(timer) = timer {
+            timer.read_back();
+        }
+        if uniforms.collect_stats != 0 {
+            self.stats.read_back();
+        }
+        Ok(())
+    }
 
-        // Return the texture as an egui image
-        let image = ctx.egui_pass.egui_texture_from_wgpu_texture(
-            &ctx.device,
-            &self.texture_view,
-            wgpu::FilterMode::Nearest,
-        );
-        Ok(image)
+    /// Get the counters of the last trace that collected stats
+    /// Returns [`None`] if no trace has collected stats yet, see [`Uniforms::collect_stats`]
+    /// The device has to be polled for the counters to arrive
+    pub fn stats(&mut self) -> Option<RenderStats> {
+        self.stats.poll()
     }
 
-    /// Get the frame as image data
-    pub async fn frame_to_image(&self, path: impl AsRef<Path>, ctx: &RenderContext) -> anyhow::Result<()> {
+    /// Get the time the gpu spent on the last measured trace in milliseconds
+    /// Returns [`None`] if timestamp queries aren't supported or no trace has been measured yet
+    /// The device has to be polled for the measurement to arrive
+    pub fn gpu_time(&mut self) -> Option<f32> {
+        self.timer.as_mut().and_then(GpuTimer::poll)
+    }
+
+    /// Check if the gpu time of the traces can be measured
+    pub fn supports_gpu_time(&self) -> bool {
+        self.timer.is_some()
+    }
+
+    /// Upload grids that are drawn at every instance that refers to them, besides the grid of the trace
+    /// The colors from the palettes are baked into the voxels, and the instances are drawn without the bvh,
+    /// the ambient occlusion and the glow of the emissive voxels
+    /// Call this again with empty lists to remove the instances
+    pub fn set_instances(&mut self, gpu: &GpuContext, grids: &[VoxelGrid], instances: &[Instance]) -> Result<()> {
+        let (instance_voxel_buffer, instance_buffer) = create_instance_buffers(gpu, grids, instances)?;
+        self.instance_voxel_buffer = instance_voxel_buffer;
+        self.instance_buffer = instance_buffer;
+        self.instance_count = instances.len() as u32;
+        self.reset_accumulation();
+        Ok(())
+    }
+
+    /// Start accumulating from scratch on the next trace
+    pub fn reset_accumulation(&mut self) {
+        self.samples = 0;
+    }
+
+    /// Resize the frame, this recreates the texture and restarts the accumulation
+    /// The egui texture of an earlier trace keeps showing the old texture, so trace again after resizing
+    pub fn resize(&mut self, gpu: &GpuContext, width: u32, height: u32) -> Result<()> {
+        // Skip empty and unchanged sizes
+        if width == 0 || height == 0 || (width, height) == self.resolution {
+            return Ok(());
+        }
+        let resolution = glam::uvec2(width, height);
+        check_resolution(resolution, &gpu.device.limits())?;
+
+        // Recreate the frame texture and the accumulation buffer
+        let (texture, texture_view, accumulation) = create_targets(gpu, resolution)?;
+        let (display_texture, display_view, display_buffer) = create_display_targets(gpu, resolution);
+        self.denoiser.resize(gpu, resolution)?;
+        self.texture = texture;
+        self.texture_view = texture_view;
+        self.display_texture = display_texture;
+        self.display_view = display_view;
+        self.display_buffer = display_buffer;
+        self.accumulation = accumulation;
+        self.resolution = (width, height);
+        self.reset_accumulation();
+        log::debug!("Resized the frame to {}x{}", width, height);
+        Ok(())
+    }
+
+    /// Get the texture [`Tracer::dispatch`] writes the frame to, so it can be used without egui
+    /// The pixels are srgb, but the format is `Rgba8Unorm` since storage textures can't be srgb
+    pub fn texture(&self) -> &wgpu::Texture {
+        &self.texture
+    }
+
+    /// Get the view of the frame texture, see [`Tracer::texture`]
+    pub fn texture_view(&self) -> &wgpu::TextureView {
+        &self.texture_view
+    }
+
+    /// Get the resolution of the frame
+    pub fn resolution(&self) -> glam::UVec2 {
+        glam::uvec2(self.resolution.0, self.resolution.1)
+    }
+
+    /// Get the amount of samples that have been accumulated
+    pub fn sample_count(&self) -> u32 {
+        self.samples
+    }
+
+    /// Get the width and height of the workgroups
+    pub fn workgroup_size(&self) -> u32 {
+        self.workgroup_size
+    }
+
+    /// Get the file the shader is loaded from
+    /// Returns [`None`] if the bundled shader is used
+    pub fn shader_path(&self) -> Option<&Path> {
+        self.shader.path.as_deref()
+    }
+
+    /// Compile the shader again and replace the pipeline
+    /// The previous pipeline is kept if the shader fails to compile, the bundled shader never changes
+    pub fn reload_shader(&mut self, gpu: &GpuContext) -> Result<()> {
+        // Remember the time first so a broken shader isn't compiled again every frame
+        self.shader_modified = self.shader.path.as_deref().and_then(modified_time);
+        let source = self.shader.load()?;
+        self.compute_pipeline = create_pipeline(
+            gpu,
+            &self.bind_group_layout,
+            source,
+            &self.shader.entry_point,
+            self.workgroup_size,
+        )?;
+        self.reset_accumulation();
+        log::info!("Reloaded the shader");
+        Ok(())
+    }
+
+    /// Reload the shader if its file changed since it was last loaded
+    /// Returns true if the shader was reloaded
+    pub fn reload_shader_if_changed(&mut self, gpu: &GpuContext) -> Result<bool> {
+        let modified = self.shader.path.as_deref().and_then(modified_time);
+        if modified.is_none() || modified == self.shader_modified {
+            return Ok(false);
+        }
+        self.reload_shader(gpu)?;
+        Ok(true)
+    }
+
+    /// Submit a copy of the frame to a buffer that can be mapped
+    /// Returns the buffer and the bytes per row, which are padded to [`wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`]
+    fn copy_frame(&self, gpu: &GpuContext) -> (wgpu::Buffer, u32) {
         // Pad the bytes per row
-        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
-        let padded_bytes_per_row = (((self.resolution.0 * 4) + align - 1) / align) * align;
-        println!("{}", padded_bytes_per_row);
+        let padded_bytes_per_row = padded_bytes_per_row(self.resolution.0);
 
-        let buffer = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+        let buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
             size: padded_bytes_per_row as u64 * self.resolution.1 as u64,
             usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
-        let mut encoder = ctx.device.create_command_encoder(&Default::default());
+        let mut encoder = gpu.device.create_command_encoder(&Default::default());
         encoder.copy_texture_to_buffer(
             wgpu::ImageCopyTexture {
                 texture: &self.texture,
@@ -306,33 +1970,1101 @@ impl Tracer {
             },
         );
 
-        ctx.queue.submit([encoder.finish()]);
+        gpu.queue.submit([encoder.finish()]);
+        (buffer, padded_bytes_per_row)
+    }
+
+    /// Copy the frame from the gpu
+    /// Returns the pixels with each row padded to [`wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`] and the padded bytes per row
+    async fn read_padded_frame(&self, gpu: &GpuContext) -> Result<(Vec<u8>, u32)> {
+        let (buffer, padded_bytes_per_row) = self.copy_frame(gpu);
 
         // Read the data from the buffer
         let buffer_slice = buffer.slice(..);
         let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
         buffer_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
 
-        ctx.device.poll(wgpu::Maintain::Wait);
+        gpu.device.poll(wgpu::Maintain::Wait);
 
         match receiver.receive().await.unwrap() {
             Ok(()) => {
                 let data = buffer_slice.get_mapped_range();
                 let result: Vec<u8> = bytemuck::cast_slice(&data).to_vec();
 
-                image::save_buffer(
-                    &path,
-                    &result,
-                    padded_bytes_per_row / 4,
-                    self.resolution.1,
-                    image::ColorType::Rgba8,
-                )?;
-
                 drop(data);
                 buffer.unmap();
-                return Ok(())
+                Ok((result, padded_bytes_per_row))
+            }
+            Err(e) => Err(VoxelError::Wgpu(e.to_string())),
+        }
+    }
+
+    /// Get the frame as an image in memory
+    /// The row padding of the gpu copy is stripped so the image has the actual resolution
+    pub async fn read_frame(&self, gpu: &GpuContext) -> Result<image::RgbaImage> {
+        let (data, padded_bytes_per_row) = self.read_padded_frame(gpu).await?;
+        strip_padding(&data, padded_bytes_per_row, self.resolution)
+    }
+
+    /// Start copying the frame from the gpu without waiting for it
+    /// The device has to be polled until [`FrameReadback::try_finish`] returns the image
+    pub fn start_read_frame(&self, gpu: &GpuContext) -> FrameReadback {
+        let (buffer, padded_bytes_per_row) = self.copy_frame(gpu);
+        let result = Arc::new(Mutex::new(None));
+        let sender = result.clone();
+        buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |mapped| {
+                *sender.lock().unwrap() = Some(mapped);
+            });
+        FrameReadback {
+            buffer,
+            padded_bytes_per_row,
+            resolution: self.resolution,
+            result,
+        }
+    }
+
+    /// Get the frame as image data
+    /// The image format is inferred from the file extension (png, jpg or bmp)
+    pub async fn frame_to_image(&self, path: impl AsRef<Path>, gpu: &GpuContext) -> Result<()> {
+        // Get the image format before doing any gpu work
+        image_format(path.as_ref())?;
+        // Read the frame from the gpu without the row padding
+        let image = self.read_frame(gpu).await?;
+        save_image(&image, path)
+    }
+
+    /// Read the camera and the uniforms a png was rendered with, see [`save_with_metadata`]
+    /// Returns [`None`] if the file isn't a png or doesn't contain the settings
+    pub fn load_metadata(path: impl AsRef<Path>) -> Option<(Camera, Uniforms)> {
+        let file = std::fs::File::open(path).ok()?;
+        let reader = png::Decoder::new(std::io::BufReader::new(file)).read_info().ok()?;
+        // The chunk is written before the image data, so it's part of the info
+        let chunk = reader
+            .info()
+            .uncompressed_latin1_text
+            .iter()
+            .find(|chunk| chunk.keyword == METADATA_KEYWORD)?;
+        let metadata: FrameMetadata = serde_json::from_str(&chunk.text).ok()?;
+        Some((metadata.camera, metadata.uniforms))
+    }
+}
+
+/// The settings a frame was rendered with, this is stored as json in saved pngs
+#[derive(Serialize, Deserialize)]
+struct FrameMetadata {
+    camera: Camera,
+    uniforms: Uniforms,
+}
+
+/// A copy of the frame that is being read from the gpu, see [`Tracer::start_read_frame`]
+pub struct FrameReadback {
+    buffer: wgpu::Buffer,
+    padded_bytes_per_row: u32,
+    resolution: (u32, u32),
+    // Set by wgpu once the buffer is mapped or the mapping failed
+    result: Arc<Mutex<Option<std::result::Result<(), wgpu::BufferAsyncError>>>>,
+}
+
+impl FrameReadback {
+    /// Get the frame if the gpu is done copying it
+    /// Returns [`None`] while the copy is still running
+    pub fn try_finish(&self) -> Option<Result<image::RgbaImage>> {
+        let mapped = self.result.lock().unwrap().take()?;
+        Some(
+            mapped
+                .map_err(|e| VoxelError::Wgpu(e.to_string()))
+                .and_then(|()| {
+                    let image = {
+                        let data = self.buffer.slice(..).get_mapped_range();
+                        strip_padding(&data, self.padded_bytes_per_row, self.resolution)
+                    };
+                    self.buffer.unmap();
+                    image
+                }),
+        )
+    }
+}
+
+/// Remove the row padding of a frame copied from the gpu, so the image has the actual resolution
+fn strip_padding(data: &[u8], padded_bytes_per_row: u32, resolution: (u32, u32)) -> Result<image::RgbaImage> {
+    let bytes_per_row = resolution.0 as usize * 4;
+    let pixels = data
+        .chunks_exact(padded_bytes_per_row as usize)
+        .flat_map(|row| &row[..bytes_per_row])
+        .copied()
+        .collect();
+    image::RgbaImage::from_raw(resolution.0, resolution.1, pixels)
+        .ok_or_else(|| VoxelError::Wgpu("The frame data doesn't match the resolution".to_string()))
+}
+
+/// Compile a shader and create the compute pipeline for it
+/// The validation errors are returned instead of panicking
+pub(crate) fn create_pipeline(
+    gpu: &GpuContext,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    source: String,
+    entry_point: &str,
+    workgroup_size: u32,
+) -> Result<wgpu::ComputePipeline> {
+    let source = set_workgroup_size(&source, workgroup_size)?;
+    // Compile the shader, catching the errors instead of panicking
+    gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let compute = gpu
+        .device
+        .create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+
+    // Create the compute pipeline
+    let compute_pipeline_layout =
+        gpu.device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[bind_group_layout],
+                push_constant_ranges: &[],
+            });
+    let compute_pipeline =
+        gpu.device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: None,
+                layout: Some(&compute_pipeline_layout),
+                module: &compute,
+                entry_point,
+            });
+    if let Some(e) = block_on(gpu.device.pop_error_scope()) {
+        return Err(VoxelError::ShaderCompile(e.to_string()));
+    }
+
+    Ok(compute_pipeline)
+}
+
+/// Get the largest square workgroup up to 16x16 that fits within the limits of the gpu
+/// The size is a power of two so the workgroups stay aligned with the frame
+pub fn workgroup_size(limits: &wgpu::Limits) -> u32 {
+    let mut size = MAX_WORKGROUP_SIZE;
+    while size > 1
+        && (size * size > limits.max_compute_invocations_per_workgroup
+            || size > limits.max_compute_workgroup_size_x
+            || size > limits.max_compute_workgroup_size_y)
+    {
+        size /= 2;
+    }
+    size
+}
+
+/// Get the amount of workgroups that cover a frame, the last workgroups stick out if the frame isn't a multiple of the size
+pub fn dispatch_size(resolution: glam::UVec2, workgroup_size: u32) -> glam::UVec2 {
+    glam::uvec2(
+        resolution.x.div_ceil(workgroup_size),
+        resolution.y.div_ceil(workgroup_size),
+    )
+}
+
+/// Replace the `@workgroup_size` attribute of the shader with a square workgroup
+fn set_workgroup_size(source: &str, size: u32) -> Result<String> {
+    let missing = || VoxelError::ShaderCompile("The shader has no @workgroup_size attribute".to_string());
+    let start = source.find("@workgroup_size(").ok_or_else(missing)?;
+    let end = start + source[start..].find(')').ok_or_else(missing)? + 1;
+    Ok(format!(
+        "{}@workgroup_size({}, {}, 1){}",
+        &source[..start],
+        size,
+        size,
+        &source[end..]
+    ))
+}
+
+/// Set the module-scope constants of a shader, like `#define` but for the `let` constants of wgsl
+/// A constant that is declared as `let NAME = ...;` at the start of a line gets the new value, otherwise it's added to the top
+fn apply_defines(source: &str, defines: &BTreeMap<String, String>) -> String {
+    let mut source = source.to_string();
+    for (name, value) in defines {
+        let declaration = format!("let {}", name);
+        // Look for the declaration, the name has to end there so `SIZE` doesn't match `SIZE_X`
+        let found = source.match_indices(&declaration).map(|(start, _)| start).find(|&start| {
+            let at_line_start = start == 0 || source[..start].ends_with('\n');
+            let rest = source[start + declaration.len()..].trim_start();
+            at_line_start && (rest.starts_with(':') || rest.starts_with('='))
+        });
+        // Replace the value between the `=` and the `;`, keeping the type if it has one
+        let replaced = found.and_then(|start| {
+            let equals = start + source[start..].find('=')?;
+            let end = equals + source[equals..].find(';')?;
+            Some(format!("{}= {}{}", &source[..equals], value, &source[end..]))
+        });
+        source = replaced.unwrap_or_else(|| format!("let {} = {};\n{}", name, value, source));
+    }
+    source
+}
+
+/// Create the frame texture and the accumulation buffer for a resolution
+fn create_targets(
+    gpu: &GpuContext,
+    resolution: glam::UVec2,
+) -> Result<(wgpu::Texture, wgpu::TextureView, wgpu::Buffer)> {
+    // Create the texture buffer
+    let texture = gpu.device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size: wgpu::Extent3d {
+            width: resolution[0],
+            height: resolution[1],
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::STORAGE_BINDING
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
+    });
+    // Create a texture view
+    let texture_view = texture.create_view(&Default::default());
+
+    // Create the accumulation buffer
+    let size = resolution.x as u64
+        * resolution.y as u64
+        * glam::Vec4::min_size().get();
+    check_storage_buffer_size("accumulation", size, &gpu.device.limits())?;
+    let accumulation = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    });
+
+    Ok((texture, texture_view, accumulation))
+}
+
+/// Create the buffers with the voxels of the instanced grids and the instances
+/// Storage buffers can't be empty, so a dummy voxel and instance are uploaded when there are none
+fn create_instance_buffers(
+    gpu: &GpuContext,
+    grids: &[VoxelGrid],
+    instances: &[Instance],
+) -> Result<(wgpu::Buffer, wgpu::Buffer)> {
+    // Put the voxels of the grids one after another, remembering the range and the bounds of each grid
+    let mut voxels = vec![];
+    let mut ranges = vec![];
+    for grid in grids {
+        let (min, max) = grid.world_bounds();
+        ranges.push((voxels.len() as u32, grid.len() as u32, min, max));
+        voxels.extend(grid.voxels().iter().map(|voxel| Voxel {
+            color: grid.color(voxel),
+            palette_index: 0,
+            ..*voxel
+        }));
+    }
+    if voxels.is_empty() {
+        voxels.push(Voxel::new(glam::IVec3::ZERO, glam::Vec3::ZERO));
+    }
+
+    let mut gpu_instances = instances
+        .iter()
+        .enumerate()
+        .map(|(i, instance)| {
+            let (start, count, min, max) = *ranges
+                .get(instance.grid_id as usize)
+                .ok_or(VoxelError::InvalidGrid(i, instance.grid_id, grids.len()))?;
+            let (scale, _, _) = instance.transform.to_scale_rotation_translation();
+            Ok(GpuInstance {
+                inverse: instance.transform.inverse(),
+                min,
+                start,
+                max,
+                count,
+                scale: scale.min_element(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if gpu_instances.is_empty() {
+        gpu_instances.push(GpuInstance {
+            inverse: glam::Mat4::IDENTITY,
+            min: glam::Vec3::ZERO,
+            start: 0,
+            max: glam::Vec3::ZERO,
+            count: 0,
+            scale: 1.0,
+        });
+    }
+
+    // Upload the voxels and the instances
+    let mut buffer = StorageBuffer::new(vec![]);
+    buffer.write(&voxels)?;
+    check_storage_buffer_size("instance voxel", buffer.as_ref().len() as u64, &gpu.device.limits())?;
+    let voxel_buffer = gpu
+        .device
+        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: &buffer.into_inner(),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+    let mut buffer = StorageBuffer::new(vec![]);
+    buffer.write(&gpu_instances)?;
+    check_storage_buffer_size("instance", buffer.as_ref().len() as u64, &gpu.device.limits())?;
+    let instance_buffer = gpu
+        .device
+        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: &buffer.into_inner(),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+    Ok((voxel_buffer, instance_buffer))
+}
+
+/// Create the srgb texture the frame is shown with and the buffer the frame is copied through
+fn create_display_targets(
+    gpu: &GpuContext,
+    resolution: glam::UVec2,
+) -> (wgpu::Texture, wgpu::TextureView, wgpu::Buffer) {
+    let texture = gpu.device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size: wgpu::Extent3d {
+            width: resolution.x,
+            height: resolution.y,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+    });
+    let view = texture.create_view(&Default::default());
+    let buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: padded_bytes_per_row(resolution.x) as u64 * resolution.y as u64,
+        usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    (texture, view, buffer)
+}
+
+/// Get the bytes per row of a frame row, padded to the alignment of buffer copies
+fn padded_bytes_per_row(width: u32) -> u32 {
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    (width * 4).div_ceil(align) * align
+}
+
+/// Create a grid buffer with room for an amount of voxels
+fn create_grid_buffer(gpu: &GpuContext, capacity: usize) -> Result<wgpu::Buffer> {
+    let size = capacity as u64 * Voxel::min_size().get();
+    check_storage_buffer_size("grid", size, &gpu.device.limits())?;
+    Ok(gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    }))
+}
+
+/// Create the light buffer
+/// Storage buffers can't be empty, so a dummy light is uploaded when there are no lights
+fn create_light_buffer(gpu: &GpuContext, lights: &[Light]) -> Result<wgpu::Buffer> {
+    let lights = if lights.is_empty() {
+        vec![Light::new(glam::Vec3::ZERO, glam::Vec3::ZERO, 0.0)]
+    } else {
+        lights.to_vec()
+    };
+    let mut buffer = StorageBuffer::new(vec![]);
+    buffer.write(&lights)?;
+    Ok(gpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: &buffer.into_inner(),
+        usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+    }))
+}
+
+/// Create the palette buffer, a dummy color is uploaded when the grid has no palette
+fn create_palette_buffer(gpu: &GpuContext, palette: &[glam::Vec3]) -> Result<wgpu::Buffer> {
+    let palette = if palette.is_empty() {
+        vec![glam::Vec3::ZERO]
+    } else {
+        palette.to_vec()
+    };
+    let mut buffer = StorageBuffer::new(vec![]);
+    buffer.write(&palette)?;
+    Ok(gpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: &buffer.into_inner(),
+        usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+    }))
+}
+
+/// Create the buffer of the emissive voxels, so the glow doesn't have to check every voxel
+/// A voxel without emission is uploaded when none of the voxels glow, the shader skips it
+fn create_emissive_buffer(gpu: &GpuContext, voxels: &[Voxel]) -> Result<wgpu::Buffer> {
+    let mut emissive: Vec<Voxel> = voxels.iter().filter(|voxel| voxel.emission > 0.0).copied().collect();
+    if emissive.is_empty() {
+        emissive.push(Voxel::new(glam::IVec3::ZERO, glam::Vec3::ZERO));
+    }
+    let mut buffer = StorageBuffer::new(vec![]);
+    buffer.write(&emissive)?;
+    check_storage_buffer_size("emissive", buffer.as_ref().len() as u64, &gpu.device.limits())?;
+    Ok(gpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: &buffer.into_inner(),
+        usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+    }))
+}
+
+/// Create the bvh buffer, the bvh always has at least the root node
+fn create_bvh_buffer(gpu: &GpuContext, nodes: &[BvhNode]) -> Result<wgpu::Buffer> {
+    let mut buffer = StorageBuffer::new(vec![]);
+    buffer.write(&nodes.to_vec())?;
+    check_storage_buffer_size("bvh", buffer.as_ref().len() as u64, &gpu.device.limits())?;
+    Ok(gpu.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: &buffer.into_inner(),
+        usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+    }))
+}
+
+/// Get the palette MagicaVoxel uses for files without an RGBA chunk, index 0 is unused by the voxels
+/// It's a 6x6x6 color cube without black, followed by ramps of red, green, blue and gray
+fn default_vox_palette() -> Vec<glam::Vec3> {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+    let mut colors = vec![[0; 3]];
+    // The blue channel changes fastest, the black corner of the cube is left out
+    for red in CUBE {
+        for green in CUBE {
+            for blue in CUBE {
+                colors.push([red, green, blue]);
+            }
+        }
+    }
+    colors.pop();
+    for channel in 0..3 {
+        colors.extend(RAMP.map(|value| {
+            let mut color = [0; 3];
+            color[channel] = value;
+            color
+        }));
+    }
+    colors.extend(RAMP.map(|value| [value; 3]));
+    colors
+        .into_iter()
+        .map(|[red, green, blue]| glam::vec3(red as f32, green as f32, blue as f32) / 255.0)
+        .collect()
+}
+
+/// Get the modification time of a file, returns [`None`] if it can't be read
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Get the source of the shader
+/// Reads the shader from the file if a path is given, otherwise the bundled shader is used
+fn load_shader(path: Option<&Path>) -> Result<String> {
+    match path {
+        Some(path) => {
+            let source = std::fs::read_to_string(path).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to read the shader {}: {}", path.display(), e),
+                )
+            })?;
+            log::info!("Loaded the shader from {}", path.display());
+            Ok(source)
+        }
+        None => Ok(SHADER_SOURCE.to_string()),
+    }
+}
+
+/// Save an image to a file
+/// The image format is inferred from the file extension (png, jpg or bmp)
+pub fn save_image(image: &image::RgbaImage, path: impl AsRef<Path>) -> Result<()> {
+    let format = image_format(path.as_ref())?;
+    match format {
+        // Jpeg doesn't support an alpha channel, so strip it
+        image::ImageFormat::Jpeg => image::DynamicImage::ImageRgba8(image.clone())
+            .to_rgb8()
+            .save_with_format(path, format)?,
+        _ => image.save_with_format(path, format)?,
+    }
+    Ok(())
+}
+
+/// Save an image to a file with the camera and the uniforms it was rendered with
+/// Only pngs can store the settings, they're written to a text chunk that other viewers ignore
+/// The other formats are saved like [`save_image`] does, see [`Tracer::load_metadata`] for reading the settings back
+pub fn save_with_metadata(
+    image: &image::RgbaImage,
+    path: impl AsRef<Path>,
+    camera: &Camera,
+    uniforms: &Uniforms,
+) -> Result<()> {
+    if image_format(path.as_ref())? != image::ImageFormat::Png {
+        return save_image(image, path);
+    }
+    let metadata = serde_json::to_string(&FrameMetadata {
+        camera: *camera,
+        uniforms: *uniforms,
+    })?;
+
+    // Encode the png by hand, since the image crate can't write text chunks
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_text_chunk(METADATA_KEYWORD.to_string(), metadata)?;
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(image.as_raw())?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Get the image format for a file from its extension
+fn image_format(path: &Path) -> Result<image::ImageFormat> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("png") => Ok(image::ImageFormat::Png),
+        Some("jpg") | Some("jpeg") => Ok(image::ImageFormat::Jpeg),
+        Some("bmp") => Ok(image::ImageFormat::Bmp),
+        Some(extension) => Err(VoxelError::UnsupportedFormat(format!(".{}", extension))),
+        None => Err(VoxelError::UnsupportedFormat("no extension".to_string())),
+    }
+}
+
+/// Run gpu commands inside a validation error scope
+/// A validation error of the commands is returned as [`VoxelError::Validation`] instead of producing a broken frame
+pub fn validate<T>(gpu: &GpuContext, commands: impl FnOnce() -> Result<T>) -> Result<T> {
+    gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let result = commands();
+    // Always pop the scope, even if the commands failed early
+    if let Some(e) = block_on(gpu.device.pop_error_scope()) {
+        return Err(VoxelError::Validation(e.to_string()));
+    }
+    result
+}
+
+/// Check if a resolution fits within the texture size limit of the gpu
+pub fn check_resolution(resolution: glam::UVec2, limits: &wgpu::Limits) -> Result<()> {
+    let max = limits.max_texture_dimension_2d;
+    if resolution.x > max || resolution.y > max {
+        return Err(VoxelError::LimitExceeded(format!(
+            "The resolution {}x{} exceeds the max_texture_dimension_2d limit of {}",
+            resolution.x,
+            resolution.y,
+            max
+        )));
+    }
+    Ok(())
+}
+
+/// Check if a storage buffer fits within the binding size limit of the gpu
+pub fn check_storage_buffer_size(name: &str, size: u64, limits: &wgpu::Limits) -> Result<()> {
+    let max = limits.max_storage_buffer_binding_size as u64;
+    if size > max {
+        return Err(VoxelError::LimitExceeded(format!(
+            "The {} buffer of {} bytes exceeds the max_storage_buffer_binding_size limit of {} bytes",
+            name,
+            size,
+            max
+        )));
+    }
+    Ok(())
+}
+
+/// Check if the gpu allows binding all the storage buffers of the tracer in one shader
+pub fn check_storage_buffer_count(adapter: &str, limits: &wgpu::Limits) -> Result<()> {
+    let max = limits.max_storage_buffers_per_shader_stage;
+    if max < STORAGE_BUFFERS {
+        return Err(VoxelError::LimitExceeded(format!(
+            "The tracer needs {} storage buffers, but {} only supports a max_storage_buffers_per_shader_stage of {}",
+            STORAGE_BUFFERS, adapter, max
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Get the path of a file in `tests/fixtures`
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    // Get a color from 8 bit channels
+    fn rgb(red: u8, green: u8, blue: u8) -> glam::Vec3 {
+        glam::vec3(red as f32, green as f32, blue as f32) / 255.0
+    }
+
+    #[test]
+    fn vox_file_with_palette() {
+        let grid = VoxelGrid::from_vox_file(fixture("palette.vox")).unwrap();
+        assert_eq!(grid.0.len(), 3);
+        // The model is centered on x and z, and the z axis of MagicaVoxel becomes y
+        let positions: Vec<_> = grid.0.iter().map(Voxel::position).collect();
+        assert_eq!(
+            positions,
+            [glam::ivec3(-1, 0, 0), glam::ivec3(1, 0, 0), glam::ivec3(-1, 2, 1)]
+        );
+        let colors: Vec<_> = grid.0.iter().map(|voxel| voxel.color).collect();
+        assert_eq!(colors, [rgb(255, 0, 0), rgb(0, 255, 0), rgb(0, 0, 255)]);
+        assert_eq!(grid.0[2].palette_index, 3);
+        assert_eq!(grid.1.len(), 255);
+        assert_eq!(grid.1[3], rgb(128, 128, 128));
+    }
+
+    #[test]
+    fn vox_file_without_palette_uses_the_default_palette() {
+        let grid = VoxelGrid::from_vox_file(fixture("default_palette.vox")).unwrap();
+        assert_eq!(grid.0.len(), 4);
+        let colors: Vec<_> = grid.0.iter().map(|voxel| voxel.color).collect();
+        // White, the next color of the cube, the first red of the ramps and the last gray
+        assert_eq!(
+            colors,
+            [rgb(255, 255, 255), rgb(255, 255, 204), rgb(238, 0, 0), rgb(17, 17, 17)]
+        );
+        assert_eq!(default_vox_palette().len(), 256);
+    }
+
+    // Limits with a small texture size and storage buffer size, so the edges are easy to hit
+    fn small_limits() -> wgpu::Limits {
+        wgpu::Limits {
+            max_texture_dimension_2d: 2048,
+            max_storage_buffer_binding_size: 1024,
+            ..wgpu::Limits::default()
+        }
+    }
+
+    #[test]
+    fn resolution_within_the_limit() {
+        let limits = small_limits();
+        assert!(check_resolution(glam::uvec2(1920, 1080), &limits).is_ok());
+        assert!(check_resolution(glam::uvec2(2048, 2048), &limits).is_ok());
+    }
+
+    #[test]
+    fn resolution_over_the_limit() {
+        let limits = small_limits();
+        assert!(check_resolution(glam::uvec2(2049, 100), &limits).is_err());
+        let error = check_resolution(glam::uvec2(100, 4096), &limits).unwrap_err();
+        assert!(matches!(error, VoxelError::LimitExceeded(_)));
+        let message = error.to_string();
+        assert!(message.contains("max_texture_dimension_2d"));
+        assert!(message.contains("2048"));
+    }
+
+    #[test]
+    fn storage_buffer_within_the_limit() {
+        let limits = small_limits();
+        assert!(check_storage_buffer_size("grid", 512, &limits).is_ok());
+        assert!(check_storage_buffer_size("grid", 1024, &limits).is_ok());
+    }
+
+    #[test]
+    fn storage_buffer_over_the_limit() {
+        let error = check_storage_buffer_size("bvh", 1025, &small_limits()).unwrap_err();
+        assert!(matches!(error, VoxelError::LimitExceeded(_)));
+        let message = error.to_string();
+        assert!(message.contains("max_storage_buffer_binding_size"));
+        assert!(message.contains("1024"));
+        assert!(message.contains("bvh"));
+    }
+
+    #[test]
+    fn enough_storage_buffers() {
+        let limits = wgpu::Limits {
+            max_storage_buffers_per_shader_stage: STORAGE_BUFFERS,
+            ..wgpu::Limits::default()
+        };
+        assert!(check_storage_buffer_count("gpu", &limits).is_ok());
+    }
+
+    #[test]
+    fn too_few_storage_buffers() {
+        // The default limit of 8 is below what the tracer binds
+        let error = check_storage_buffer_count("Some gpu", &wgpu::Limits::default()).unwrap_err();
+        assert!(matches!(error, VoxelError::LimitExceeded(_)));
+        let message = error.to_string();
+        assert!(message.contains("max_storage_buffers_per_shader_stage"));
+        assert!(message.contains("Some gpu"));
+        assert!(message.contains("8"));
+    }
+
+    // Read a little endian u32 out of serialized bytes
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Read a little endian f32 out of serialized bytes
+    fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+        f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn uniforms_serialize_to_the_shader_layout() {
+        let uniforms = Uniforms {
+            resolution: glam::uvec2(1920, 1080),
+            background_color: glam::vec4(0.25, 0.5, 0.75, 1.0),
+            far: 123.0,
+            hit_epsilon: 0.002,
+            collect_stats: 1,
+            tile_offset: glam::uvec2(7, 9),
+            full_resolution: glam::uvec2(7680, 4320),
+            ..Default::default()
+        };
+        let mut buffer = UniformBuffer::new(vec![]);
+        buffer.write(&uniforms).unwrap();
+        let bytes = buffer.into_inner();
+        assert_eq!(bytes.len(), 336);
+        assert_eq!(read_u32(&bytes, 16), 1920);
+        assert_eq!(read_u32(&bytes, 20), 1080);
+        assert_eq!(read_f32(&bytes, 32), 0.25);
+        assert_eq!(read_f32(&bytes, 44), 1.0);
+        assert_eq!(read_f32(&bytes, 128), 123.0);
+        assert_eq!(read_f32(&bytes, 304), 0.002);
+        assert_eq!(read_u32(&bytes, 308), 1);
+        assert_eq!(read_u32(&bytes, 312), 7);
+        assert_eq!(read_u32(&bytes, 316), 9);
+        assert_eq!(read_u32(&bytes, 320), 7680);
+        assert_eq!(read_u32(&bytes, 324), 4320);
+    }
+
+    #[test]
+    fn voxels_serialize_to_the_shader_layout() {
+        let voxels = vec![
+            Voxel::new(glam::ivec3(1, 2, 3), glam::vec3(1.0, 0.0, 0.0)),
+            Voxel {
+                palette_index: 5,
+                pattern: PATTERN_NOISE,
+                pattern_scale: 0.5,
+                ..Voxel::new(glam::ivec3(-4, 5, -6), glam::vec3(0.0, 0.5, 1.0))
+            },
+        ];
+        let mut buffer = StorageBuffer::new(vec![]);
+        buffer.write(&voxels).unwrap();
+        let bytes = buffer.into_inner();
+        // The voxels follow each other with the size of the shader struct as the stride
+        assert_eq!(bytes.len(), 2 * 64);
+        assert_eq!(read_u32(&bytes, 4) as i32, 2);
+        assert_eq!(read_f32(&bytes, 16), 1.0);
+        assert_eq!(read_u32(&bytes, 64) as i32, -4);
+        assert_eq!(read_u32(&bytes, 72) as i32, -6);
+        assert_eq!(read_f32(&bytes, 64 + 20), 0.5);
+        assert_eq!(read_u32(&bytes, 64 + 44), 5);
+        assert_eq!(read_u32(&bytes, 64 + 48), PATTERN_NOISE);
+        assert_eq!(read_f32(&bytes, 64 + 52), 0.5);
+    }
+
+    #[test]
+    fn lights_serialize_to_the_shader_layout() {
+        let lights = vec![
+            Light::new(glam::vec3(1.0, 2.0, 3.0), glam::vec3(1.0, 1.0, 1.0), 2.0),
+            Light::directional(glam::vec3(0.0, 1.0, 0.0), glam::vec3(0.5, 0.25, 0.125), 4.0),
+        ];
+        let mut buffer = StorageBuffer::new(vec![]);
+        buffer.write(&lights).unwrap();
+        let bytes = buffer.into_inner();
+        assert_eq!(bytes.len(), 2 * 48);
+        assert_eq!(read_f32(&bytes, 8), 3.0);
+        assert_eq!(read_f32(&bytes, 28), 2.0);
+        assert_eq!(read_u32(&bytes, 32), LIGHT_POINT);
+        assert_eq!(read_f32(&bytes, 48 + 20), 0.25);
+        assert_eq!(read_f32(&bytes, 48 + 28), 4.0);
+        assert_eq!(read_u32(&bytes, 48 + 32), LIGHT_DIRECTIONAL);
+    }
+
+    // Fill a frame copy like the gpu does, each row padded to the copy alignment
+    // Every pixel stores its coordinates and the padding is filled with 0xff, so leaked padding is easy to spot
+    fn padded_frame(resolution: (u32, u32)) -> (Vec<u8>, u32) {
+        let padded = padded_bytes_per_row(resolution.0);
+        let mut data = vec![0xff; (padded * resolution.1) as usize];
+        for y in 0..resolution.1 {
+            for x in 0..resolution.0 {
+                let offset = (y * padded + x * 4) as usize;
+                data[offset..offset + 4].copy_from_slice(&[(x % 251) as u8, (y % 251) as u8, 0, 1]);
             }
-            Err(e) => Err(anyhow::anyhow!(e)),
         }
+        (data, padded)
+    }
+
+    #[test]
+    fn strip_padding_keeps_the_resolution() {
+        // 1000 pixels are 4000 bytes, which isn't a multiple of the 256 byte alignment
+        let (data, padded) = padded_frame((1000, 1000));
+        assert_eq!(padded, 4096);
+        let image = strip_padding(&data, padded, (1000, 1000)).unwrap();
+        assert_eq!(image.dimensions(), (1000, 1000));
+        // None of the padding ends up in the image
+        assert!(image.pixels().all(|pixel| pixel[2] == 0 && pixel[3] == 1));
+    }
+
+    // Get a gpu for the tests that need one
+    fn test_gpu() -> GpuContext {
+        block_on(GpuContext::new(&crate::render::AdapterOptions::default())).unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn too_small_buffer_is_a_validation_error() {
+        let gpu = test_gpu();
+        let layout = gpu.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(Uniforms::min_size()),
+                },
+                count: None,
+            }],
+        });
+        // A buffer that only fits the resolution of the uniforms
+        let buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 16,
+            usage: wgpu::BufferUsages::UNIFORM,
+            mapped_at_creation: false,
+        });
+        let result = validate(&gpu, || {
+            gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                }],
+            });
+            Ok(())
+        });
+        assert!(matches!(result, Err(VoxelError::Validation(_))), "{:?}", result);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn valid_commands_pass_validation() {
+        let gpu = test_gpu();
+        let result = validate(&gpu, || create_light_buffer(&gpu, &[Light::default()]));
+        assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn corner_pixels_are_written() {
+        let gpu = test_gpu();
+        // 1000 isn't a multiple of any workgroup size, so the last workgroups are partly outside the frame
+        let image = Tracer::render_offline(
+            &gpu,
+            glam::uvec2(1000, 1000),
+            Uniforms::default(),
+            &VoxelGrid::default(),
+            &[],
+            Camera::default(),
+        )
+        .unwrap();
+        assert_eq!(image.dimensions(), (1000, 1000));
+        // The frame starts out cleared to zero, so a written pixel has an opaque background
+        for (x, y) in [(0, 0), (999, 0), (0, 999), (999, 999)] {
+            assert_eq!(image.get_pixel(x, y)[3], 255, "pixel {}, {} wasn't written", x, y);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn same_seed_renders_the_same_frame() {
+        let gpu = test_gpu();
+        let grid = VoxelGrid::from_voxels(
+            (-2..=2).map(|x| Voxel::new(glam::ivec3(x, x.abs() - 1, 0), glam::vec3(0.8, 0.4, 0.2))),
+        );
+        let lights = [Light::default()];
+        // Several jittered samples per pixel, so the frame depends on the random numbers
+        let uniforms = Uniforms::builder().samples_per_pixel(4).seed(7).build();
+        let render = || {
+            Tracer::render_offline(&gpu, glam::uvec2(64, 64), uniforms, &grid, &lights, Camera::default())
+                .unwrap()
+                .into_raw()
+        };
+        assert_eq!(render(), render());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn flat_color_is_written_as_srgb() {
+        let gpu = test_gpu();
+        // The background is linear, the frame holds the srgb bytes
+        let uniforms = Uniforms::builder()
+            .floor(None)
+            .background_color(glam::vec4(0.001, 0.2, 0.5, 1.0))
+            .build();
+        let image = Tracer::render_offline(
+            &gpu,
+            glam::uvec2(16, 16),
+            uniforms,
+            &VoxelGrid::default(),
+            &[],
+            Camera::default(),
+        )
+        .unwrap();
+        assert!(image.pixels().all(|pixel| pixel.0 == [3, 124, 188, 255]));
+    }
+
+    #[test]
+    fn empty_grid_bounds() {
+        let grid = VoxelGrid::default();
+        assert!(grid.is_empty());
+        assert_eq!(grid.bounds(), (glam::IVec3::ZERO, glam::IVec3::ZERO));
+        assert_eq!(grid.get(glam::IVec3::ZERO), None);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn empty_grid_renders_the_background() {
+        let gpu = test_gpu();
+        let uniforms = Uniforms::builder()
+            .floor(None)
+            .background_color(glam::vec4(0.0, 0.0, 1.0, 1.0))
+            .build();
+        let image = Tracer::render_offline(
+            &gpu,
+            glam::uvec2(16, 16),
+            uniforms,
+            &VoxelGrid::default(),
+            &[],
+            Camera::default(),
+        )
+        .unwrap();
+        assert!(image.pixels().all(|pixel| pixel.0 == [0, 0, 255, 255]));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn missed_pixels_are_transparent_in_the_saved_png() {
+        let gpu = test_gpu();
+        let uniforms = Uniforms {
+            alpha_background: 1,
+            ..Uniforms::builder().floor(None).build()
+        };
+        let image = Tracer::render_offline(
+            &gpu,
+            glam::uvec2(16, 16),
+            uniforms,
+            &VoxelGrid::default(),
+            &[],
+            Camera::default(),
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("voxeltracer-transparent-{}.png", std::process::id()));
+        save_with_metadata(&image, &path, &Camera::default(), &uniforms).unwrap();
+        let saved = image::open(&path).unwrap().to_rgba8();
+        std::fs::remove_file(&path).unwrap();
+        for (x, y) in [(0, 0), (8, 8), (15, 0), (15, 15)] {
+            assert_eq!(saved.get_pixel(x, y)[3], 0, "pixel {}, {} isn't transparent", x, y);
+        }
+    }
+
+    #[test]
+    fn workgroup_size_fits_the_limits() {
+        assert_eq!(workgroup_size(&wgpu::Limits::default()), 16);
+        let limits = wgpu::Limits {
+            max_compute_invocations_per_workgroup: 64,
+            ..Default::default()
+        };
+        assert_eq!(workgroup_size(&limits), 8);
+        let limits = wgpu::Limits {
+            max_compute_workgroup_size_y: 4,
+            ..Default::default()
+        };
+        assert_eq!(workgroup_size(&limits), 4);
+    }
+
+    #[test]
+    fn dispatch_covers_the_frame() {
+        assert_eq!(dispatch_size(glam::uvec2(1000, 1000), 16), glam::uvec2(63, 63));
+        assert_eq!(dispatch_size(glam::uvec2(1024, 8), 16), glam::uvec2(64, 1));
+        assert_eq!(dispatch_size(glam::uvec2(1, 17), 8), glam::uvec2(1, 3));
+    }
+
+    #[test]
+    fn dispatch_matches_the_substituted_workgroup_size() {
+        let resolution = glam::uvec2(1000, 563);
+        for limits in [wgpu::Limits::default(), wgpu::Limits::downlevel_webgl2_defaults()] {
+            let size = workgroup_size(&limits);
+            let source = set_workgroup_size(SHADER_SOURCE, size).unwrap();
+            assert!(source.contains(&format!("@workgroup_size({}, {}, 1)", size, size)));
+            // The workgroups cover every pixel, and none of them is completely outside the frame
+            let workgroups = dispatch_size(resolution, size);
+            assert!((workgroups * size).cmpge(resolution).all());
+            assert!(((workgroups - 1) * size).cmplt(resolution).all());
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu-tests"), ignore = "needs a gpu, run with --features gpu-tests")]
+    fn tracer_uses_the_workgroup_size_of_the_device() {
+        let gpu = test_gpu();
+        let tracer = Tracer::new(&gpu, &Uniforms::default(), &ShaderOptions::default()).unwrap();
+        assert_eq!(tracer.workgroup_size(), workgroup_size(&gpu.device.limits()));
+    }
+
+    // Check that every cell of the index points at the voxel in that cell
+    fn assert_index_is_valid(grid: &VoxelGrid) {
+        assert_eq!(grid.2.len(), grid.0.len());
+        for (index, voxel) in grid.0.iter().enumerate() {
+            assert_eq!(grid.index_of(voxel.position()), Some(index));
+        }
+    }
+
+    #[test]
+    fn set_get_and_remove_voxels() {
+        let mut grid = VoxelGrid::default();
+        grid.set(glam::ivec3(1, 2, 3), rgb(255, 0, 0));
+        grid.set(glam::ivec3(0, 0, 0), rgb(0, 255, 0));
+        // Setting an occupied cell replaces the voxel
+        grid.set(glam::ivec3(1, 2, 3), rgb(0, 0, 255));
+        assert_eq!(grid.len(), 2);
+        assert_eq!(grid.get(glam::ivec3(1, 2, 3)).unwrap().color, rgb(0, 0, 255));
+        assert_eq!(grid.get(glam::ivec3(3, 2, 1)), None);
+        assert!(grid.remove(glam::ivec3(1, 2, 3)));
+        assert!(!grid.remove(glam::ivec3(1, 2, 3)));
+        assert_eq!(grid.voxels(), &[Voxel::new(glam::IVec3::ZERO, rgb(0, 255, 0))]);
+        assert_index_is_valid(&grid);
+    }
+
+    #[test]
+    fn editing_by_index_keeps_the_index_valid() {
+        let mut grid = VoxelGrid::from_voxels((0..5).map(|x| Voxel::new(glam::ivec3(x, 0, 0), glam::Vec3::ONE)));
+        assert!(grid.insert_at(1, Voxel::new(glam::ivec3(0, 1, 0), glam::Vec3::ONE)));
+        // A cell can't hold two voxels
+        assert!(!grid.insert_at(0, Voxel::new(glam::ivec3(3, 0, 0), glam::Vec3::ZERO)));
+        assert_index_is_valid(&grid);
+        assert_eq!(grid.remove_at(3).unwrap().position(), glam::ivec3(2, 0, 0));
+        assert_eq!(grid.remove_at(10), None);
+        assert_index_is_valid(&grid);
+        // Moving a voxel into an occupied cell is refused
+        assert!(!grid.replace_at(0, Voxel::new(glam::ivec3(4, 0, 0), glam::Vec3::ONE)));
+        assert!(grid.replace_at(0, Voxel::new(glam::ivec3(0, 0, 7), glam::Vec3::ONE)));
+        assert_eq!(grid.get(glam::IVec3::ZERO), None);
+        assert_index_is_valid(&grid);
+    }
+
+    #[test]
+    fn saved_grid_keeps_its_format() {
+        let mut grid = VoxelGrid::from_voxels([Voxel::new(glam::ivec3(1, 0, 0), glam::Vec3::ONE)]);
+        grid.set_palette(vec![rgb(255, 0, 0)]);
+        let source = ron::to_string(&grid).unwrap();
+        // The index isn't saved, the grid is stored as its voxels and its palette
+        assert!(source.starts_with("([("));
+        let loaded: VoxelGrid = ron::from_str(&source).unwrap();
+        assert_eq!(loaded, grid);
+        assert_index_is_valid(&loaded);
+        // Older scenes without a palette still load
+        let voxels = ron::to_string(grid.voxels()).unwrap();
+        let loaded: VoxelGrid = ron::from_str(&format!("({})", voxels)).unwrap();
+        assert!(loaded.palette().is_empty());
+        assert_eq!(loaded.get(glam::ivec3(1, 0, 0)), grid.voxels().first());
     }
 }
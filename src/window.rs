@@ -1,5 +1,13 @@
 use crate::render::RenderContext;
-use sdl2::event::{Event, WindowEvent};
+use std::collections::HashSet;
+use sdl2::{
+    event::{Event, WindowEvent},
+    keyboard::Keycode,
+    video::FullscreenType,
+};
+
+// The keys that move the camera
+const MOVEMENT_KEYS: [Keycode; 6] = [Keycode::W, Keycode::A, Keycode::S, Keycode::D, Keycode::Q, Keycode::E];
 
 /// A wrapper around an sdl2 window
 pub struct Window {
@@ -11,6 +19,16 @@ pub struct Window {
     sdl_window: sdl2::video::Window,
     // The flag for if the window should be closed
     should_close: bool,
+    // The flag for if the window is fullscreen
+    fullscreen: bool,
+    // The flag for if a screenshot was requested
+    screenshot_requested: bool,
+    // The flag for if the window was resized
+    resized: bool,
+    // The movement keys that are held down
+    held_keys: HashSet<Keycode>,
+    // The scale factor that is used instead of the one of the display, for testing the ui at other scales
+    scale_override: Option<f32>,
 }
 
 impl Window {
@@ -27,6 +45,8 @@ impl Window {
         let video_subsystem = sdl.video().map_err(|e| anyhow::anyhow!(e))?;
         // Create the window
         let mut window_builder = &mut video_subsystem.window(&title.into(), width, height);
+        // Render at the native resolution on HiDPI displays
+        window_builder = window_builder.allow_highdpi();
         if resizable {
             window_builder = window_builder.resizable();
         }
@@ -40,6 +60,11 @@ impl Window {
             sdl_window,
             event_pump,
             should_close: false,
+            fullscreen: false,
+            screenshot_requested: false,
+            resized: false,
+            held_keys: HashSet::new(),
+            scale_override: None,
         })
     }
 
@@ -49,6 +74,10 @@ impl Window {
         render_context: &mut RenderContext,
         egui_platform: &mut egui_sdl2_platform::Platform,
     ) {
+        // The flag for if fullscreen should be toggled after handling the events
+        let mut toggle_fullscreen = false;
+        // The flag for if the window changed size, the surface is only reconfigured once for all the events
+        let mut size_changed = false;
         // Poll the events
         for event in self.event_pump.poll_iter() {
             // Let the egui platform handle the event
@@ -57,14 +86,98 @@ impl Window {
             match event {
                 Event::Quit { .. } => self.should_close = true,
                 Event::Window { win_event, .. } => match win_event {
-                    WindowEvent::Resized(w, h) | WindowEvent::SizeChanged(w, h) => {
-                        render_context.resize(w as u32, h as u32);
-                    }
+                    WindowEvent::Resized(..) | WindowEvent::SizeChanged(..) => size_changed = true,
+                    // The key up events are missed while the window isn't focused
+                    WindowEvent::FocusLost => self.held_keys.clear(),
                     _ => {}
                 },
+                // Toggle fullscreen unless egui is using the keyboard
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } if !egui_platform.context().wants_keyboard_input() => toggle_fullscreen = true,
+                // Request a screenshot unless egui is using the keyboard
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12 | Keycode::PrintScreen),
+                    repeat: false,
+                    ..
+                } if !egui_platform.context().wants_keyboard_input() => {
+                    self.screenshot_requested = true
+                }
+                // Hold the movement keys unless egui is using the keyboard
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } if MOVEMENT_KEYS.contains(&keycode) && !egui_platform.context().wants_keyboard_input() => {
+                    self.held_keys.insert(keycode);
+                }
+                // Always release the keys, so they don't get stuck when egui takes the keyboard
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    self.held_keys.remove(&keycode);
+                }
                 _ => {}
             }
         }
+
+        if toggle_fullscreen {
+            if let Err(e) = self.set_fullscreen(!self.fullscreen) {
+                log::error!("Failed to toggle fullscreen: {}", e);
+            }
+            size_changed = true;
+        }
+        // Dragging the edge of the window sends many events per frame, only the last size matters
+        if size_changed {
+            // The surface has to match the drawable size, not the logical size
+            let (width, height) = self.drawable_size();
+            render_context.resize(width, height);
+            self.resized = true;
+        }
+    }
+
+    /// Switch between borderless fullscreen and windowed mode
+    pub fn set_fullscreen(&mut self, fullscreen: bool) -> anyhow::Result<()> {
+        let mode = if fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        self.sdl_window
+            .set_fullscreen(mode)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        self.fullscreen = fullscreen;
+        Ok(())
+    }
+
+    /// Check if the window is fullscreen
+    pub fn is_fullscreen(&self) -> bool {
+        self.fullscreen
+    }
+
+    /// Check if a screenshot was requested since the last call
+    pub fn take_screenshot_request(&mut self) -> bool {
+        std::mem::take(&mut self.screenshot_requested)
+    }
+
+    /// Check if the window was resized since the last call
+    pub fn take_resized(&mut self) -> bool {
+        std::mem::take(&mut self.resized)
+    }
+
+    /// Get the direction to move the camera in from the held keys
+    /// The x axis is right (D/A), the y axis is up (E/Q) and the z axis is forward (W/S)
+    pub fn movement(&self) -> glam::Vec3 {
+        let axis = |positive, negative| {
+            self.held_keys.contains(&positive) as i32 as f32 - self.held_keys.contains(&negative) as i32 as f32
+        };
+        glam::vec3(
+            axis(Keycode::D, Keycode::A),
+            axis(Keycode::E, Keycode::Q),
+            axis(Keycode::W, Keycode::S),
+        )
     }
 
     /// Check if the window should be closed
@@ -82,4 +195,28 @@ impl Window {
     pub fn size(&self) -> (u32, u32) {
         self.sdl_window.size()
     }
+
+    /// Get the size of the drawable area in pixels
+    /// This differs from [`Window::size`] on HiDPI displays
+    pub fn drawable_size(&self) -> (u32, u32) {
+        self.sdl_window.drawable_size()
+    }
+
+    /// Get the ratio between the drawable size and the window size, or the overridden scale factor
+    pub fn scale_factor(&self) -> f32 {
+        if let Some(scale) = self.scale_override {
+            return scale;
+        }
+        let (width, _) = self.size();
+        let (drawable_width, _) = self.drawable_size();
+        if width == 0 {
+            return 1.0;
+        }
+        drawable_width as f32 / width as f32
+    }
+
+    /// Use a scale factor instead of the one of the display, [`None`] goes back to the display scale
+    pub fn set_scale_factor(&mut self, scale: Option<f32>) {
+        self.scale_override = scale;
+    }
 }